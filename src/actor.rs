@@ -1,4 +1,6 @@
-use std::{marker::PhantomData, panic::RefUnwindSafe};
+use std::{collections::BTreeSet, fmt, marker::PhantomData, panic::RefUnwindSafe, sync::Mutex};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use super::context::Context;
 
@@ -8,7 +10,7 @@ pub trait PersistentActor: 'static + Sized + RefUnwindSafe {
     fn init(&self, cx: &mut Context<Self>) {}
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub struct ActorName(&'static str);
 
 pub struct PersistentActorId<T>
@@ -52,7 +54,7 @@ where
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct AnyActorId {
     pub name: ActorName,
     value: u32,
@@ -96,3 +98,49 @@ where
         actor_id.into_any()
     }
 }
+
+impl Serialize for ActorName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ActorName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Ok(ActorName(intern(name)))
+    }
+}
+
+/// Names read back from storage or a message are leaked the first time they are seen, there is
+/// only one per actor or message type.
+pub(crate) fn intern(name: String) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap();
+    match names.get(name.as_str()) {
+        Some(name) => name,
+        None => {
+            let name = Box::leak(name.into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+impl fmt::Display for ActorName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for AnyActorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.name, self.value)
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    codec::CodecId,
+    database::{Bytes, PersistentValue},
+    dispatcher::AnyCallbackId,
+    dyn_table::DispatchResult,
+    envelope::Envelope,
+    message::{AnyMessage, Message, MessageName},
+};
+
+/// Future returned by [`crate::context::Context::ask`]. It is either ready when created, because
+/// the response arrived before the handler was re-run, or it never completes and the handler is
+/// suspended.
+pub struct Ask<R> {
+    result: Option<DispatchResult<R>>,
+}
+
+/// A run of an async handler: the message it handles and the responses to its asks so far.
+pub(crate) struct Invocation {
+    /// Id responses are routed with, allocated the first time the handler has to wait.
+    pub(crate) id: Option<u64>,
+    message: AnyMessage,
+    responses: BTreeMap<u32, (CodecId, Bytes)>,
+    requested: BTreeSet<u32>,
+    next_index: u32,
+}
+
+/// An async handler waiting for responses. It is kept in the storage of the actor until the
+/// handler completes.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Suspended {
+    pub(crate) message: MessageName,
+    codec: CodecId,
+    bytes: Bytes,
+    pub(crate) sender: Option<AnyActorId>,
+    pub(crate) reply_to: Option<AnyCallbackId>,
+    envelope: Envelope,
+    responses: BTreeMap<u32, (CodecId, Bytes)>,
+    requested: BTreeSet<u32>,
+}
+
+impl PersistentValue for Suspended {}
+
+impl<R> Ask<R> {
+    pub(crate) fn ready(result: DispatchResult<R>) -> Self {
+        Self {
+            result: Some(result),
+        }
+    }
+
+    pub(crate) fn pending() -> Self {
+        Self { result: None }
+    }
+}
+
+// The result is never pinned
+impl<R> Unpin for Ask<R> {}
+
+impl<R> Future for Ask<R> {
+    type Output = DispatchResult<R>;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Invocation {
+    pub(crate) fn new(message: AnyMessage) -> Self {
+        Self {
+            id: None,
+            message,
+            responses: BTreeMap::new(),
+            requested: BTreeSet::new(),
+            next_index: 0,
+        }
+    }
+
+    pub(crate) fn resume(id: u64, message: AnyMessage, suspended: Suspended) -> Self {
+        Self {
+            id: Some(id),
+            message,
+            responses: suspended.responses,
+            requested: suspended.requested,
+            next_index: 0,
+        }
+    }
+
+    /// The message the handler is run with.
+    pub(crate) fn message(&self) -> AnyMessage {
+        self.message.clone()
+    }
+
+    /// Index of the next ask made by the handler. Asks are told apart by the order they are made
+    /// in, which is why the handler must take the same path every time it is re-run.
+    pub(crate) fn next_index(&mut self) -> u32 {
+        self.next_index += 1;
+        self.next_index - 1
+    }
+
+    /// The response to the `index`-th ask, if it already arrived.
+    pub(crate) fn response<R>(&self, index: u32) -> Option<DispatchResult<R>>
+    where
+        R: Message,
+    {
+        let (codec, bytes) = self.responses.get(&index)?;
+        let message = AnyMessage::from_parts(MessageName::name_for::<R>(), *codec, bytes.clone());
+        Some(message.downcast())
+    }
+
+    /// Records that the request of the `index`-th ask was sent. Returns `false` if it was already
+    /// sent by a previous run.
+    pub(crate) fn mark_requested(&mut self, index: u32) -> bool {
+        self.requested.insert(index)
+    }
+
+    pub(crate) fn suspend(
+        self,
+        sender: Option<AnyActorId>,
+        reply_to: Option<AnyCallbackId>,
+    ) -> DispatchResult<Suspended> {
+        Ok(Suspended {
+            message: self.message.name,
+            codec: self.message.codec,
+            bytes: self.message.bytes()?.into_owned(),
+            sender,
+            reply_to,
+            envelope: self.message.envelope,
+            responses: self.responses,
+            requested: self.requested,
+        })
+    }
+}
+
+impl Suspended {
+    /// The message the handler was run with.
+    pub(crate) fn message(&self) -> AnyMessage {
+        AnyMessage::from_parts(self.message, self.codec, self.bytes.clone())
+            .with_envelope(self.envelope.clone())
+    }
+
+    pub(crate) fn add_response(&mut self, index: u32, response: &AnyMessage) -> DispatchResult<()> {
+        let bytes = response.bytes()?.into_owned();
+        self.responses.insert(index, (response.codec, bytes));
+        Ok(())
+    }
+}
+
+/// Key the state of a suspended async handler run is stored under, in the runtime's storage of
+/// the actor.
+pub(crate) fn state_key(id: u64) -> String {
+    format!("ask/{}", id)
+}
+
+/// Polls `future` once. Async handlers only wait on [`Ask`]s, which are either ready or pending
+/// for the whole run, so there is no point in polling again.
+pub(crate) fn poll_once<F>(future: F) -> Poll<F::Output>
+where
+    F: Future,
+{
+    let mut future = std::pin::pin!(future);
+    future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+}
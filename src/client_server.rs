@@ -1,10 +1,13 @@
+use std::convert::Infallible;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
     actor::{PersistentActor, PersistentActorId},
     context::Context,
-    dispatcher::{Callback, CallbackId},
-    handler::Handler,
+    dispatcher::Callback,
+    dyn_table::DispatchError,
+    handler::{AsyncHandler, Handler, Receives},
     message::Message,
 };
 
@@ -20,38 +23,43 @@ impl PersistentActor for Client {
     fn init(&self, cx: &mut Context<Self>) {
         if self.i > 1 {
             cx.storage.put("counter", self.i - 1);
-            let callback = cx.dispatcher.create_callback::<Self, ServerResponse>();
-            cx.dispatcher.send(
-                self.server_id,
-                Double {
-                    callback,
-                    n: self.n,
-                },
-            );
+            let callback = cx.create_callback::<ServerResponse>();
+            cx.dispatcher
+                .send_with_callback(self.server_id, Double { n: self.n }, callback)
+                .unwrap();
         }
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct ServerResponse {
     n: u32,
 }
 
+impl Message for ServerResponse {
+    const NAME: &'static str = "ServerResponse";
+}
+
 impl Callback<ServerResponse> for Client {
     type Env = ();
-
-    fn handle(&self, cx: &mut Context<Self>, env: (), msg: ServerResponse) {
+    type Error = Infallible;
+
+    fn handle(
+        &self,
+        cx: &mut Context<Self>,
+        env: (),
+        msg: ServerResponse,
+    ) -> Result<(), Infallible> {
         let counter: &mut u32 = cx.storage.borrow_mut("counter");
         if *counter > 1 {
             *counter = *counter - 1;
-            let callback = cx.dispatcher.create_callback::<Self, ServerResponse>();
-            cx.dispatcher.send(
-                self.server_id,
-                Double {
-                    callback,
-                    n: *counter,
-                },
-            );
+            let n = *counter;
+            let callback = cx.create_callback::<ServerResponse>();
+            cx.dispatcher
+                .send_with_callback(self.server_id, Double { n }, callback)
+                .unwrap();
         }
+        Ok(())
     }
 }
 
@@ -61,10 +69,9 @@ impl PersistentActor for Server {
     const NAME: &'static str = "Server";
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Double {
     n: u32,
-    callback: CallbackId<ServerResponse>,
 }
 
 impl Message for Double {
@@ -72,8 +79,48 @@ impl Message for Double {
 }
 
 impl Handler<Double> for Server {
-    fn handle(&self, cx: &mut Context<Self>, Double { callback, n }: Double) {
-        callback.call(ServerResponse { n: 2 * n });
+    type Error = DispatchError;
+
+    fn handle(&self, cx: &mut Context<Self>, Double { n }: Double) -> Result<(), DispatchError> {
+        cx.reply(ServerResponse { n: 2 * n })
+    }
+}
+
+/// The same loop as `Client` written as a single async handler, without a counter in storage.
+struct AskClient {
+    server_id: PersistentActorId<Server>,
+    i: u32,
+    n: u32,
+}
+
+impl PersistentActor for AskClient {
+    const NAME: &'static str = "AskClient";
+
+    fn init(&self, cx: &mut Context<Self>) {
+        cx.dispatcher.send(cx.actor_id, Start).unwrap();
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Start;
+
+impl Message for Start {
+    const NAME: &'static str = "Start";
+}
+
+impl Receives<Start> for AskClient {}
+
+impl AsyncHandler<Start> for AskClient {
+    type Error = DispatchError;
+
+    async fn handle(&self, cx: &mut Context<'_, Self>, _: Start) -> Result<(), DispatchError> {
+        let mut n = self.n;
+        for _ in 1..self.i {
+            let response: ServerResponse = cx.ask(self.server_id, Double { n }).await?;
+            n = response.n;
+        }
+        cx.storage.put("result", n);
+        Ok(())
     }
 }
 
@@ -0,0 +1,213 @@
+use std::fmt;
+
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+
+use crate::database::Bytes;
+
+/// Identifies the codec some bytes were encoded with, so they can be decoded regardless of the
+/// codec currently configured.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum CodecId {
+    MessagePack,
+    Json,
+    Bincode,
+}
+
+/// A serialization format for messages.
+pub trait Codec {
+    const ID: CodecId;
+
+    fn encode<T>(value: &T) -> Result<Bytes, CodecError>
+    where
+        T: Serialize + ?Sized;
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: for<'a> Deserialize<'a>;
+}
+
+/// Compact and self-describing, the default.
+pub struct MessagePack;
+
+/// Human readable, useful to inspect logs while debugging.
+pub struct Json;
+
+/// The most compact of the three, but not self-describing so the decoding type must match the
+/// encoded one exactly.
+pub struct Bincode;
+
+#[derive(Debug)]
+pub struct CodecError {
+    pub codec: CodecId,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl CodecId {
+    pub fn encode<T>(self, value: &T) -> Result<Bytes, CodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        match self {
+            CodecId::MessagePack => MessagePack::encode(value),
+            CodecId::Json => Json::encode(value),
+            CodecId::Bincode => Bincode::encode(value),
+        }
+    }
+
+    pub fn decode<T>(self, bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        match self {
+            CodecId::MessagePack => MessagePack::decode(bytes),
+            CodecId::Json => Json::decode(bytes),
+            CodecId::Bincode => Bincode::decode(bytes),
+        }
+    }
+}
+
+impl Codec for MessagePack {
+    const ID: CodecId = CodecId::MessagePack;
+
+    fn encode<T>(value: &T) -> Result<Bytes, CodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        rmps::to_vec(value).map_err(|err| CodecError::new(Self::ID, err))
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        rmps::from_read_ref(bytes).map_err(|err| CodecError::new(Self::ID, err))
+    }
+}
+
+impl Codec for Json {
+    const ID: CodecId = CodecId::Json;
+
+    fn encode<T>(value: &T) -> Result<Bytes, CodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        serde_json::to_vec(value).map_err(|err| CodecError::new(Self::ID, err))
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        serde_json::from_slice(bytes).map_err(|err| CodecError::new(Self::ID, err))
+    }
+}
+
+impl Codec for Bincode {
+    const ID: CodecId = CodecId::Bincode;
+
+    fn encode<T>(value: &T) -> Result<Bytes, CodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        bincode::serialize(value).map_err(|err| CodecError::new(Self::ID, err))
+    }
+
+    fn decode<T>(bytes: &[u8]) -> Result<T, CodecError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        bincode::deserialize(bytes).map_err(|err| CodecError::new(Self::ID, err))
+    }
+}
+
+impl CodecError {
+    fn new<E>(codec: CodecId, err: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            codec,
+            source: Box::new(err),
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} codec error: {}", self.codec, self.source)
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::{Bincode, Codec, CodecId, Json, MessagePack};
+
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    struct Sample {
+        id: u64,
+        name: String,
+        values: Vec<i32>,
+        next: Option<Box<Sample>>,
+    }
+
+    fn sample() -> Sample {
+        let next = Sample {
+            id: 2,
+            name: String::new(),
+            values: Vec::new(),
+            next: None,
+        };
+        Sample {
+            id: 1,
+            name: "sample".to_string(),
+            values: vec![-1, 0, i32::MAX],
+            next: Some(Box::new(next)),
+        }
+    }
+
+    fn round_trip<C>()
+    where
+        C: Codec,
+    {
+        let bytes = C::encode(&sample()).unwrap();
+        assert_eq!(C::decode::<Sample>(&bytes).unwrap(), sample());
+        // Bytes stored with their codec id decode the same
+        assert_eq!(C::ID.decode::<Sample>(&bytes).unwrap(), sample());
+    }
+
+    #[test]
+    fn messagepack_round_trips() {
+        round_trip::<MessagePack>();
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trip::<Json>();
+        let bytes = Json::encode(&sample()).unwrap();
+        let json = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(json.contains(r#""name":"sample""#));
+    }
+
+    #[test]
+    fn bincode_round_trips() {
+        round_trip::<Bincode>();
+    }
+
+    #[test]
+    fn decoding_another_type_fails() {
+        for codec in [CodecId::MessagePack, CodecId::Json, CodecId::Bincode] {
+            let bytes = codec.encode("sample").unwrap();
+            let error = codec.decode::<Sample>(&bytes).unwrap_err();
+            assert_eq!(error.codec, codec);
+        }
+    }
+}
@@ -1,16 +1,79 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use crate::{
     actor::{PersistentActor, PersistentActorId},
-    database::Database,
+    ask::{self, Ask, Invocation},
+    codec::CodecId,
+    database::{Database, Version},
+    dispatcher::{AnyCallbackId, Callback, CallbackId, CallbackTarget},
+    dyn_table::{DispatchError, DispatchResult, DynTable},
+    envelope::{Envelope, TraceId},
+    gather::{self, GatherPolicy, GatherState, Gathered},
     global_storage::{GlobalEffect, GlobalStorage, GlobalStorageCache},
+    handler::Receives,
+    message::{Message, MessageName},
+    quarantine::QuarantineCommand,
+    timer::{TimerId, Timestamp},
+    topic::Subscription,
 };
 
 use super::{actor::AnyActorId, dispatcher::Dispatcher, message::AnyMessage};
 
+/// Key of the next callback id in the runtime's storage of an actor.
+const NEXT_CALLBACK_KEY: &str = "callback/next";
+
+#[derive(Default)]
 pub struct Effects {
-    messages: HashMap<AnyActorId, AnyMessage>,
-    global_effects: HashMap<String, GlobalEffect>,
+    /// Messages sent during the run, delivered in this order.
+    pub(crate) messages: Vec<(AnyActorId, AnyMessage)>,
+    /// Version of every key read from the database during the run. The effects can only be
+    /// committed if none of them changed in between.
+    pub(crate) reads: HashMap<String, Version>,
+    /// Versions the handler required some keys to be at, see `GlobalStorage::put_if_version` and
+    /// `GlobalStorage::require_unchanged`.
+    pub(crate) guards: HashMap<String, Version>,
+    /// Storage effects keyed by backend key, across the actor's private storage and every shared
+    /// namespace opened during the run.
+    pub(crate) global_effects: HashMap<String, GlobalEffect>,
+    /// Timers created during the run and when they are due, their messages are already in
+    /// `global_effects`.
+    pub(crate) timers: Vec<(TimerId, Timestamp)>,
+    pub(crate) cancelled_timers: Vec<TimerId>,
+    /// Reminders registered during the run with their first firing, `None` for unregistered
+    /// ones or ones that never fire.
+    pub(crate) reminders: Vec<(String, Option<Timestamp>)>,
+    /// Subscriptions made, `true`, or dropped, `false`, during the run.
+    pub(crate) subscriptions: Vec<(Subscription, bool)>,
+    /// Messages published during the run, delivered to the subscribers of their topic once the
+    /// effects are committed.
+    pub(crate) published: Vec<(String, AnyMessage)>,
+    /// Decisions about quarantined actors taken during the run.
+    pub(crate) quarantine: Vec<QuarantineCommand>,
+    /// Whether the actor stopped itself, see [`Context::stop`].
+    pub(crate) stop: bool,
+    /// Recipients whose full mailbox rejected a message sent during the run, once per message.
+    pub(crate) rejected: Vec<AnyActorId>,
+    /// First timer id not handed out by the run.
+    pub(crate) next_timer: u64,
+}
+
+/// Settings the runtime starts every handler run with.
+#[derive(Clone)]
+pub struct RunConfig {
+    /// Codec outgoing messages are encoded with.
+    pub codec: CodecId,
+    /// Whether outgoing messages can stay typed, see [`crate::log::Log::DURABLE`].
+    pub local_delivery: bool,
+    /// The time according to the runtime's clock when the run started.
+    pub now: Timestamp,
+    /// First timer id the run can hand out.
+    pub next_timer: u64,
+    /// Trace messages sent during the run carry, the one of the message being handled or a new
+    /// one started by the runtime.
+    pub trace_id: TraceId,
+    /// Messages that can still be sent to the actors whose mailbox rejects messages when full,
+    /// see [`crate::mailbox::Overflow::Reject`].
+    pub mailbox_room: HashMap<AnyActorId, u64>,
 }
 
 pub struct Context<'a, A>
@@ -18,14 +81,37 @@ where
     A: PersistentActor,
 {
     pub actor_id: PersistentActorId<A>,
+    /// Storage private to this actor.
     pub storage: GlobalStorage<'a>,
+    /// Keys the runtime keeps for this actor, e.g. its callbacks, gathers and suspended asks.
+    pub(crate) runtime_storage: GlobalStorage<'a>,
     pub dispatcher: Dispatcher,
+    shared: HashMap<String, GlobalStorage<'a>>,
+    table: &'a DynTable,
+    sender: Option<AnyActorId>,
+    reply_to: Option<AnyCallbackId>,
+    attempt: u32,
+    envelope: Option<Envelope>,
+    /// Set while running an async handler.
+    pub(crate) invocation: Option<Invocation>,
 }
 
 pub struct AnyContext<'a> {
     pub id: AnyActorId,
     pub storage: GlobalStorage<'a>,
+    /// See [`Context::runtime_storage`].
+    pub(crate) runtime_storage: GlobalStorage<'a>,
     pub dispatcher: Dispatcher,
+    shared: HashMap<String, GlobalStorage<'a>>,
+    table: &'a DynTable,
+    /// Sender of the message being handled, `None` while running `init`.
+    pub(crate) sender: Option<AnyActorId>,
+    /// Callback replies to the message being handled go to, see [`Context::reply`].
+    pub(crate) reply_to: Option<AnyCallbackId>,
+    /// See [`Context::attempt`].
+    pub(crate) attempt: u32,
+    /// Envelope of the message being handled, `None` while running `init`.
+    pub(crate) envelope: Option<Envelope>,
 }
 
 impl<'a, A> Context<'a, A>
@@ -34,30 +120,376 @@ where
 {
     pub fn new(
         actor_id: PersistentActorId<A>,
+        table: &'a DynTable,
         db: &'a Database,
         cache: &'a mut GlobalStorageCache,
+        config: RunConfig,
     ) -> Self {
         Self {
             actor_id,
-            storage: GlobalStorage::new(db, cache),
-            dispatcher: Dispatcher::new(),
+            storage: GlobalStorage::private(actor_id.into_any(), db, cache),
+            runtime_storage: GlobalStorage::runtime(actor_id.into_any(), db),
+            dispatcher: Dispatcher::new(actor_id.into_any(), config),
+            shared: HashMap::new(),
+            table,
+            sender: None,
+            reply_to: None,
+            attempt: 1,
+            envelope: None,
+            invocation: None,
+        }
+    }
+
+    /// The actor that sent the message being handled, `None` while running `init`.
+    pub fn sender(&self) -> Option<AnyActorId> {
+        self.sender
+    }
+
+    /// Which attempt at handling the message this run is, starting at 1. It only goes up if the
+    /// handler has a [`crate::retry::RetryPolicy`].
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Headers, trace and deadline of the message being handled, `None` while running `init`.
+    pub fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    /// Sends `message` back to the sender of the message being handled. If the sender attached a
+    /// callback with [`Dispatcher::send_with_callback`] the reply goes to it, otherwise it goes to
+    /// the sender's `Handler<R>`. Fails if there is no sender, or if it cannot receive `R`.
+    pub fn reply<R>(&mut self, message: R) -> DispatchResult<()>
+    where
+        R: Message,
+    {
+        let sender = self.sender.ok_or(DispatchError::NoSender)?;
+        let name = MessageName::name_for::<R>();
+        match self.reply_to {
+            Some(callback) => {
+                if callback.message != name {
+                    return Err(DispatchError::TypeMissmatch);
+                }
+                if let CallbackTarget::Callback(_) = callback.target {
+                    if !self.table.has_callback(callback.actor_id.name, name) {
+                        return Err(DispatchError::MethodNotFound);
+                    }
+                }
+                self.dispatcher
+                    .send_unchecked(callback.actor_id, message, Some(callback.target))
+            }
+            None => {
+                if !self.table.has_handler(sender.name, name) {
+                    return Err(DispatchError::MethodNotFound);
+                }
+                self.dispatcher.send_unchecked(sender, message, None)
+            }
+        }
+    }
+
+    pub fn create_callback<M>(&mut self) -> CallbackId<M>
+    where
+        M: Message,
+        A: Callback<M, Env = ()>,
+    {
+        self.create_callback_with_env(())
+    }
+
+    /// Creates a callback handled by this actor's `Callback<M>` implementation, which receives
+    /// `env` when it is called. The callback only exists once the effects of the handler are
+    /// committed.
+    pub fn create_callback_with_env<M>(&mut self, env: A::Env) -> CallbackId<M>
+    where
+        M: Message,
+        A: Callback<M>,
+    {
+        let id = self.next_callback_id();
+        self.runtime_storage.put(callback_key(id), env);
+        CallbackId::new(self.actor_id.into_any(), id, None)
+    }
+
+    pub fn create_expiring_callback<M>(&mut self, expiry: Duration) -> DispatchResult<CallbackId<M>>
+    where
+        M: Message,
+        A: Callback<M, Env = ()>,
+    {
+        self.create_expiring_callback_with_env(expiry, ())
+    }
+
+    /// Like [`Context::create_callback_with_env`], if the callback is not called within `expiry`
+    /// it is dropped and this actor's [`Callback::expired`] runs with `env` instead.
+    pub fn create_expiring_callback_with_env<M>(
+        &mut self,
+        expiry: Duration,
+        env: A::Env,
+    ) -> DispatchResult<CallbackId<M>>
+    where
+        M: Message,
+        A: Callback<M>,
+    {
+        let id = self.next_callback_id();
+        let expires_at = self.dispatcher.now() + expiry;
+        let timer =
+            self.dispatcher
+                .callback_expiry(id, MessageName::name_for::<M>(), expires_at)?;
+        self.runtime_storage.put(callback_key(id), env);
+        self.runtime_storage.put(expiry_key(id), timer);
+        Ok(CallbackId::new(
+            self.actor_id.into_any(),
+            id,
+            Some(expires_at),
+        ))
+    }
+
+    /// Stops this actor once the effects of the handler are committed. It is not scheduled
+    /// again, messages sent to it go to the dead letters, and its pending callbacks, gathers,
+    /// asks, reminders and subscriptions are removed.
+    pub fn stop(&mut self) {
+        self.dispatcher.stop();
+    }
+
+    pub fn gather<R>(
+        &mut self,
+        callbacks: u32,
+        policy: GatherPolicy,
+    ) -> DispatchResult<Vec<CallbackId<R>>>
+    where
+        R: Message,
+        A: Callback<Gathered, Env = ()>,
+    {
+        self.gather_with_env(callbacks, policy, ())
+    }
+
+    /// Creates `callbacks` callbacks whose responses are collected together. Once `policy` is
+    /// met this actor's `Callback<Gathered>` implementation is called once with all of them, and
+    /// `env`. Like a callback the gather is stored with the actor's storage, so it survives
+    /// restarts, and only exists once the effects of the handler are committed.
+    pub fn gather_with_env<R>(
+        &mut self,
+        callbacks: u32,
+        policy: GatherPolicy,
+        env: <A as Callback<Gathered>>::Env,
+    ) -> DispatchResult<Vec<CallbackId<R>>>
+    where
+        R: Message,
+        A: Callback<Gathered>,
+    {
+        let id = self.next_callback_id();
+        let actor_id = self.actor_id.into_any();
+        self.runtime_storage.put(callback_key(id), env);
+        let mut state = GatherState::new::<R>(callbacks, policy.quorum);
+        if state.is_complete() {
+            // Nothing to wait for
+            let gathered = state.into_gathered(false);
+            self.dispatcher.send_unchecked(
+                actor_id,
+                gathered,
+                Some(CallbackTarget::Callback(id)),
+            )?;
+        } else {
+            if let Some(timeout) = policy.timeout {
+                let at = self.dispatcher.now() + timeout;
+                state.timeout = Some(self.dispatcher.gather_timeout(id, at)?);
+            }
+            self.runtime_storage.put(gather::state_key(id), state);
         }
+        Ok((0..callbacks)
+            .map(|index| CallbackId::gather(actor_id, id, index))
+            .collect())
+    }
+
+    /// Subscribes to the messages `M` published to `topic`. The subscription is stored in the
+    /// database once the effects of the handler are committed, and lasts until
+    /// [`Context::unsubscribe`] is called.
+    pub fn subscribe<M>(&mut self, topic: impl Into<String>)
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        let subscription = self.subscription::<M>(topic.into());
+        self.dispatcher.subscribe(subscription);
+    }
+
+    pub fn unsubscribe<M>(&mut self, topic: impl Into<String>)
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        let subscription = self.subscription::<M>(topic.into());
+        self.dispatcher.unsubscribe(subscription);
+    }
+
+    /// Sends `message` to every actor subscribed to messages `M` on `topic` when the effects of
+    /// the handler are committed, with the same guarantees as [`Dispatcher::send`].
+    pub fn publish<M>(&mut self, topic: impl Into<String>, message: M) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        self.dispatcher.publish(topic.into(), message)
+    }
+
+    /// Lets a quarantined actor run again, starting with a new attempt at the message that got it
+    /// quarantined. Does nothing if the actor is not quarantined.
+    pub fn release_actor(&mut self, actor_id: AnyActorId) {
+        self.dispatcher
+            .quarantine(QuarantineCommand::Release(actor_id));
+    }
+
+    /// Moves the message that got `actor_id` quarantined to the dead letters and lets the actor
+    /// run again.
+    pub fn skip_quarantined_message(&mut self, actor_id: AnyActorId) {
+        self.dispatcher
+            .quarantine(QuarantineCommand::Skip(actor_id));
+    }
+
+    /// Replaces the message that got `actor_id` quarantined with `message` and lets the actor run
+    /// again, see [`crate::quarantine::ActorQuarantined::decode`].
+    pub fn fix_quarantined_message<M>(
+        &mut self,
+        actor_id: AnyActorId,
+        message: M,
+    ) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        self.dispatcher.fix_quarantined_message(actor_id, message)
+    }
+
+    fn subscription<M>(&self, topic: String) -> Subscription
+    where
+        M: Message,
+    {
+        Subscription {
+            topic,
+            actor_id: self.actor_id.into_any(),
+            message: MessageName::name_for::<M>(),
+        }
+    }
+
+    /// Sends `request` to `actor_id` and waits for its reply, see
+    /// [`crate::handler::AsyncHandler`]. Fails right away outside of an async handler.
+    pub fn ask<B, Req, Resp>(&mut self, actor_id: PersistentActorId<B>, request: Req) -> Ask<Resp>
+    where
+        B: Receives<Req>,
+        Req: Message,
+        Resp: Message,
+    {
+        let mut invocation = match self.invocation.take() {
+            Some(invocation) => invocation,
+            None => return Ask::ready(Err(DispatchError::NotAsync)),
+        };
+        let index = invocation.next_index();
+        let ask = match invocation.response(index) {
+            Some(response) => Ask::ready(response),
+            None if invocation.mark_requested(index) => {
+                let id = *invocation.id.get_or_insert_with(|| self.next_callback_id());
+                let reply_to = AnyCallbackId {
+                    actor_id: self.actor_id.into_any(),
+                    target: CallbackTarget::Ask {
+                        invocation: id,
+                        index,
+                    },
+                    message: MessageName::name_for::<Resp>(),
+                };
+                match self
+                    .dispatcher
+                    .send_with_reply_to(actor_id.into_any(), request, reply_to)
+                {
+                    Ok(()) => Ask::pending(),
+                    Err(err) => Ask::ready(Err(err)),
+                }
+            }
+            // Requested by a previous run, still waiting for the response
+            None => Ask::pending(),
+        };
+        self.invocation = Some(invocation);
+        ask
+    }
+
+    /// Effects of an async handler run. If the handler completed they are all committed,
+    /// otherwise only the requests of its asks are, together with the state of the run.
+    pub(crate) fn into_async_effects(mut self, completed: bool) -> DispatchResult<Effects> {
+        let invocation = self.invocation.take().unwrap();
+        if completed {
+            return Ok(self.into_effects());
+        }
+        let id = invocation.id.ok_or(DispatchError::Stalled)?;
+        let suspended = invocation.suspend(self.sender, self.reply_to)?;
+        // Callback ids handed out by this run, including `id`, must not be reused
+        let next_callback = self.runtime_storage.take::<u64, _>(NEXT_CALLBACK_KEY);
+        self.storage.discard();
+        self.runtime_storage.discard();
+        self.shared.clear();
+        self.dispatcher.retain_asks();
+        if let Some(next_callback) = next_callback {
+            self.runtime_storage.put(NEXT_CALLBACK_KEY, next_callback);
+        }
+        self.runtime_storage.put(ask::state_key(id), suspended);
+        Ok(self.into_effects())
+    }
+
+    /// Removes the callback `id`, returning its environment if it had not been called yet. The
+    /// expiry of the callback, if any, is cancelled.
+    pub(crate) fn take_callback_env<M>(&mut self, id: u64) -> Option<A::Env>
+    where
+        M: Message,
+        A: Callback<M>,
+    {
+        if let Some(timer) = self.runtime_storage.take::<TimerId, _>(expiry_key(id)) {
+            self.dispatcher.cancel_timer(timer);
+        }
+        self.runtime_storage.take(callback_key(id))
+    }
+
+    fn next_callback_id(&mut self) -> u64 {
+        let id = self
+            .runtime_storage
+            .take::<u64, _>(NEXT_CALLBACK_KEY)
+            .unwrap_or(0);
+        self.runtime_storage.put(NEXT_CALLBACK_KEY, id + 1);
+        id
+    }
+
+    /// Storage for the namespace `namespace`, visible to every actor that opens it.
+    pub fn shared_storage(&mut self, namespace: &str) -> &mut GlobalStorage<'a> {
+        let db = self.storage.db();
+        self.shared
+            .entry(namespace.to_string())
+            .or_insert_with(|| GlobalStorage::shared(db, namespace))
     }
 
     pub fn into_effects(self) -> Effects {
-        Effects {
-            messages: self.dispatcher.messages,
-            global_effects: self.storage.effects,
+        let mut effects = Effects::default();
+        self.dispatcher
+            .into_effects(&mut effects, self.storage.db());
+        self.storage.into_effects(&mut effects);
+        self.runtime_storage.into_effects(&mut effects);
+        for storage in self.shared.into_values() {
+            storage.into_effects(&mut effects);
         }
+        effects
     }
 }
 
 impl<'a> AnyContext<'a> {
-    pub fn new(id: AnyActorId, db: &'a Database, cache: &'a mut GlobalStorageCache) -> Self {
+    pub fn new(
+        id: AnyActorId,
+        table: &'a DynTable,
+        db: &'a Database,
+        cache: &'a mut GlobalStorageCache,
+        config: RunConfig,
+    ) -> Self {
         Self {
             id,
-            storage: GlobalStorage::new(db, cache),
-            dispatcher: Dispatcher::new(),
+            storage: GlobalStorage::private(id, db, cache),
+            runtime_storage: GlobalStorage::runtime(id, db),
+            dispatcher: Dispatcher::new(id, config),
+            shared: HashMap::new(),
+            table,
+            sender: None,
+            reply_to: None,
+            attempt: 1,
+            envelope: None,
         }
     }
 
@@ -68,7 +500,38 @@ impl<'a> AnyContext<'a> {
         self.id.downcast::<A>().map(|actor_id| Context {
             actor_id,
             storage: self.storage,
+            runtime_storage: self.runtime_storage,
             dispatcher: self.dispatcher,
+            shared: self.shared,
+            table: self.table,
+            sender: self.sender,
+            reply_to: self.reply_to,
+            attempt: self.attempt,
+            envelope: self.envelope,
+            invocation: None,
         })
     }
+
+    /// Effects of a run that did not get to the handler of the actor, see
+    /// [`Context::into_effects`].
+    pub(crate) fn into_effects(self) -> Effects {
+        let mut effects = Effects::default();
+        self.dispatcher
+            .into_effects(&mut effects, self.storage.db());
+        self.storage.into_effects(&mut effects);
+        self.runtime_storage.into_effects(&mut effects);
+        for storage in self.shared.into_values() {
+            storage.into_effects(&mut effects);
+        }
+        effects
+    }
+}
+
+fn callback_key(id: u64) -> String {
+    format!("callback/{}", id)
+}
+
+/// Key of the timer expiring the callback `id`.
+fn expiry_key(id: u64) -> String {
+    format!("callback/{}/expiry", id)
 }
@@ -0,0 +1,339 @@
+use std::{any::TypeId, collections::BTreeSet, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{Bytes, DbError, PersistentValue, ValueUpcasters, VALUE_CODEC},
+    global_storage::{GlobalEffect, StorageError},
+    schema::{self, Upcasters},
+};
+
+/// A value whose updates commute. Handlers record a delta with `merge` instead of reading and
+/// writing the value, and the database merges the delta into the stored value at commit time.
+/// Since no read is recorded, concurrent merges never conflict.
+pub trait Crdt: PersistentValue + Default {
+    fn merge(&mut self, other: Self);
+}
+
+/// Grow-only set. Elements are kept encoded so the database can add to the set without knowing
+/// the type of its elements, see [`GlobalEffect::SetAdd`].
+#[derive(Serialize, Deserialize)]
+pub struct GSet<T> {
+    elements: BTreeSet<Bytes>,
+    _marker: PhantomData<T>,
+}
+
+/// Register that only keeps the largest value ever merged into it.
+#[derive(Serialize, Deserialize)]
+pub struct Max<T>(pub Option<T>);
+
+pub type MergeFn = fn(&ValueUpcasters, Option<&[u8]>, &[u8]) -> Result<Bytes, DbError>;
+
+/// An encoded [`Crdt`] together with the function merging it into a stored value of its type.
+pub struct Delta {
+    bytes: Bytes,
+    merge: MergeFn,
+}
+
+impl<T> GSet<T>
+where
+    T: Serialize + for<'a> Deserialize<'a>,
+{
+    pub fn insert(&mut self, element: &T) -> bool {
+        self.elements.insert(encode_element(element))
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(&encode_element(element))
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.elements
+            .iter()
+            .filter_map(|bytes| VALUE_CODEC.decode(bytes).ok())
+    }
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> PersistentValue for GSet<T> where T: 'static + Serialize + for<'a> Deserialize<'a> {}
+
+impl<T> Crdt for GSet<T>
+where
+    T: 'static + Serialize + for<'a> Deserialize<'a>,
+{
+    fn merge(&mut self, other: Self) {
+        self.elements.extend(other.elements);
+    }
+}
+
+impl<T> Default for Max<T> {
+    fn default() -> Self {
+        Max(None)
+    }
+}
+
+impl<T> PersistentValue for Max<T> where T: 'static + Ord + Serialize + for<'a> Deserialize<'a> {}
+
+impl<T> Crdt for Max<T>
+where
+    T: 'static + Ord + Serialize + for<'a> Deserialize<'a>,
+{
+    fn merge(&mut self, other: Self) {
+        if other.0 > self.0 {
+            self.0 = other.0;
+        }
+    }
+}
+
+impl Delta {
+    pub fn new<C>(value: C) -> Self
+    where
+        C: Crdt,
+    {
+        Self {
+            bytes: encode_in_handler(&value),
+            merge: merge_bytes::<C>,
+        }
+    }
+}
+
+impl GlobalEffect {
+    /// Whether the effect can be applied without knowing the value it is applied to.
+    pub fn is_commutative(&self) -> bool {
+        !matches!(self, GlobalEffect::Modified(_) | GlobalEffect::Deleted)
+    }
+
+    /// Applies a commutative effect to the encoded value `base`, `None` if the key has no value.
+    /// Fails if `base` cannot be decoded or an increment overflows.
+    pub(crate) fn merge_into(
+        &self,
+        upcasters: &ValueUpcasters,
+        base: Option<&[u8]>,
+    ) -> Result<Bytes, DbError> {
+        match self {
+            GlobalEffect::Increment(n) => {
+                let value: i64 = decode_or_default(upcasters, base)?;
+                encode(&value.checked_add(*n).ok_or(DbError)?)
+            }
+            GlobalEffect::MaxRegister(n) => {
+                let value: Option<i64> = base.map(|base| decode(upcasters, base)).transpose()?;
+                encode(&value.map_or(*n, |value| value.max(*n)))
+            }
+            GlobalEffect::SetAdd(elements) => {
+                let mut set: GSet<()> = decode_or_default(upcasters, base)?;
+                set.elements.extend(elements.iter().cloned());
+                encode(&set)
+            }
+            GlobalEffect::Merge(delta) => (delta.merge)(upcasters, base, &delta.bytes),
+            GlobalEffect::Modified(_) | GlobalEffect::Deleted => {
+                unreachable!("not a commutative effect")
+            }
+        }
+    }
+
+    /// Combines two commutative effects on the same key into one. Returns `None` if they
+    /// cannot be applied to the same type of value, or if two increments overflow.
+    pub(crate) fn combine(self, other: GlobalEffect) -> Option<GlobalEffect> {
+        match (self, other) {
+            (GlobalEffect::Increment(a), GlobalEffect::Increment(b)) => {
+                Some(GlobalEffect::Increment(a.checked_add(b)?))
+            }
+            (GlobalEffect::MaxRegister(a), GlobalEffect::MaxRegister(b)) => {
+                Some(GlobalEffect::MaxRegister(a.max(b)))
+            }
+            (GlobalEffect::SetAdd(mut a), GlobalEffect::SetAdd(b)) => {
+                a.extend(b);
+                Some(GlobalEffect::SetAdd(a))
+            }
+            (GlobalEffect::Merge(a), GlobalEffect::Merge(b)) => {
+                // Deltas are encoded by the running code, they never need upcasting
+                let bytes = (b.merge)(&Upcasters::new(), Some(&a.bytes), &b.bytes).ok()?;
+                Some(GlobalEffect::Merge(Delta { bytes, ..b }))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn merge_bytes<C>(
+    upcasters: &ValueUpcasters,
+    base: Option<&[u8]>,
+    delta: &[u8],
+) -> Result<Bytes, DbError>
+where
+    C: Crdt,
+{
+    let mut value: C = decode_or_default(upcasters, base)?;
+    value.merge(decode(upcasters, delta)?);
+    encode(&value)
+}
+
+fn encode<V>(value: &V) -> Result<Bytes, DbError>
+where
+    V: PersistentValue,
+{
+    schema::encode(VALUE_CODEC, V::VERSION, value).map_err(|_| DbError)
+}
+
+/// Encodes a value inside a handler, failing like any other storage error.
+fn encode_in_handler<V>(value: &V) -> Bytes
+where
+    V: PersistentValue,
+{
+    encode(value).unwrap_or_else(|_| std::panic::panic_any(StorageError::Value))
+}
+
+/// Encodes an element of a [`GSet`]. Elements are not versioned on their own, they are part of
+/// the encoding of the set.
+pub(crate) fn encode_element<T>(element: &T) -> Bytes
+where
+    T: Serialize,
+{
+    VALUE_CODEC
+        .encode(element)
+        .unwrap_or_else(|_| std::panic::panic_any(StorageError::Value))
+}
+
+fn decode<V>(upcasters: &ValueUpcasters, bytes: &[u8]) -> Result<V, DbError>
+where
+    V: PersistentValue,
+{
+    upcasters
+        .decode(TypeId::of::<V>(), VALUE_CODEC, V::VERSION, bytes)
+        .map_err(|_| DbError)
+}
+
+fn decode_or_default<V>(upcasters: &ValueUpcasters, bytes: Option<&[u8]>) -> Result<V, DbError>
+where
+    V: PersistentValue + Default,
+{
+    bytes.map_or_else(|| Ok(V::default()), |bytes| decode(upcasters, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode_element, Delta, GSet, Max};
+    use crate::{
+        database::{Bytes, PersistentValue},
+        global_storage::GlobalEffect,
+        schema::Upcasters,
+    };
+
+    const ORDERS: [[usize; 3]; 6] = [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ];
+
+    /// Applies the effects built by `effect` in every order, checking they all end up with the
+    /// same value, and returns it.
+    fn apply_in_any_order<V>(effect: impl Fn(usize) -> GlobalEffect) -> V
+    where
+        V: PersistentValue,
+    {
+        let results: Vec<Bytes> = ORDERS
+            .iter()
+            .map(|order| {
+                let mut value: Option<Bytes> = None;
+                for &i in order {
+                    let merged = effect(i).merge_into(&Upcasters::new(), value.as_deref());
+                    value = Some(merged.ok().unwrap());
+                }
+                value.unwrap()
+            })
+            .collect();
+        assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+        decode(&Upcasters::new(), &results[0]).ok().unwrap()
+    }
+
+    fn gset(elements: &[u32]) -> GSet<u32> {
+        let mut set = GSet::default();
+        for element in elements {
+            set.insert(element);
+        }
+        set
+    }
+
+    #[test]
+    fn increments_commute() {
+        let value: i64 = apply_in_any_order(|i| GlobalEffect::Increment([3, -5, 10][i]));
+        assert_eq!(value, 8);
+    }
+
+    #[test]
+    fn max_registers_commute() {
+        let value: i64 = apply_in_any_order(|i| GlobalEffect::MaxRegister([3, 12, -1][i]));
+        assert_eq!(value, 12);
+    }
+
+    #[test]
+    fn set_adds_commute() {
+        let elements: [&[u32]; 3] = [&[1, 2], &[2, 3], &[5]];
+        let set: GSet<u32> = apply_in_any_order(|i| {
+            GlobalEffect::SetAdd(elements[i].iter().map(encode_element).collect())
+        });
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn crdt_merges_commute() {
+        let max: Max<u32> =
+            apply_in_any_order(|i| GlobalEffect::Merge(Delta::new(Max(Some([4, 9, 7][i])))));
+        assert_eq!(max.0, Some(9));
+        let elements: [&[u32]; 3] = [&[1], &[1, 4], &[2]];
+        let set: GSet<u32> =
+            apply_in_any_order(|i| GlobalEffect::Merge(Delta::new(gset(elements[i]))));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn combining_matches_applying_in_turn() {
+        let combined = GlobalEffect::Increment(3).combine(GlobalEffect::Increment(4));
+        assert!(matches!(combined, Some(GlobalEffect::Increment(7))));
+        let combined = GlobalEffect::MaxRegister(3).combine(GlobalEffect::MaxRegister(-4));
+        assert!(matches!(combined, Some(GlobalEffect::MaxRegister(3))));
+        let combined = GlobalEffect::Merge(Delta::new(gset(&[1])))
+            .combine(GlobalEffect::Merge(Delta::new(gset(&[2]))))
+            .unwrap();
+        let bytes = combined.merge_into(&Upcasters::new(), None).ok().unwrap();
+        let set: GSet<u32> = decode(&Upcasters::new(), &bytes).ok().unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn effects_on_different_types_do_not_combine() {
+        let combined = GlobalEffect::Increment(1).combine(GlobalEffect::MaxRegister(1));
+        assert!(combined.is_none());
+    }
+
+    #[test]
+    fn increments_do_not_overflow() {
+        let combined = GlobalEffect::Increment(i64::MAX).combine(GlobalEffect::Increment(1));
+        assert!(combined.is_none());
+        let base = GlobalEffect::Increment(i64::MAX)
+            .merge_into(&Upcasters::new(), None)
+            .ok()
+            .unwrap();
+        let merged = GlobalEffect::Increment(1).merge_into(&Upcasters::new(), Some(&base));
+        assert!(merged.is_err());
+    }
+}
@@ -1,31 +1,324 @@
+use std::{any::TypeId, collections::HashMap};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    codec::CodecId,
+    global_storage::GlobalEffect,
+    schema::{SchemaVersion, Upcasters},
+};
+
 pub type Bytes = Vec<u8>;
 
+/// Version of a key in the database. Every commit bumps the version of the keys it writes, a key
+/// that was never written is at [`Version::ZERO`].
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
+pub struct Version(u64);
+
 pub struct DbError;
 
+pub enum CommitError {
+    /// A key read by the transaction was modified by another commit before it was applied.
+    Conflict { key: String },
+    /// A commutative effect could not be merged into the value stored at the key.
+    Value { key: String },
+    /// A key guarded by the transaction is not at the version it required.
+    Guard {
+        key: String,
+        expected: Version,
+        actual: Version,
+    },
+}
+
 pub type DbResult<T> = Result<T, DbError>;
 
-pub struct Database;
+/// Codec persisted values are encoded with.
+pub const VALUE_CODEC: CodecId = CodecId::MessagePack;
+
+/// Upcasters for persisted values, keyed by the type of the value.
+pub type ValueUpcasters = Upcasters<TypeId>;
+
+pub struct Database {
+    values: HashMap<String, Versioned>,
+    last_version: Version,
+    upcasters: ValueUpcasters,
+}
+
+/// Value stored for a key. Deleted keys are kept around as tombstones so that a delete followed
+/// by a write is still seen as a change by readers of the key.
+struct Versioned {
+    version: Version,
+    bytes: Option<Bytes>,
+}
+
+impl Version {
+    pub const ZERO: Version = Version(0);
+
+    fn next(self) -> Version {
+        Version(self.0 + 1)
+    }
+}
 
 impl Database {
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+            last_version: Version::ZERO,
+            upcasters: Upcasters::new(),
+        }
+    }
+
+    /// Registers `upcast` to migrate values of type `V` stored at schema version `from` to
+    /// version `from + 1` when they are read.
+    pub fn register_upcaster<V, Old, New>(&mut self, from: SchemaVersion, upcast: fn(Old) -> New)
+    where
+        V: PersistentValue,
+        Old: 'static + for<'a> Deserialize<'a>,
+        New: 'static + Serialize,
+    {
+        self.upcasters.register(TypeId::of::<V>(), from, upcast);
+    }
+
+    pub(crate) fn upcasters(&self) -> &ValueUpcasters {
+        &self.upcasters
+    }
+
     pub fn get_resource<V>(&self, key: &str) -> DbResult<Option<V>>
     where
         V: PersistentValue,
     {
-        todo!()
+        self.get_versioned(key).map(|(_, value)| value)
     }
 
-    pub fn update_resource<V>(&mut self, key: String, value: V) -> DbResult<bool>
+    /// Like [`Database::get_resource`] but also returns the version the value was read at.
+    pub fn get_versioned<V>(&self, key: &str) -> DbResult<(Version, Option<V>)>
     where
         V: PersistentValue,
     {
-        todo!()
+        match self.values.get(key) {
+            Some(Versioned {
+                version,
+                bytes: Some(bytes),
+            }) => {
+                let value = self
+                    .upcasters
+                    .decode(TypeId::of::<V>(), VALUE_CODEC, V::VERSION, bytes)
+                    .map_err(|_| DbError)?;
+                Ok((*version, Some(value)))
+            }
+            Some(Versioned {
+                version,
+                bytes: None,
+            }) => Ok((*version, None)),
+            None => Ok((Version::ZERO, None)),
+        }
+    }
+
+    /// Length of the bytes stored at `key`, zero if it has no value.
+    pub(crate) fn stored_size(&self, key: &str) -> usize {
+        self.values
+            .get(key)
+            .and_then(|versioned| versioned.bytes.as_ref())
+            .map_or(0, |bytes| bytes.len())
+    }
+
+    /// Whether `key` has a value, readable or not.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values
+            .get(key)
+            .is_some_and(|versioned| versioned.bytes.is_some())
+    }
+
+    /// Keys starting with `prefix` that have a value.
+    pub fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values
+            .iter()
+            .filter(move |(key, versioned)| key.starts_with(prefix) && versioned.bytes.is_some())
+            .map(|(key, _)| key.as_str())
+    }
+
+    pub fn version(&self, key: &str) -> Version {
+        self.values
+            .get(key)
+            .map_or(Version::ZERO, |versioned| versioned.version)
     }
+
+    /// Atomically applies `effects` if none of the keys in `reads` changed since the version
+    /// recorded for them and every key in `guards` is at the required version. Otherwise nothing
+    /// is written and [`CommitError::Conflict`] or [`CommitError::Guard`] is returned.
+    /// Commutative effects are merged into the value stored at the time of the commit.
+    pub fn commit(
+        &mut self,
+        reads: &HashMap<String, Version>,
+        guards: &HashMap<String, Version>,
+        effects: HashMap<String, GlobalEffect>,
+    ) -> Result<(), CommitError> {
+        for (key, version) in reads {
+            if self.version(key) != *version {
+                return Err(CommitError::Conflict { key: key.clone() });
+            }
+        }
+        for (key, expected) in guards {
+            let actual = self.version(key);
+            if actual != *expected {
+                return Err(CommitError::Guard {
+                    key: key.clone(),
+                    expected: *expected,
+                    actual,
+                });
+            }
+        }
+        if effects.is_empty() {
+            return Ok(());
+        }
+        let mut writes = Vec::with_capacity(effects.len());
+        for (key, effect) in effects {
+            let bytes = match effect {
+                GlobalEffect::Modified(bytes) => Some(bytes),
+                GlobalEffect::Deleted => None,
+                effect => {
+                    let base = self
+                        .values
+                        .get(&key)
+                        .and_then(|value| value.bytes.as_deref());
+                    match effect.merge_into(&self.upcasters, base) {
+                        Ok(bytes) => Some(bytes),
+                        Err(_) => return Err(CommitError::Value { key }),
+                    }
+                }
+            };
+            writes.push((key, bytes));
+        }
+        let version = self.last_version.next();
+        self.last_version = version;
+        for (key, bytes) in writes {
+            self.values.insert(key, Versioned { version, bytes });
+        }
+        Ok(())
+    }
+}
+
+pub trait PersistentValue: 'static + Serialize + for<'a> Deserialize<'a> {
+    /// Bump when the encoding of the type changes, registering an upcaster from the previous
+    /// version with [`Database::register_upcaster`].
+    const VERSION: SchemaVersion = 0;
 }
 
-pub trait PersistentValue: 'static + Serialize + for<'a> Deserialize<'a> {}
+impl PersistentValue for () {}
 
 impl PersistentValue for u32 {}
 
+impl PersistentValue for u64 {}
+
+impl PersistentValue for i64 {}
+
 impl PersistentValue for bool {}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{CommitError, Database, Version};
+    use crate::global_storage::GlobalEffect;
+
+    fn write(db: &mut Database, key: &str, value: u64) -> Result<(), CommitError> {
+        let bytes = crate::schema::encode(super::VALUE_CODEC, 0, &value)
+            .ok()
+            .unwrap();
+        let effects = HashMap::from([(key.to_string(), GlobalEffect::Modified(bytes))]);
+        db.commit(&HashMap::new(), &HashMap::new(), effects)
+    }
+
+    fn read(db: &Database, key: &str) -> Option<u64> {
+        db.get_resource(key).ok().unwrap()
+    }
+
+    #[test]
+    fn commits_bump_versions() {
+        let mut db = Database::new();
+        assert_eq!(db.version("a"), Version::ZERO);
+        assert!(write(&mut db, "a", 1).is_ok());
+        let first = db.version("a");
+        assert!(first > Version::ZERO);
+        assert!(write(&mut db, "b", 2).is_ok());
+        assert_eq!(db.version("a"), first);
+        assert!(db.version("b") > first);
+        assert_eq!(read(&db, "a"), Some(1));
+    }
+
+    #[test]
+    fn deleted_keys_keep_their_version() {
+        let mut db = Database::new();
+        assert!(write(&mut db, "a", 1).is_ok());
+        let effects = HashMap::from([("a".to_string(), GlobalEffect::Deleted)]);
+        assert!(db.commit(&HashMap::new(), &HashMap::new(), effects).is_ok());
+        assert_eq!(read(&db, "a"), None);
+        assert!(db.version("a") > Version::ZERO);
+        assert_eq!(db.keys_with_prefix("").count(), 0);
+    }
+
+    #[test]
+    fn detects_conflicting_reads() {
+        let mut db = Database::new();
+        assert!(write(&mut db, "a", 1).is_ok());
+        let reads = HashMap::from([("a".to_string(), db.version("a"))]);
+        assert!(write(&mut db, "a", 2).is_ok());
+        let bytes = crate::schema::encode(super::VALUE_CODEC, 0, &3u64)
+            .ok()
+            .unwrap();
+        let effects = HashMap::from([("b".to_string(), GlobalEffect::Modified(bytes))]);
+        let result = db.commit(&reads, &HashMap::new(), effects);
+        assert!(matches!(result, Err(CommitError::Conflict { key }) if key == "a"));
+        // Nothing was written
+        assert_eq!(read(&db, "b"), None);
+    }
+
+    #[test]
+    fn unchanged_reads_commit() {
+        let mut db = Database::new();
+        assert!(write(&mut db, "a", 1).is_ok());
+        let reads = HashMap::from([
+            ("a".to_string(), db.version("a")),
+            ("missing".to_string(), Version::ZERO),
+        ]);
+        let effects = HashMap::from([("b".to_string(), GlobalEffect::Increment(1))]);
+        assert!(db.commit(&reads, &HashMap::new(), effects).is_ok());
+        assert_eq!(db.get_resource::<i64>("b").ok().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn detects_failed_guards() {
+        let mut db = Database::new();
+        assert!(write(&mut db, "a", 1).is_ok());
+        let actual = db.version("a");
+        let guards = HashMap::from([("a".to_string(), Version::ZERO)]);
+        let result = db.commit(&HashMap::new(), &guards, HashMap::new());
+        assert!(matches!(
+            result,
+            Err(CommitError::Guard { key, expected, actual: found })
+                if key == "a" && expected == Version::ZERO && found == actual
+        ));
+        let guards = HashMap::from([("a".to_string(), actual)]);
+        assert!(db.commit(&HashMap::new(), &guards, HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn failed_merges_write_nothing() {
+        let mut db = Database::new();
+        assert!(write(&mut db, "a", 1).is_ok());
+        let effects = HashMap::from([
+            ("b".to_string(), GlobalEffect::Increment(1)),
+            ("c".to_string(), GlobalEffect::Increment(i64::MAX)),
+        ]);
+        assert!(db.commit(&HashMap::new(), &HashMap::new(), effects).is_ok());
+        let version = db.version("c");
+        let effects = HashMap::from([
+            ("b".to_string(), GlobalEffect::Increment(1)),
+            ("c".to_string(), GlobalEffect::Increment(1)),
+        ]);
+        let result = db.commit(&HashMap::new(), &HashMap::new(), effects);
+        assert!(matches!(result, Err(CommitError::Value { key }) if key == "c"));
+        assert_eq!(db.version("c"), version);
+        assert_eq!(db.get_resource::<i64>("b").ok().unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    codec::CodecId,
+    database::{Bytes, Database, DbResult, PersistentValue},
+    dyn_table::{DispatchError, DispatchResult, HandlerError},
+    envelope::Envelope,
+    message::{AnyMessage, Message, MessageName},
+    timer::Timestamp,
+};
+
+/// Prefix of the keys dead letters are stored under, in the runtime's part of the database.
+const DEAD_LETTERS_PREFIX: &str = "runtime/dead_letters/";
+/// Key of the next dead letter id, a max register like the next timer id.
+pub(crate) const NEXT_DEAD_LETTER_KEY: &str = "runtime/next_dead_letter";
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DeadLetterId(u64);
+
+/// Why a message could not be handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FailureKind {
+    /// The recipient has no handler for the message.
+    MethodNotFound,
+    TypeMissmatch,
+    /// The message, or a message sent by the handler, could not be encoded or decoded.
+    Codec,
+    /// Any other [`DispatchError`] raised by the handler, e.g. a `reply` without a sender.
+    Dispatch,
+    /// The handler returned an error, see [`crate::handler::Handler::Error`].
+    Handler,
+    /// The handler could not read its storage, or its effects could not be committed.
+    Storage,
+    Panic,
+    /// The recipient does not exist in the runtime.
+    ActorNotFound,
+    /// The recipient, or the recipient of a message sent by the handler, had no room left in
+    /// its mailbox.
+    MailboxFull,
+    /// The recipient already received a message its sender sent after this one, handling it
+    /// would break the order of the sender's messages.
+    OutOfOrder,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub description: String,
+    /// The error returned by the handler, for failures of kind [`FailureKind::Handler`]. Only
+    /// the failure supervision decides on has it, stored and cloned failures keep its
+    /// description.
+    #[serde(skip)]
+    error: Option<Box<HandlerError>>,
+}
+
+/// A message that could not be delivered or handled, as stored in the database. It stays there
+/// until it is replayed or discarded through the [`crate::runtime::Runtime`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: DeadLetterId,
+    pub from: AnyActorId,
+    pub to: AnyActorId,
+    pub failure: Failure,
+    /// How many times handling the message was attempted before it was given up on.
+    pub attempts: u32,
+    pub failed_at: Timestamp,
+    pub envelope: Envelope,
+    message: MessageName,
+    codec: CodecId,
+    bytes: Bytes,
+}
+
+impl PersistentValue for DeadLetter {}
+
+impl DeadLetterId {
+    pub(crate) fn new(id: u64) -> Self {
+        DeadLetterId(id)
+    }
+
+    pub(crate) fn key(self) -> String {
+        format!("{}{}", DEAD_LETTERS_PREFIX, self.0)
+    }
+}
+
+impl Failure {
+    pub(crate) fn new(kind: FailureKind, description: impl Into<String>) -> Self {
+        Self {
+            kind,
+            description: description.into(),
+            error: None,
+        }
+    }
+
+    /// The error the handler returned, use [`HandlerError::downcast_ref`] to get it back as the
+    /// type of the handler's error.
+    pub fn handler_error(&self) -> Option<&HandlerError> {
+        self.error.as_deref()
+    }
+}
+
+impl Clone for Failure {
+    fn clone(&self) -> Self {
+        Failure::new(self.kind, self.description.clone())
+    }
+}
+
+impl From<DispatchError> for Failure {
+    fn from(err: DispatchError) -> Self {
+        let kind = match &err {
+            DispatchError::Handler(_) => FailureKind::Handler,
+            DispatchError::MethodNotFound => FailureKind::MethodNotFound,
+            DispatchError::MailboxFull(_) => FailureKind::MailboxFull,
+            DispatchError::TypeMissmatch => FailureKind::TypeMissmatch,
+            DispatchError::Encode { .. } | DispatchError::Decode { .. } => FailureKind::Codec,
+            DispatchError::CallbackNotFound
+            | DispatchError::NoSender
+            | DispatchError::NotAsync
+            | DispatchError::Stalled => FailureKind::Dispatch,
+        };
+        match err {
+            DispatchError::Handler(error) => Failure {
+                kind,
+                description: error.description().to_string(),
+                error: Some(Box::new(error)),
+            },
+            err => Failure::new(kind, format!("{:?}", err)),
+        }
+    }
+}
+
+impl DeadLetter {
+    pub(crate) fn new(
+        id: DeadLetterId,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: &AnyMessage,
+        failure: Failure,
+        attempts: u32,
+        failed_at: Timestamp,
+    ) -> DispatchResult<Self> {
+        Ok(Self {
+            id,
+            from,
+            to,
+            failure,
+            attempts,
+            failed_at,
+            envelope: message.envelope.clone(),
+            message: message.name,
+            codec: message.codec,
+            bytes: message.bytes()?.into_owned(),
+        })
+    }
+
+    /// Name of the message, to know what to decode it as with [`DeadLetter::decode`].
+    pub fn message_name(&self) -> MessageName {
+        self.message
+    }
+
+    pub fn decode<M>(&self) -> DispatchResult<M>
+    where
+        M: Message,
+    {
+        self.message().downcast()
+    }
+
+    pub(crate) fn message(&self) -> AnyMessage {
+        AnyMessage::from_parts(self.message, self.codec, self.bytes.clone())
+            .with_envelope(self.envelope.clone())
+    }
+}
+
+/// The dead letters stored in `db`, oldest first.
+pub(crate) fn load(db: &Database) -> DbResult<Vec<DeadLetter>> {
+    let mut dead_letters = Vec::new();
+    for key in db.keys_with_prefix(DEAD_LETTERS_PREFIX) {
+        dead_letters.extend(db.get_resource::<DeadLetter>(key)?);
+    }
+    dead_letters.sort_by_key(|dead_letter| dead_letter.id);
+    Ok(dead_letters)
+}
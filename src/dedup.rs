@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{actor::AnyActorId, database::PersistentValue};
+
+/// Prefix of the keys dedup windows are stored under, in the runtime's part of the database.
+const DEDUP_PREFIX: &str = "runtime/dedup/";
+/// Idempotency keys remembered per recipient unless the runtime is configured otherwise.
+pub const DEFAULT_DEDUP_WINDOW: usize = 1024;
+
+/// The idempotency keys of the last messages handled by an actor, oldest first. A message with
+/// one of them is a duplicate and is dropped before dispatch.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct DedupWindow {
+    keys: VecDeque<String>,
+}
+
+impl PersistentValue for DedupWindow {}
+
+impl DedupWindow {
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.keys.iter().any(|k| k == key)
+    }
+
+    /// Adds `key`, forgetting the oldest keys so at most `size` are kept.
+    pub(crate) fn insert(&mut self, key: String, size: usize) {
+        self.keys.push_back(key);
+        while self.keys.len() > size {
+            self.keys.pop_front();
+        }
+    }
+}
+
+/// Key the dedup window of `actor_id` is stored under.
+pub(crate) fn window_key(actor_id: AnyActorId) -> String {
+    format!("{}{}", DEDUP_PREFIX, actor_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DedupWindow;
+
+    fn window(keys: &[&str], size: usize) -> DedupWindow {
+        let mut window = DedupWindow::default();
+        for key in keys {
+            window.insert(key.to_string(), size);
+        }
+        window
+    }
+
+    #[test]
+    fn inserted_keys_are_remembered() {
+        let window = window(&["a", "b"], 3);
+        assert!(window.contains("a"));
+        assert!(window.contains("b"));
+        assert!(!window.contains("c"));
+    }
+
+    #[test]
+    fn oldest_keys_expire() {
+        let window = window(&["a", "b", "c", "d"], 3);
+        assert!(!window.contains("a"));
+        assert!(window.contains("b"));
+        assert!(window.contains("d"));
+    }
+
+    #[test]
+    fn shrinking_the_window_expires_keys() {
+        let mut window = window(&["a", "b", "c"], 3);
+        window.insert("d".to_string(), 1);
+        assert!(!window.contains("c"));
+        assert!(window.contains("d"));
+    }
+
+    #[test]
+    fn empty_windows_remember_nothing() {
+        let window = window(&["a"], 0);
+        assert!(!window.contains("a"));
+    }
+}
@@ -1,66 +1,592 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, marker::PhantomData};
+use std::{collections::HashMap, fmt, marker::PhantomData, time::Duration};
 
-use crate::{actor::PersistentActor, context::Context};
+use crate::{
+    actor::PersistentActor,
+    codec::CodecId,
+    context::{Context, Effects, RunConfig},
+    database::{Bytes, Database, PersistentValue, VALUE_CODEC},
+    dyn_table::{DispatchError, DispatchResult},
+    envelope::{Envelope, TraceId},
+    gather::{GatherTimeout, Gathered},
+    global_storage::GlobalEffect,
+    quarantine::QuarantineCommand,
+    reminder::{self, CatchUp, Reminder, Schedule, StoredReminder},
+    schema,
+    timer::{self, Timer, TimerId, Timestamp},
+    topic::Subscription,
+};
 
 use super::{
     actor::{AnyActorId, PersistentActorId},
-    handler::Handler,
-    message::{AnyMessage, Message},
+    handler::Receives,
+    message::{AnyMessage, Message, MessageName},
 };
 
 pub struct Dispatcher {
-    pub(crate) messages: HashMap<AnyActorId, AnyMessage>,
+    /// Messages sent during the run, in the order they were sent.
+    pub(crate) messages: Vec<(AnyActorId, AnyMessage)>,
+    /// The actor messages are sent from.
+    actor_id: AnyActorId,
+    /// Codec outgoing messages are encoded with.
+    codec: CodecId,
+    /// Whether outgoing messages can stay typed, see [`crate::log::Log::DURABLE`]. Otherwise
+    /// they are encoded right away so encoding errors are reported to the handler.
+    local_delivery: bool,
+    now: Timestamp,
+    /// See [`RunConfig::mailbox_room`], less the messages sent during the run.
+    mailbox_room: HashMap<AnyActorId, u64>,
+    rejected: Vec<AnyActorId>,
+    /// Trace every message sent during the run is part of.
+    trace_id: TraceId,
+    next_timer: u64,
+    /// Timers created during the run, with their encoded [`Timer`].
+    timers: Vec<(TimerId, Timestamp, Bytes)>,
+    cancelled_timers: Vec<TimerId>,
+    /// Reminders registered during the run by key, with their encoding. `None` for
+    /// unregistered ones.
+    reminders: HashMap<String, Option<(StoredReminder, Bytes)>>,
+    /// Subscriptions made, `true`, or dropped, `false`, during the run.
+    subscriptions: Vec<(Subscription, bool)>,
+    published: Vec<(String, AnyMessage)>,
+    quarantine: Vec<QuarantineCommand>,
+    stop: bool,
 }
 
 impl Dispatcher {
-    pub fn new() -> Self {
+    pub fn new(actor_id: AnyActorId, config: RunConfig) -> Self {
         Self {
-            messages: HashMap::new(),
+            messages: Vec::new(),
+            actor_id,
+            codec: config.codec,
+            local_delivery: config.local_delivery,
+            now: config.now,
+            mailbox_room: config.mailbox_room,
+            rejected: Vec::new(),
+            trace_id: config.trace_id,
+            next_timer: config.next_timer,
+            timers: Vec::new(),
+            cancelled_timers: Vec::new(),
+            reminders: HashMap::new(),
+            subscriptions: Vec::new(),
+            published: Vec::new(),
+            quarantine: Vec::new(),
+            stop: false,
+        }
+    }
+
+    /// The time the handler run started at, timers are relative to it.
+    pub fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    /// Sends `message` to `actor_id` once the handler's effects are committed. Fails if the
+    /// message has to be encoded and cannot be, in which case nothing is sent.
+    pub fn send<A, M>(&mut self, actor_id: PersistentActorId<A>, message: M) -> DispatchResult<()>
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        let message = self.wrap(message)?;
+        self.push(actor_id.into_any(), message)
+    }
+
+    /// Like [`Dispatcher::send`], `envelope` can add headers or a deadline to the envelope of
+    /// the message before it is sent.
+    pub fn send_with_envelope<A, M>(
+        &mut self,
+        actor_id: PersistentActorId<A>,
+        message: M,
+        envelope: impl FnOnce(&mut Envelope),
+    ) -> DispatchResult<()>
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        let mut message = self.wrap(message)?;
+        envelope(&mut message.envelope);
+        self.push(actor_id.into_any(), message)
+    }
+
+    /// Sends `message` to `actor_id` once `delay` has passed. The timer is committed with the rest
+    /// of the handler's effects and survives restarts of the runtime.
+    pub fn send_after<A, M>(
+        &mut self,
+        actor_id: PersistentActorId<A>,
+        message: M,
+        delay: Duration,
+    ) -> DispatchResult<TimerId>
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        self.send_at(actor_id, message, self.now + delay)
+    }
+
+    /// Sends `message` to `actor_id` at `at`, or as soon as the handler's effects are committed
+    /// if `at` is in the past.
+    pub fn send_at<A, M>(
+        &mut self,
+        actor_id: PersistentActorId<A>,
+        message: M,
+        at: Timestamp,
+    ) -> DispatchResult<TimerId>
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        // The message is stored, it is always encoded
+        let message = AnyMessage::encode(&message, self.codec)?;
+        self.timer(actor_id.into_any(), message, at)
+    }
+
+    fn timer(
+        &mut self,
+        actor_id: AnyActorId,
+        mut message: AnyMessage,
+        at: Timestamp,
+    ) -> DispatchResult<TimerId> {
+        self.stamp(&mut message);
+        let timer = Timer::new(at, self.actor_id, actor_id, &message)?;
+        let bytes = schema::encode(VALUE_CODEC, Timer::VERSION, &timer).map_err(|error| {
+            DispatchError::Encode {
+                message: message.name,
+                error,
+            }
+        })?;
+        let id = TimerId::new(self.next_timer);
+        self.next_timer += 1;
+        self.timers.push((id, at, bytes));
+        Ok(id)
+    }
+
+    /// Schedules the timeout of the gather `gather` of the actor the dispatcher sends from.
+    pub(crate) fn gather_timeout(&mut self, gather: u64, at: Timestamp) -> DispatchResult<TimerId> {
+        let mut message = AnyMessage::encode(&GatherTimeout, self.codec)?;
+        message.envelope.callback = Some(CallbackTarget::GatherTimeout(gather));
+        self.timer(self.actor_id, message, at)
+    }
+
+    /// Schedules the expiry of the callback `id`, handling messages `message`, of the actor the
+    /// dispatcher sends from.
+    pub(crate) fn callback_expiry(
+        &mut self,
+        id: u64,
+        message: MessageName,
+        at: Timestamp,
+    ) -> DispatchResult<TimerId> {
+        let mut expired = AnyMessage::encode(&CallbackExpired, self.codec)?;
+        expired.envelope.callback = Some(CallbackTarget::Expire { id, message });
+        self.timer(self.actor_id, expired, at)
+    }
+
+    /// Cancels `callback`. The actor that created it drops it the next time it runs, without
+    /// running its `Callback` implementation, and later calls fail like calls to a callback that
+    /// was already called. Cancelling a callback of a gather cancels the whole gather.
+    pub fn cancel<M>(&mut self, callback: CallbackId<M>) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        let mut message = self.wrap(CancelCallback)?;
+        message.envelope.callback = Some(CallbackTarget::Cancel {
+            id: callback.id,
+            message: callback.handled_message(),
+        });
+        self.push(callback.actor_id, message)
+    }
+
+    /// Stops the actor the dispatcher sends from once the effects of the run are committed.
+    pub(crate) fn stop(&mut self) {
+        self.stop = true;
+    }
+
+    /// Cancels the timer `id` if it has not fired yet.
+    pub fn cancel_timer(&mut self, id: TimerId) {
+        let created = self.timers.len();
+        self.timers.retain(|(timer, ..)| *timer != id);
+        if self.timers.len() == created {
+            self.cancelled_timers.push(id);
         }
     }
 
-    pub fn send<A, M>(&mut self, actor_id: PersistentActorId<A>, message: M)
+    /// Registers a recurring [`Reminder`] for `actor_id`, replacing any reminder it already had
+    /// with the same name. The reminder is committed with the rest of the handler's effects.
+    /// Registering a reminder again with the same schedule and catch-up policy, e.g. from `init`
+    /// on every start, keeps the stored one and the firings it missed while the runtime was
+    /// down.
+    pub fn register_reminder<A>(
+        &mut self,
+        actor_id: PersistentActorId<A>,
+        name: impl Into<String>,
+        schedule: Schedule,
+        catch_up: CatchUp,
+    ) -> DispatchResult<()>
+    where
+        A: Receives<Reminder>,
+    {
+        let actor_id = actor_id.into_any();
+        let name = name.into();
+        let key = reminder::reminder_key(actor_id, &name);
+        let reminder = StoredReminder::new(actor_id, name, schedule, catch_up, self.now);
+        let bytes =
+            schema::encode(VALUE_CODEC, StoredReminder::VERSION, &reminder).map_err(|error| {
+                DispatchError::Encode {
+                    message: MessageName::name_for::<Reminder>(),
+                    error,
+                }
+            })?;
+        self.reminders.insert(key, Some((reminder, bytes)));
+        Ok(())
+    }
+
+    pub fn unregister_reminder<A>(&mut self, actor_id: PersistentActorId<A>, name: &str)
+    where
+        A: Receives<Reminder>,
+    {
+        let key = reminder::reminder_key(actor_id.into_any(), name);
+        self.reminders.insert(key, None);
+    }
+
+    pub(crate) fn subscribe(&mut self, subscription: Subscription) {
+        self.subscriptions.push((subscription, true));
+    }
+
+    pub(crate) fn unsubscribe(&mut self, subscription: Subscription) {
+        self.subscriptions.push((subscription, false));
+    }
+
+    pub(crate) fn publish<M>(&mut self, topic: String, message: M) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        let message = self.wrap(message)?;
+        self.published.push((topic, message));
+        Ok(())
+    }
+
+    pub(crate) fn quarantine(&mut self, command: QuarantineCommand) {
+        self.quarantine.push(command);
+    }
+
+    pub(crate) fn fix_quarantined_message<M>(
+        &mut self,
+        actor_id: AnyActorId,
+        message: M,
+    ) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        let message = self.wrap(message)?;
+        self.quarantine
+            .push(QuarantineCommand::Fix(actor_id, Box::new(message)));
+        Ok(())
+    }
+
+    /// Like [`Dispatcher::send`], replies to the message go to `callback` instead of a handler of
+    /// the sender.
+    pub fn send_with_callback<A, M, R>(
+        &mut self,
+        actor_id: PersistentActorId<A>,
+        message: M,
+        callback: CallbackId<R>,
+    ) -> DispatchResult<()>
     where
         M: Message,
-        A: Handler<M>,
+        A: Receives<M>,
+        R: Message,
     {
-        self.messages
-            .insert(AnyActorId::from(actor_id), AnyMessage::from(message));
+        self.send_with_reply_to(actor_id.into_any(), message, callback.into_any())
     }
 
-    pub fn create_callback<A, M>(&mut self) -> CallbackId<M>
+    /// Calls `callback` with `message`. A callback can only be called once, later calls are
+    /// dispatched but fail with [`crate::dyn_table::DispatchError::CallbackNotFound`].
+    pub fn call<M>(&mut self, callback: CallbackId<M>, message: M) -> DispatchResult<()>
     where
-        A: Callback<M, Env = ()>,
+        M: Message,
     {
-        self.create_callback_with_env::<A, M>(())
+        let mut message = self.wrap(message)?;
+        message.envelope.callback = Some(callback.target());
+        self.push(callback.actor_id, message)
+    }
+
+    /// Sends `message` to `actor_id` without checking it can be handled, the caller must have
+    /// checked it against the [`crate::dyn_table::DynTable`].
+    pub(crate) fn send_unchecked<M>(
+        &mut self,
+        actor_id: AnyActorId,
+        message: M,
+        callback: Option<CallbackTarget>,
+    ) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        let mut message = self.wrap(message)?;
+        message.envelope.callback = callback;
+        self.push(actor_id, message)
+    }
+
+    pub(crate) fn send_with_reply_to<M>(
+        &mut self,
+        actor_id: AnyActorId,
+        message: M,
+        reply_to: AnyCallbackId,
+    ) -> DispatchResult<()>
+    where
+        M: Message,
+    {
+        let mut message = self.wrap(message)?;
+        message.envelope.reply_to = Some(reply_to);
+        self.push(actor_id, message)
+    }
+
+    /// Drops every message except the requests of [`crate::context::Context::ask`], and every
+    /// timer, reminder, subscription or quarantine change, and the stop of the actor.
+    pub(crate) fn retain_asks(&mut self) {
+        self.timers.clear();
+        self.cancelled_timers.clear();
+        self.reminders.clear();
+        self.subscriptions.clear();
+        self.published.clear();
+        self.quarantine.clear();
+        self.rejected.clear();
+        self.stop = false;
+        self.messages.retain(|(_, message)| {
+            matches!(
+                message.envelope.reply_to,
+                Some(AnyCallbackId {
+                    target: CallbackTarget::Ask { .. },
+                    ..
+                })
+            )
+        });
     }
 
-    pub fn create_callback_with_env<A, M>(&mut self, env: A::Env) -> CallbackId<M>
+    fn wrap<M>(&self, message: M) -> DispatchResult<AnyMessage>
     where
-        A: Callback<M>,
+        M: Message,
     {
-        todo!()
+        let mut message = if self.local_delivery {
+            AnyMessage::local(message, self.codec)
+        } else {
+            AnyMessage::encode(&message, self.codec)?
+        };
+        self.stamp(&mut message);
+        Ok(message)
+    }
+
+    /// Makes `message` part of the run's trace, created now.
+    fn stamp(&self, message: &mut AnyMessage) {
+        message.envelope.trace_id = self.trace_id;
+        message.envelope.created_at = self.now;
+    }
+
+    fn push(&mut self, actor_id: AnyActorId, message: AnyMessage) -> DispatchResult<()> {
+        if let Some(room) = self.mailbox_room.get_mut(&actor_id) {
+            if *room == 0 {
+                self.rejected.push(actor_id);
+                return Err(DispatchError::MailboxFull(actor_id));
+            }
+            *room -= 1;
+        }
+        self.messages.push((actor_id, message));
+        Ok(())
+    }
+
+    /// Moves the effects of the run other than storage ones into `effects`. `db` is where
+    /// reminders registered again are read from.
+    pub(crate) fn into_effects(self, effects: &mut Effects, db: &Database) {
+        effects.messages = self.messages;
+        effects.rejected = self.rejected;
+        if !self.timers.is_empty() {
+            effects.global_effects.insert(
+                timer::NEXT_TIMER_KEY.to_string(),
+                GlobalEffect::MaxRegister(self.next_timer as i64),
+            );
+        }
+        for (id, due, bytes) in self.timers {
+            effects
+                .global_effects
+                .insert(id.key(), GlobalEffect::Modified(bytes));
+            effects.timers.push((id, due));
+        }
+        for id in self.cancelled_timers {
+            effects
+                .global_effects
+                .insert(id.key(), GlobalEffect::Deleted);
+            effects.cancelled_timers.push(id);
+        }
+        effects.next_timer = self.next_timer;
+        for (key, reminder) in self.reminders {
+            let (next, effect) = match reminder {
+                Some((reminder, bytes)) => {
+                    // A stored reminder that cannot be read is replaced
+                    if let Ok((version, Some(stored))) = db.get_versioned::<StoredReminder>(&key) {
+                        if stored.same_schedule(&reminder) {
+                            effects.reads.insert(key.clone(), version);
+                            effects.reminders.push((key, stored.next));
+                            continue;
+                        }
+                    }
+                    (reminder.next, GlobalEffect::Modified(bytes))
+                }
+                None => (None, GlobalEffect::Deleted),
+            };
+            effects.global_effects.insert(key.clone(), effect);
+            effects.reminders.push((key, next));
+        }
+        for (subscription, subscribed) in self.subscriptions {
+            let effect = match subscribed {
+                // Subscriptions only hold names and ids, encoding them cannot fail
+                true => GlobalEffect::Modified(
+                    schema::encode(VALUE_CODEC, Subscription::VERSION, &subscription).unwrap(),
+                ),
+                false => GlobalEffect::Deleted,
+            };
+            effects.global_effects.insert(subscription.key(), effect);
+            effects.subscriptions.push((subscription, subscribed));
+        }
+        effects.published = self.published;
+        effects.quarantine = self.quarantine;
+        effects.stop = self.stop;
     }
 }
 
+/// A callback created with [`Context::create_callback`]. It can be sent to other actors, which
+/// call it through [`Dispatcher::call`].
 #[derive(Serialize, Deserialize)]
 pub struct CallbackId<M> {
-    pub id: u32,
+    /// The actor that created the callback and handles it.
+    pub actor_id: AnyActorId,
+    pub id: u64,
+    /// When the callback expires, if it does. Calls made after that fail like calls to a
+    /// callback that was already called.
+    #[serde(default)]
+    pub expires_at: Option<Timestamp>,
+    /// Position of the callback in the gather `id`, `None` if it is not part of a gather.
+    #[serde(default)]
+    index: Option<u32>,
     _marker: PhantomData<M>,
 }
 
-impl<M> CallbackId<M> {
-    // Note: If we want to support this a CallbackId needs to hold a reference to the dispatcher.
-    // That would require some interior mutability. Also that would mean that a CallbackId is not
-    //Alternative one can have a `call` function in dispatcher.
-    pub fn call(&self, args: M) {
-        todo!()
+/// A [`CallbackId`] with the name of its message instead of its type. It can also point to an
+/// async handler waiting for the response to an ask.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct AnyCallbackId {
+    pub actor_id: AnyActorId,
+    pub(crate) target: CallbackTarget,
+    pub message: MessageName,
+}
+
+/// What a message sent to a callback resumes.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum CallbackTarget {
+    /// A `Callback` implementation, with the environment stored under this id.
+    Callback(u64),
+    /// The async handler run `invocation`, waiting for the response to its `index`-th ask.
+    Ask { invocation: u64, index: u32 },
+    /// The gather `gather`, waiting for the response to its `index`-th callback, see
+    /// [`crate::context::Context::gather`].
+    Gather { gather: u64, index: u32 },
+    /// The gather `gather`, when its timeout expires.
+    GatherTimeout(u64),
+    /// The callback `id` handling messages `message`, when it expires, see
+    /// [`crate::context::Context::create_expiring_callback`].
+    Expire { id: u64, message: MessageName },
+    /// The callback `id` handling messages `message`, when it is cancelled with
+    /// [`Dispatcher::cancel`].
+    Cancel { id: u64, message: MessageName },
+}
+
+/// Sent by the timer of a callback with an expiry.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct CallbackExpired;
+
+impl Message for CallbackExpired {
+    const NAME: &'static str = "CallbackExpired";
+}
+
+/// Sent to the actor that created a callback to cancel it.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct CancelCallback;
+
+impl Message for CancelCallback {
+    const NAME: &'static str = "CancelCallback";
+}
+
+impl<M> CallbackId<M>
+where
+    M: Message,
+{
+    pub(crate) fn new(actor_id: AnyActorId, id: u64, expires_at: Option<Timestamp>) -> Self {
+        Self {
+            actor_id,
+            id,
+            expires_at,
+            index: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The `index`-th callback of the gather `gather`.
+    pub(crate) fn gather(actor_id: AnyActorId, gather: u64, index: u32) -> Self {
+        Self {
+            actor_id,
+            id: gather,
+            expires_at: None,
+            index: Some(index),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Name of the messages handled by the callback of the creator, `Gathered` for the callbacks
+    /// of a gather.
+    fn handled_message(self) -> MessageName {
+        match self.index {
+            Some(_) => MessageName::name_for::<Gathered>(),
+            None => MessageName::name_for::<M>(),
+        }
+    }
+
+    pub fn into_any(self) -> AnyCallbackId {
+        AnyCallbackId {
+            actor_id: self.actor_id,
+            target: self.target(),
+            message: MessageName::name_for::<M>(),
+        }
+    }
+
+    fn target(self) -> CallbackTarget {
+        match self.index {
+            Some(index) => CallbackTarget::Gather {
+                gather: self.id,
+                index,
+            },
+            None => CallbackTarget::Callback(self.id),
+        }
+    }
+}
+
+// Implemented by hand, deriving would require `M: Clone`
+impl<M> Clone for CallbackId<M> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
 
-pub trait Callback<M>: PersistentActor {
-    type Env;
+impl<M> Copy for CallbackId<M> {}
 
-    fn handle(&self, cx: &mut Context<Self>, env: Self::Env, msg: M);
+pub trait Callback<M: Message>: PersistentActor {
+    /// State kept with the callback until it is called. It is stored with the actor's storage, so
+    /// it survives restarts.
+    type Env: PersistentValue;
+    /// See [`crate::handler::Handler::Error`]. The callback can be called again if it fails.
+    type Error: fmt::Debug + Send + Sync + 'static;
+
+    fn handle(&self, cx: &mut Context<Self>, env: Self::Env, msg: M) -> Result<(), Self::Error>;
+
+    /// Runs instead of `handle` if the callback expires before it is called, see
+    /// [`Context::create_expiring_callback`].
+    fn expired(&self, _cx: &mut Context<Self>, _env: Self::Env) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
@@ -1,14 +1,29 @@
-use std::{any::Any, collections::HashMap, panic::RefUnwindSafe};
+use std::{any::Any, collections::HashMap, fmt, panic::RefUnwindSafe, task::Poll};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    actor::{ActorName, PersistentActor},
+    actor::{ActorName, AnyActorId, PersistentActor},
+    ask::{self, Invocation, Suspended},
+    codec::{CodecError, CodecId},
     context::{AnyContext, Effects},
-    handler::Handler,
+    dispatcher::{Callback, CallbackTarget},
+    gather::{self, GatherState, Gathered},
+    handler::{AsyncHandler, Handler, Receives},
     message::{AnyMessage, Message, MessageName},
+    retry::RetryPolicy,
+    schema::{DecodeError, SchemaVersion, Upcasters},
 };
 
 pub type AnyHandler =
     dyn Fn(&dyn Any, AnyContext, AnyMessage) -> DispatchResult<Effects> + RefUnwindSafe;
+pub type AnyAsyncHandler =
+    dyn Fn(&dyn Any, AnyContext, Invocation) -> DispatchResult<Effects> + RefUnwindSafe;
+pub type AnyCallback =
+    dyn Fn(&dyn Any, AnyContext, u64, AnyMessage) -> DispatchResult<Effects> + RefUnwindSafe;
+/// Drops a callback that expired, `true`, or was cancelled, `false`.
+pub type AnyCallbackDrop =
+    dyn Fn(&dyn Any, AnyContext, u64, bool) -> DispatchResult<Effects> + RefUnwindSafe;
 pub type AnyInit = dyn Fn(&dyn Any, AnyContext) -> DispatchResult<Effects> + RefUnwindSafe;
 
 #[derive(PartialEq, Eq, Hash)]
@@ -16,21 +31,67 @@ struct HandlerId(ActorName, MessageName);
 
 pub struct DynTable {
     handlers: HashMap<HandlerId, Box<AnyHandler>>,
+    /// Retry policies of single handlers, they take precedence over the policy of the actor.
+    retry_policies: HashMap<HandlerId, RetryPolicy>,
+    actor_retry_policies: HashMap<ActorName, RetryPolicy>,
+    async_handlers: HashMap<HandlerId, Box<AnyAsyncHandler>>,
+    callbacks: HashMap<HandlerId, Box<AnyCallback>>,
+    callback_drops: HashMap<HandlerId, Box<AnyCallbackDrop>>,
     init: HashMap<ActorName, Box<AnyInit>>,
+    /// Current schema version of every message with a registered handler.
+    message_versions: HashMap<MessageName, SchemaVersion>,
+    message_upcasters: Upcasters<MessageName>,
 }
 
 pub type DispatchResult<T> = Result<T, DispatchError>;
 
+#[derive(Debug)]
 pub enum DispatchError {
     TypeMissmatch,
     MethodNotFound,
+    /// The callback was already called, or never committed.
+    CallbackNotFound,
+    /// `reply` was called while handling a message without a sender.
+    NoSender,
+    /// `ask` was called outside of an async handler.
+    NotAsync,
+    /// An async handler waited on something other than an ask, it would never be resumed.
+    Stalled,
+    /// An outgoing message could not be encoded.
+    Encode {
+        message: MessageName,
+        error: CodecError,
+    },
+    /// A message could not be decoded as the type its handler expects.
+    Decode {
+        message: MessageName,
+        error: DecodeError,
+    },
+    /// The recipient of a message has no room left for it, see
+    /// [`crate::mailbox::Overflow::Reject`].
+    MailboxFull(AnyActorId),
+    /// The handler returned an error.
+    Handler(HandlerError),
+}
+
+/// An error returned by a handler. It keeps its type so it can be told apart from others.
+pub struct HandlerError {
+    error: Box<dyn Any + Send + Sync>,
+    description: String,
 }
 
 impl DynTable {
     pub fn new() -> Self {
         Self {
             handlers: HashMap::new(),
+            retry_policies: HashMap::new(),
+            actor_retry_policies: HashMap::new(),
+            async_handlers: HashMap::new(),
+            callbacks: HashMap::new(),
+            callback_drops: HashMap::new(),
             init: HashMap::new(),
+            message_versions: HashMap::new(),
+            message_upcasters: Upcasters::new(),
         }
     }
 
@@ -41,6 +102,18 @@ impl DynTable {
         self.init.contains_key(&ActorName::name_for::<A>())
     }
 
+    /// Whether actors `actor_name` have a handler for messages `message_name`.
+    pub fn has_handler(&self, actor_name: ActorName, message_name: MessageName) -> bool {
+        let handler_id = HandlerId(actor_name, message_name);
+        self.handlers.contains_key(&handler_id) || self.async_handlers.contains_key(&handler_id)
+    }
+
+    /// Whether actors `actor_name` have a callback for messages `message_name`.
+    pub fn has_callback(&self, actor_name: ActorName, message_name: MessageName) -> bool {
+        self.callbacks
+            .contains_key(&HandlerId(actor_name, message_name))
+    }
+
     pub fn register_handler<A, M>(&mut self)
     where
         M: Message,
@@ -52,17 +125,135 @@ impl DynTable {
                     .downcast_ref::<A>()
                     .ok_or(DispatchError::TypeMissmatch)?;
                 let mut cx = cx.downcast::<A>().ok_or(DispatchError::TypeMissmatch)?;
-                let message = message
-                    .downcast::<M>()
-                    .ok_or(DispatchError::TypeMissmatch)?;
-                actor.handle(&mut cx, message);
+                let message = message.downcast::<M>()?;
+                actor.handle(&mut cx, message).map_err(HandlerError::new)?;
                 Ok(cx.into_effects())
             };
         let handler_id = HandlerId(ActorName::name_for::<A>(), MessageName::name_for::<M>());
-        if self.handlers.contains_key(&handler_id) {
+        if self.has_handler(handler_id.0, handler_id.1) {
             panic!("Handler already exists")
         }
         self.handlers.insert(handler_id, Box::new(handler));
+        self.message_versions
+            .insert(MessageName::name_for::<M>(), M::VERSION);
+    }
+
+    pub fn register_async_handler<A, M>(&mut self)
+    where
+        M: Message,
+        A: AsyncHandler<M>,
+    {
+        let handler =
+            |actor: &dyn Any, cx: AnyContext, invocation: Invocation| -> DispatchResult<Effects> {
+                let actor = actor
+                    .downcast_ref::<A>()
+                    .ok_or(DispatchError::TypeMissmatch)?;
+                let mut cx = cx.downcast::<A>().ok_or(DispatchError::TypeMissmatch)?;
+                let message = invocation.message().downcast::<M>()?;
+                cx.invocation = Some(invocation);
+                let poll = ask::poll_once(actor.handle(&mut cx, message));
+                if let Poll::Ready(Err(error)) = poll {
+                    return Err(HandlerError::new(error).into());
+                }
+                cx.into_async_effects(poll.is_ready())
+            };
+        let handler_id = HandlerId(ActorName::name_for::<A>(), MessageName::name_for::<M>());
+        if self.has_handler(handler_id.0, handler_id.1) {
+            panic!("Handler already exists")
+        }
+        self.async_handlers.insert(handler_id, Box::new(handler));
+        self.message_versions
+            .insert(MessageName::name_for::<M>(), M::VERSION);
+    }
+
+    pub fn register_callback<A, M>(&mut self)
+    where
+        M: Message,
+        A: Callback<M>,
+    {
+        let callback = |actor: &dyn Any,
+                        cx: AnyContext,
+                        id: u64,
+                        message: AnyMessage|
+         -> DispatchResult<Effects> {
+            let actor = actor
+                .downcast_ref::<A>()
+                .ok_or(DispatchError::TypeMissmatch)?;
+            let mut cx = cx.downcast::<A>().ok_or(DispatchError::TypeMissmatch)?;
+            let env = cx
+                .take_callback_env::<M>(id)
+                .ok_or(DispatchError::CallbackNotFound)?;
+            let message = message.downcast::<M>()?;
+            Callback::handle(actor, &mut cx, env, message).map_err(HandlerError::new)?;
+            Ok(cx.into_effects())
+        };
+        let drop =
+            |actor: &dyn Any, cx: AnyContext, id: u64, expired: bool| -> DispatchResult<Effects> {
+                let actor = actor
+                    .downcast_ref::<A>()
+                    .ok_or(DispatchError::TypeMissmatch)?;
+                let mut cx = cx.downcast::<A>().ok_or(DispatchError::TypeMissmatch)?;
+                // Already called, expired or cancelled
+                let Some(env) = cx.take_callback_env::<M>(id) else {
+                    return Ok(cx.into_effects());
+                };
+                if expired {
+                    Callback::expired(actor, &mut cx, env).map_err(HandlerError::new)?;
+                }
+                Ok(cx.into_effects())
+            };
+        let handler_id = HandlerId(ActorName::name_for::<A>(), MessageName::name_for::<M>());
+        if self.callbacks.contains_key(&handler_id) {
+            panic!("Callback already exists")
+        }
+        self.callbacks.insert(handler_id, Box::new(callback));
+        self.callback_drops.insert(
+            HandlerId(ActorName::name_for::<A>(), MessageName::name_for::<M>()),
+            Box::new(drop),
+        );
+        self.message_versions
+            .insert(MessageName::name_for::<M>(), M::VERSION);
+    }
+
+    pub fn set_retry_policy<A, M>(&mut self, policy: RetryPolicy)
+    where
+        M: Message,
+        A: Receives<M>,
+    {
+        let handler_id = HandlerId(ActorName::name_for::<A>(), MessageName::name_for::<M>());
+        self.retry_policies.insert(handler_id, policy);
+    }
+
+    /// Sets the retry policy of every handler and callback of actors `A` without a policy of
+    /// their own.
+    pub fn set_actor_retry_policy<A>(&mut self, policy: RetryPolicy)
+    where
+        A: PersistentActor,
+    {
+        self.actor_retry_policies
+            .insert(ActorName::name_for::<A>(), policy);
+    }
+
+    pub fn retry_policy(
+        &self,
+        actor_name: ActorName,
+        message_name: MessageName,
+    ) -> Option<&RetryPolicy> {
+        self.retry_policies
+            .get(&HandlerId(actor_name, message_name))
+            .or_else(|| self.actor_retry_policies.get(&actor_name))
+    }
+
+    /// Registers `upcast` to migrate messages `M` written at schema version `from` to version
+    /// `from + 1` before they are dispatched.
+    pub fn register_upcaster<M, Old, New>(&mut self, from: SchemaVersion, upcast: fn(Old) -> New)
+    where
+        M: Message,
+        Old: 'static + for<'a> Deserialize<'a>,
+        New: 'static + Serialize,
+    {
+        self.message_upcasters
+            .register(MessageName::name_for::<M>(), from, upcast);
     }
 
     pub fn register_actor<A>(&mut self)
@@ -105,10 +296,178 @@ impl DynTable {
         message: AnyMessage,
     ) -> DispatchResult<Effects> {
         let handler_id = HandlerId(actor_name, message.name);
+        match message.envelope.callback {
+            Some(CallbackTarget::Callback(id)) => {
+                let callback = self
+                    .callbacks
+                    .get(&handler_id)
+                    .ok_or(DispatchError::MethodNotFound)?;
+                callback(actor, cx, id, self.upcast(message)?)
+            }
+            Some(CallbackTarget::Ask { invocation, index }) => {
+                self.resume(actor_name, actor, cx, invocation, index, message)
+            }
+            Some(CallbackTarget::Gather { gather, index }) => {
+                let codec = message.codec;
+                self.gather(actor_name, actor, cx, gather, codec, Some((index, message)))
+            }
+            Some(CallbackTarget::GatherTimeout(gather)) => {
+                self.gather(actor_name, actor, cx, gather, message.codec, None)
+            }
+            Some(CallbackTarget::Expire { id, message }) => {
+                self.drop_callback(actor_name, actor, cx, id, message, true)
+            }
+            Some(CallbackTarget::Cancel { id, message }) => {
+                self.drop_callback(actor_name, actor, cx, id, message, false)
+            }
+            None => {
+                if let Some(handler) = self.handlers.get(&handler_id) {
+                    return handler(actor, cx, self.upcast(message)?);
+                }
+                let handler = self
+                    .async_handlers
+                    .get(&handler_id)
+                    .ok_or(DispatchError::MethodNotFound)?;
+                handler(actor, cx, Invocation::new(self.upcast(message)?))
+            }
+        }
+    }
+
+    /// Re-runs the async handler run `id` with `response`, the response to its `index`-th ask.
+    fn resume(
+        &self,
+        actor_name: ActorName,
+        actor: &dyn Any,
+        mut cx: AnyContext,
+        id: u64,
+        index: u32,
+        response: AnyMessage,
+    ) -> DispatchResult<Effects> {
+        let mut suspended: Suspended = cx
+            .runtime_storage
+            .take(ask::state_key(id))
+            .ok_or(DispatchError::CallbackNotFound)?;
+        suspended.add_response(index, &response)?;
         let handler = self
-            .handlers
-            .get(&handler_id)
+            .async_handlers
+            .get(&HandlerId(actor_name, suspended.message))
+            .ok_or(DispatchError::MethodNotFound)?;
+        cx.sender = suspended.sender;
+        cx.reply_to = suspended.reply_to;
+        let message = self.upcast(suspended.message())?;
+        cx.envelope = Some(message.envelope.clone());
+        handler(actor, cx, Invocation::resume(id, message, suspended))
+    }
+
+    /// Adds `response`, the response to the `index`-th callback of the gather `id`, or times the
+    /// gather out if there is none. Once the gather completes the actor's `Callback<Gathered>`
+    /// is run with it, in the same run.
+    fn gather(
+        &self,
+        actor_name: ActorName,
+        actor: &dyn Any,
+        mut cx: AnyContext,
+        id: u64,
+        codec: CodecId,
+        response: Option<(u32, AnyMessage)>,
+    ) -> DispatchResult<Effects> {
+        // Responses arriving once the gather completed are dropped
+        let Some(mut state) = cx
+            .runtime_storage
+            .take::<GatherState, _>(gather::state_key(id))
+        else {
+            return Ok(Effects::default());
+        };
+        let timed_out = match response {
+            Some((index, response)) => {
+                state.add_response(index, &response)?;
+                false
+            }
+            None => true,
+        };
+        if !timed_out && !state.is_complete() {
+            cx.runtime_storage.put(gather::state_key(id), state);
+            return Ok(cx.into_effects());
+        }
+        if let (Some(timer), false) = (state.timeout, timed_out) {
+            cx.dispatcher.cancel_timer(timer);
+        }
+        let callback = self
+            .callbacks
+            .get(&HandlerId(actor_name, MessageName::name_for::<Gathered>()))
+            .ok_or(DispatchError::MethodNotFound)?;
+        let gathered = AnyMessage::local(state.into_gathered(timed_out), codec);
+        callback(actor, cx, id, gathered)
+    }
+
+    /// Drops the callback `id` handling messages `message`, running its expiry if it `expired`.
+    /// Cancelling a gather also drops its state and its timeout.
+    fn drop_callback(
+        &self,
+        actor_name: ActorName,
+        actor: &dyn Any,
+        mut cx: AnyContext,
+        id: u64,
+        message: MessageName,
+        expired: bool,
+    ) -> DispatchResult<Effects> {
+        if let Some(state) = cx
+            .runtime_storage
+            .take::<GatherState, _>(gather::state_key(id))
+        {
+            if let Some(timer) = state.timeout {
+                cx.dispatcher.cancel_timer(timer);
+            }
+        }
+        let drop = self
+            .callback_drops
+            .get(&HandlerId(actor_name, message))
             .ok_or(DispatchError::MethodNotFound)?;
-        handler(actor, cx, message)
+        drop(actor, cx, id, expired)
+    }
+
+    fn upcast(&self, message: AnyMessage) -> DispatchResult<AnyMessage> {
+        let name = message.name;
+        message
+            .upcast(&self.message_upcasters, self.message_versions[&name])
+            .map_err(|error| DispatchError::Decode {
+                message: name,
+                error,
+            })
+    }
+}
+
+impl HandlerError {
+    pub(crate) fn new<E>(error: E) -> Self
+    where
+        E: fmt::Debug + Send + Sync + 'static,
+    {
+        Self {
+            description: format!("{:?}", error),
+            error: Box::new(error),
+        }
+    }
+
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: 'static,
+    {
+        self.error.downcast_ref()
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl From<HandlerError> for DispatchError {
+    fn from(error: HandlerError) -> Self {
+        DispatchError::Handler(error)
+    }
+}
+
+impl fmt::Debug for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.description)
     }
 }
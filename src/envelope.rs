@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dispatcher::{AnyCallbackId, CallbackTarget},
+    scheduler::Priority,
+    timer::Timestamp,
+};
+
+/// Identifies a chain of messages caused by one another. Messages sent by a handler carry the
+/// trace id of the message being handled, messages sent from `init` or from outside the runtime
+/// start a new trace.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TraceId(u64);
+
+/// Metadata travelling with a message, read by handlers through
+/// [`crate::context::Context::envelope`]. Durable logs, timers and dead letters store it with
+/// the message, it carries everything the runtime needs to route the message again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Free form metadata. Unlike the trace id, headers are not passed along to the messages a
+    /// handler sends.
+    pub headers: BTreeMap<String, String>,
+    pub trace_id: TraceId,
+    pub created_at: Timestamp,
+    /// The runtime drops the message instead of handling it after this time.
+    pub deadline: Option<Timestamp>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// See [`crate::message::AnyMessage::with_idempotency_key`].
+    pub idempotency_key: Option<String>,
+    /// Callback of the receiver the message is for, `None` if it is for one of its handlers.
+    #[serde(default)]
+    pub(crate) callback: Option<CallbackTarget>,
+    /// Where a reply to the message should go, see [`crate::context::Context::reply`].
+    #[serde(default)]
+    pub(crate) reply_to: Option<AnyCallbackId>,
+    /// Which attempt at handling the message this delivery is, starting at 1, see
+    /// [`crate::retry::RetryPolicy`].
+    #[serde(default = "first_attempt")]
+    pub(crate) attempt: u32,
+}
+
+/// Hands out the ids of the traces a runtime starts. The ids are derived from a seed and a
+/// counter, so a runtime given the same seed starts the same traces, see
+/// [`crate::runtime::Runtime::set_trace_seed`].
+pub(crate) struct TraceIds {
+    seed: u64,
+    next: u64,
+}
+
+impl TraceIds {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { seed, next: 0 }
+    }
+
+    /// A fresh trace id, unique with high probability.
+    pub(crate) fn next(&mut self) -> TraceId {
+        self.next += 1;
+        // The finalizer of SplitMix64, consecutive counts give unrelated ids
+        let mut id = self
+            .seed
+            .wrapping_add(self.next.wrapping_mul(0x9e3779b97f4a7c15));
+        id = (id ^ (id >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        id = (id ^ (id >> 27)).wrapping_mul(0x94d049bb133111eb);
+        TraceId(id ^ (id >> 31))
+    }
+}
+
+impl Envelope {
+    /// An envelope for a message that is not sent yet. Its trace and creation time are set when
+    /// it is sent, from the run sending it or the runtime's clock.
+    pub fn new(priority: Priority) -> Self {
+        Self {
+            headers: BTreeMap::new(),
+            trace_id: TraceId(0),
+            created_at: Timestamp::from_millis(0),
+            deadline: None,
+            priority,
+            idempotency_key: None,
+            callback: None,
+            reply_to: None,
+            attempt: 1,
+        }
+    }
+
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.deadline.is_some_and(|deadline| deadline < now)
+    }
+}
+
+fn first_attempt() -> u32 {
+    1
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::TraceIds;
+
+    #[test]
+    fn trace_ids_are_seeded() {
+        let (mut a, mut b) = (TraceIds::new(7), TraceIds::new(7));
+        for _ in 0..10 {
+            assert_eq!(a.next(), b.next());
+        }
+        assert_ne!(TraceIds::new(7).next(), TraceIds::new(8).next());
+    }
+
+    #[test]
+    fn trace_ids_are_distinct() {
+        let mut traces = TraceIds::new(0);
+        let ids: HashSet<_> = (0..1000).map(|_| traces.next()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+}
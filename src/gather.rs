@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::CodecId,
+    database::{Bytes, PersistentValue},
+    dyn_table::{DispatchError, DispatchResult},
+    message::{AnyMessage, Message, MessageName},
+    timer::TimerId,
+};
+
+/// When a gather created with [`crate::context::Context::gather`] is done waiting.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GatherPolicy {
+    pub quorum: Quorum,
+    /// Delay after which the gather completes with the responses received so far, even if the
+    /// quorum was not reached.
+    pub timeout: Option<Duration>,
+}
+
+/// How many of the callbacks of a gather must be called for it to complete.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Quorum {
+    All,
+    Any,
+    /// At least this many, or all of them if there are fewer.
+    AtLeast(u32),
+}
+
+/// The responses collected by a gather, delivered to the `Callback<Gathered>` of the actor that
+/// created it. Responses are kept by the index of the callback they were sent to, in the order
+/// the callbacks were handed out.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Gathered {
+    /// How many callbacks the gather had.
+    pub callbacks: u32,
+    /// Whether the gather completed because of its timeout rather than its quorum.
+    pub timed_out: bool,
+    message: MessageName,
+    responses: BTreeMap<u32, (CodecId, Bytes)>,
+}
+
+impl Message for Gathered {
+    const NAME: &'static str = "Gathered";
+}
+
+/// A gather waiting for responses. It is kept in the storage of the actor that created it until
+/// it completes, responses arriving afterwards are dropped.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GatherState {
+    message: MessageName,
+    callbacks: u32,
+    quorum: Quorum,
+    /// Timer completing the gather if it times out.
+    pub(crate) timeout: Option<TimerId>,
+    responses: BTreeMap<u32, (CodecId, Bytes)>,
+}
+
+impl PersistentValue for GatherState {}
+
+/// Sent by the timer of a gather with a timeout.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct GatherTimeout;
+
+impl Message for GatherTimeout {
+    const NAME: &'static str = "GatherTimeout";
+}
+
+impl GatherPolicy {
+    pub fn all() -> Self {
+        Self::new(Quorum::All)
+    }
+
+    pub fn any() -> Self {
+        Self::new(Quorum::Any)
+    }
+
+    pub fn quorum(n: u32) -> Self {
+        Self::new(Quorum::AtLeast(n))
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    fn new(quorum: Quorum) -> Self {
+        Self {
+            quorum,
+            timeout: None,
+        }
+    }
+}
+
+impl Gathered {
+    /// How many callbacks were called.
+    pub fn received(&self) -> u32 {
+        self.responses.len() as u32
+    }
+
+    /// The response to every callback, `None` for the ones that were not called.
+    pub fn decode<R>(&self) -> DispatchResult<Vec<Option<R>>>
+    where
+        R: Message,
+    {
+        if self.message != MessageName::name_for::<R>() {
+            return Err(DispatchError::TypeMissmatch);
+        }
+        (0..self.callbacks)
+            .map(|index| {
+                self.responses
+                    .get(&index)
+                    .map(|(codec, bytes)| {
+                        AnyMessage::from_parts(self.message, *codec, bytes.clone()).downcast()
+                    })
+                    .transpose()
+            })
+            .collect()
+    }
+}
+
+impl GatherState {
+    pub(crate) fn new<R>(callbacks: u32, quorum: Quorum) -> Self
+    where
+        R: Message,
+    {
+        Self {
+            message: MessageName::name_for::<R>(),
+            callbacks,
+            quorum,
+            timeout: None,
+            responses: BTreeMap::new(),
+        }
+    }
+
+    /// Records the response to the `index`-th callback. A callback called twice keeps its first
+    /// response.
+    pub(crate) fn add_response(&mut self, index: u32, response: &AnyMessage) -> DispatchResult<()> {
+        if index >= self.callbacks || self.responses.contains_key(&index) {
+            return Ok(());
+        }
+        let bytes = response.bytes()?.into_owned();
+        self.responses.insert(index, (response.codec, bytes));
+        Ok(())
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        let required = match self.quorum {
+            Quorum::All => self.callbacks,
+            Quorum::Any => 1,
+            Quorum::AtLeast(n) => n,
+        };
+        self.responses.len() as u32 >= required.min(self.callbacks)
+    }
+
+    pub(crate) fn into_gathered(self, timed_out: bool) -> Gathered {
+        Gathered {
+            callbacks: self.callbacks,
+            timed_out,
+            message: self.message,
+            responses: self.responses,
+        }
+    }
+}
+
+pub(crate) fn state_key(id: u64) -> String {
+    format!("gather/{}", id)
+}
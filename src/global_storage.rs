@@ -1,6 +1,19 @@
-use std::{any::Any, collections::HashMap};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    ops::{AddAssign, Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::database::{Database, DbError, PersistentValue};
+use serde::Serialize;
+
+use crate::{
+    actor::AnyActorId,
+    context::Effects,
+    crdt::{self, Crdt, Delta},
+    database::{Bytes, Database, DbError, PersistentValue, Version, VALUE_CODEC},
+    schema,
+};
 
 pub enum StorageError {
     Db(DbError),
@@ -8,38 +21,234 @@ pub enum StorageError {
     KeyNotFound,
 }
 
+/// A key was not at the version a conditional write expected.
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub key: String,
+    pub expected: Version,
+    pub actual: Version,
+}
+
+/// Where the keys of a [`GlobalStorage`] live in the backend.
+#[derive(Clone)]
+pub enum Namespace {
+    /// Keys private to a single actor. Only that actor ever reads or writes them, so values can
+    /// be cached across handler runs.
+    Actor(AnyActorId),
+    /// Keys shared by every actor opening the namespace with `cx.shared_storage`. Other actors
+    /// may write them at any time, so values are never cached across handler runs.
+    Shared(String),
+    /// Keys the runtime keeps for a single actor, e.g. its callbacks, gathers and suspended
+    /// asks. Handlers cannot open it, so they cannot overwrite them.
+    Runtime(AnyActorId),
+}
+
 pub struct GlobalStorage<'a> {
     db: &'a Database,
-    cache: &'a mut GlobalStorageCache,
-    pub(crate) effects: HashMap<String, GlobalEffect>,
+    namespace: Namespace,
+    cache: CacheRef<'a>,
+    effects: HashMap<String, PendingEffect>,
+    /// Version of every key read from the database during this run, keyed by backend key.
+    reads: HashMap<String, Version>,
+    /// Versions keys must be at for the effects to be committed, keyed by backend key.
+    guards: HashMap<String, Version>,
 }
 
+/// Values of an actor's keys kept in memory across handler runs. Entries are sized by the length
+/// of their serialized value and, once clean, can be evicted least recently used first.
 pub struct GlobalStorageCache {
-    map: HashMap<String, Box<dyn Any>>,
+    map: HashMap<String, CacheEntry>,
+    /// Keys modified by the handler currently running. They are dropped if its effects are not
+    /// committed, so a retry starts from the committed values. They are never evicted.
+    dirty: HashSet<String>,
+    stats: CacheStats,
+}
+
+struct CacheEntry {
+    value: Box<dyn CachedValue>,
+    /// Serialized length of the value, as read from the database or as serialized for the
+    /// commit of a run modifying it.
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Serialized size of the values currently in the cache.
+    pub bytes: usize,
+}
+
+/// Memory budgets in bytes for the caches of a runtime, unbounded by default.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheBudget {
+    pub per_actor: usize,
+    pub per_runtime: usize,
 }
 
 pub enum GlobalEffect {
+    Modified(Bytes),
+    Deleted,
+    /// Adds to the `i64` stored at the key, a missing key counts as zero. An increment that
+    /// would overflow fails the commit with [`crate::database::CommitError::Value`].
+    Increment(i64),
+    /// Keeps the largest of the `i64` stored at the key and this one.
+    MaxRegister(i64),
+    /// Adds the encoded elements to the [`crdt::GSet`] stored at the key.
+    SetAdd(Vec<Bytes>),
+    /// Merges a [`Crdt`] into the value stored at the key.
+    Merge(Delta),
+}
+
+/// Effects recorded while a handler runs. Values of modified keys stay in the cache until the
+/// storage is turned into [`GlobalEffect`]s.
+enum PendingEffect {
     Modified,
     Deleted,
+    /// A commutative [`GlobalEffect`] on a key the handler has not read.
+    Commutative(GlobalEffect),
+}
+
+enum CacheRef<'a> {
+    Borrowed(&'a mut GlobalStorageCache),
+    Owned(GlobalStorageCache),
+}
+
+/// A value the handler modified with a commutative effect after writing it. It is decoded the
+/// next time the handler accesses it.
+struct Encoded(Bytes);
+
+trait CachedValue: Any {
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+
+    fn to_bytes(&self) -> Bytes;
+}
+
+impl Namespace {
+    /// The key used in the backend for `key` in this namespace. Shared namespace names and
+    /// actor ids are prefixed with their length, so one containing `/` cannot collide with
+    /// another, e.g. an actor named `A/1` with the keys of actor `A/1` itself.
+    pub fn backend_key(&self, key: &str) -> String {
+        match self {
+            Namespace::Actor(actor_id) => {
+                let id = actor_id.to_string();
+                format!("actor/{}/{}/{}", id.len(), id, key)
+            }
+            Namespace::Shared(name) => format!("shared/{}/{}/{}", name.len(), name, key),
+            Namespace::Runtime(actor_id) => {
+                let id = actor_id.to_string();
+                format!("runtime/actors/{}/{}/{}", id.len(), id, key)
+            }
+        }
+    }
 }
 
 impl<'a> GlobalStorage<'a> {
-    pub fn new(db: &'a Database, cache: &'a mut GlobalStorageCache) -> Self {
+    /// Storage private to `actor_id`, backed by the actor's long-lived cache.
+    pub fn private(
+        actor_id: AnyActorId,
+        db: &'a Database,
+        cache: &'a mut GlobalStorageCache,
+    ) -> Self {
         Self {
             db,
-            cache,
+            namespace: Namespace::Actor(actor_id),
+            cache: CacheRef::Borrowed(cache),
             effects: HashMap::new(),
+            reads: HashMap::new(),
+            guards: HashMap::new(),
         }
     }
 
+    /// Storage for the shared namespace `name`. The cache only lives for the current handler run.
+    pub fn shared(db: &'a Database, name: impl Into<String>) -> Self {
+        Self {
+            db,
+            namespace: Namespace::Shared(name.into()),
+            cache: CacheRef::Owned(GlobalStorageCache::new()),
+            effects: HashMap::new(),
+            reads: HashMap::new(),
+            guards: HashMap::new(),
+        }
+    }
+
+    /// Storage for the keys the runtime keeps for `actor_id`. The cache only lives for the
+    /// current handler run.
+    pub(crate) fn runtime(actor_id: AnyActorId, db: &'a Database) -> Self {
+        Self {
+            db,
+            namespace: Namespace::Runtime(actor_id),
+            cache: CacheRef::Owned(GlobalStorageCache::new()),
+            effects: HashMap::new(),
+            reads: HashMap::new(),
+            guards: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn db(&self) -> &'a Database {
+        self.db
+    }
+
     pub fn put<K, V>(&mut self, key: K, value: V)
     where
         K: Into<String>,
         V: PersistentValue,
     {
         let key = key.into();
-        self.effects.insert(key.clone(), GlobalEffect::Modified);
-        self.cache.insert(key, value);
+        self.effects.insert(key.clone(), PendingEffect::Modified);
+        self.cache.insert_dirty(key, value);
+    }
+
+    /// Writes `value` only if `key` is at version `expected`. The check is done now and again
+    /// when the effects are committed. If the key changes in between all the effects of the
+    /// handler are discarded and the handler is re-run, seeing the mismatch this time.
+    pub fn put_if_version<K, V>(
+        &mut self,
+        key: K,
+        expected: Version,
+        value: V,
+    ) -> Result<(), VersionMismatch>
+    where
+        K: Into<String>,
+        V: PersistentValue,
+    {
+        let key = key.into();
+        let backend_key = self.namespace.backend_key(&key);
+        let actual = self.version_of(&backend_key);
+        if actual != expected {
+            return Err(VersionMismatch {
+                key,
+                expected,
+                actual,
+            });
+        }
+        self.guards.insert(backend_key, expected);
+        self.put(key, value);
+        Ok(())
+    }
+
+    /// Requires `keys` to still be at the version seen by this run when the effects are
+    /// committed. Otherwise all the effects of the handler are discarded and it is re-run.
+    pub fn require_unchanged<K>(&mut self, keys: &[K])
+    where
+        K: AsRef<str>,
+    {
+        for key in keys {
+            let backend_key = self.namespace.backend_key(key.as_ref());
+            let version = self.version_of(&backend_key);
+            self.guards.insert(backend_key, version);
+        }
+    }
+
+    /// Version of `key`, as seen by this run if it already read the key.
+    pub fn version(&self, key: &str) -> Version {
+        self.version_of(&self.namespace.backend_key(key))
     }
 
     pub fn has_any(&self, key: &str) -> bool {
@@ -53,9 +262,12 @@ impl<'a> GlobalStorage<'a> {
     {
         let key = key.into();
         self.populate_cache::<V>(key.clone());
-        self.effects.insert(key.clone(), GlobalEffect::Modified);
+        if let Some(PendingEffect::Commutative(effect)) = self.effects.remove(&key) {
+            self.apply_to_cached(&key, effect);
+        }
+        self.effects.insert(key.clone(), PendingEffect::Modified);
         self.cache
-            .get_mut(&key)
+            .get_dirty(&key)
             .unwrap_or_else(|| std::panic::panic_any(StorageError::KeyNotFound))
     }
 
@@ -63,24 +275,150 @@ impl<'a> GlobalStorage<'a> {
     where
         K: Into<String>,
     {
-        // TODO: delete from cache
-        self.effects.insert(key.into(), GlobalEffect::Deleted);
+        let key = key.into();
+        self.cache.remove(&key);
+        self.effects.insert(key, PendingEffect::Deleted);
+    }
+
+    /// Removes `key`, returning its value if it had one.
+    pub fn take<V, K>(&mut self, key: K) -> Option<V>
+    where
+        K: Into<String>,
+        V: PersistentValue,
+    {
+        let key = key.into();
+        self.populate_cache::<V>(key.clone());
+        if let Some(PendingEffect::Commutative(effect)) = self.effects.remove(&key) {
+            self.apply_to_cached(&key, effect);
+        }
+        self.effects.insert(key.clone(), PendingEffect::Deleted);
+        self.cache.take(&key)
+    }
+
+    /// Adds `n` to the `i64` at `key` without reading it, so it never conflicts with concurrent
+    /// increments.
+    pub fn increment<K>(&mut self, key: K, n: i64)
+    where
+        K: Into<String>,
+    {
+        self.record_commutative(key.into(), GlobalEffect::Increment(n));
+    }
+
+    /// Sets the `i64` at `key` to `value` if it is larger, without reading it.
+    pub fn max<K>(&mut self, key: K, value: i64)
+    where
+        K: Into<String>,
+    {
+        self.record_commutative(key.into(), GlobalEffect::MaxRegister(value));
+    }
+
+    /// Adds `element` to the [`crdt::GSet`] at `key` without reading it.
+    pub fn set_add<K, T>(&mut self, key: K, element: &T)
+    where
+        K: Into<String>,
+        T: Serialize,
+    {
+        let element = crdt::encode_element(element);
+        self.record_commutative(key.into(), GlobalEffect::SetAdd(vec![element]));
+    }
+
+    /// Merges `value` into the [`Crdt`] at `key` without reading it.
+    pub fn merge<K, C>(&mut self, key: K, value: C)
+    where
+        K: Into<String>,
+        C: Crdt,
+    {
+        self.record_commutative(key.into(), GlobalEffect::Merge(Delta::new(value)));
+    }
+
+    /// Forgets everything done through this storage so far, as if the handler had not run.
+    pub(crate) fn discard(&mut self) {
+        self.effects.clear();
+        self.reads.clear();
+        self.guards.clear();
+        self.cache.rollback();
+    }
+
+    /// Moves the reads and effects of this storage into `effects`, keyed by backend key. The
+    /// values of modified keys are serialized from the cache at this point.
+    pub(crate) fn into_effects(mut self, effects: &mut Effects) {
+        effects.reads.extend(self.reads);
+        effects.guards.extend(self.guards);
+        for (key, effect) in self.effects {
+            let effect = match effect {
+                PendingEffect::Modified => match self.cache.serialize(&key) {
+                    Some(bytes) => GlobalEffect::Modified(bytes),
+                    None => continue,
+                },
+                PendingEffect::Deleted => GlobalEffect::Deleted,
+                PendingEffect::Commutative(effect) => {
+                    // The cached value, if any, will be stale once the effect is merged
+                    self.cache.remove(&key);
+                    effect
+                }
+            };
+            effects
+                .global_effects
+                .insert(self.namespace.backend_key(&key), effect);
+        }
+    }
+
+    fn version_of(&self, backend_key: &str) -> Version {
+        match self.reads.get(backend_key) {
+            Some(version) => *version,
+            None => self.db.version(backend_key),
+        }
+    }
+
+    fn record_commutative(&mut self, key: String, effect: GlobalEffect) {
+        let pending = match self.effects.remove(&key) {
+            None => PendingEffect::Commutative(effect),
+            Some(PendingEffect::Commutative(pending)) => PendingEffect::Commutative(
+                pending
+                    .combine(effect)
+                    .unwrap_or_else(|| std::panic::panic_any(StorageError::Value)),
+            ),
+            // The handler already knows the value, apply the effect right away
+            Some(PendingEffect::Modified) | Some(PendingEffect::Deleted) => {
+                self.apply_to_cached(&key, effect);
+                PendingEffect::Modified
+            }
+        };
+        self.effects.insert(key, pending);
+    }
+
+    /// Applies a commutative effect to the value of `key` in the cache, or to no value if the key
+    /// is not in the cache.
+    fn apply_to_cached(&mut self, key: &str, effect: GlobalEffect) {
+        let base = self.cache.get_bytes(key);
+        let bytes = effect
+            .merge_into(self.db.upcasters(), base.as_deref())
+            .unwrap_or_else(|err| std::panic::panic_any(StorageError::Db(err)));
+        self.cache.insert_encoded(key.to_string(), bytes);
     }
 
     fn populate_cache<V>(&mut self, key: String)
     where
         V: PersistentValue,
     {
-        if self.cache.contains_key(&key) {
+        if self.cache.lookup(&key) {
+            return;
+        }
+        // Removed by this run, the stored value is as good as gone
+        if matches!(self.effects.get(&key), Some(PendingEffect::Deleted)) {
             return;
         }
-        if let Some(value) = self
+        let backend_key = self.namespace.backend_key(&key);
+        let (version, value) = self
             .db
-            .get_resource::<V>(&key)
-            .unwrap_or_else(|err| std::panic::panic_any(err))
-        {
-            self.cache.insert(key, value);
+            .get_versioned::<V>(&backend_key)
+            .unwrap_or_else(|err| std::panic::panic_any(StorageError::Db(err)));
+        if let Some(value) = value {
+            // Sized by the stored bytes rather than by serializing the value again
+            let size = self.db.stored_size(&backend_key);
+            self.cache.insert_entry(key, Box::new(value), size);
         }
+        self.reads.insert(backend_key, version);
     }
 }
 
@@ -88,6 +426,8 @@ impl GlobalStorageCache {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            dirty: HashSet::new(),
+            stats: CacheStats::default(),
         }
     }
 
@@ -99,15 +439,18 @@ impl GlobalStorageCache {
     where
         V: PersistentValue,
     {
-        self.map.insert(key, Box::new(value));
+        let size = value.to_bytes().len();
+        self.insert_entry(key, Box::new(value), size);
     }
 
     pub fn get<V>(&self, key: &str) -> Option<&V>
     where
         V: PersistentValue,
     {
-        let any = self.map.get(key)?;
-        let value = any
+        let entry = self.map.get(key)?;
+        let value = entry
+            .value
+            .as_any()
             .downcast_ref::<V>()
             .unwrap_or_else(|| std::panic::panic_any(StorageError::Value));
         Some(value)
@@ -117,10 +460,354 @@ impl GlobalStorageCache {
     where
         V: PersistentValue,
     {
-        let any = self.map.get_mut(key)?;
-        let value = any
+        let entry = self.map.get_mut(key)?;
+        let value = entry
+            .value
+            .as_any_mut()
             .downcast_mut::<V>()
             .unwrap_or_else(|| std::panic::panic_any(StorageError::Value));
         Some(value)
     }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Whether `key` is in the cache, counting the lookup as a hit or a miss.
+    fn lookup(&mut self, key: &str) -> bool {
+        match self.map.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = tick();
+                self.stats.hits += 1;
+                true
+            }
+            None => {
+                self.stats.misses += 1;
+                false
+            }
+        }
+    }
+
+    /// Inserts a value modified by the running handler. It is sized once it is serialized for
+    /// the commit, see [`GlobalStorageCache::serialize`].
+    fn insert_dirty<V>(&mut self, key: String, value: V)
+    where
+        V: PersistentValue,
+    {
+        self.dirty.insert(key.clone());
+        self.insert_entry(key, Box::new(value), 0);
+    }
+
+    fn insert_encoded(&mut self, key: String, bytes: Bytes) {
+        self.dirty.insert(key.clone());
+        let size = bytes.len();
+        self.insert_entry(key, Box::new(Encoded(bytes)), size);
+    }
+
+    fn get_dirty<V>(&mut self, key: &str) -> Option<&mut V>
+    where
+        V: PersistentValue,
+    {
+        let entry = self.map.get_mut(key)?;
+        if let Some(Encoded(bytes)) = entry.value.as_any().downcast_ref::<Encoded>() {
+            let value: V = schema::decode(VALUE_CODEC, V::VERSION, bytes)
+                .unwrap_or_else(|_| std::panic::panic_any(StorageError::Value));
+            entry.value = Box::new(value);
+        }
+        self.dirty.insert(key.to_string());
+        self.get_mut(key)
+    }
+
+    /// Removes `key` from the cache and returns its value.
+    fn take<V>(&mut self, key: &str) -> Option<V>
+    where
+        V: PersistentValue,
+    {
+        let entry = self.map.remove(key)?;
+        self.stats.bytes -= entry.size;
+        self.dirty.remove(key);
+        let value = match entry.value.into_any().downcast::<V>() {
+            Ok(value) => *value,
+            Err(value) => match value.downcast::<Encoded>() {
+                Ok(encoded) => schema::decode(VALUE_CODEC, V::VERSION, &encoded.0)
+                    .unwrap_or_else(|_| std::panic::panic_any(StorageError::Value)),
+                Err(_) => std::panic::panic_any(StorageError::Value),
+            },
+        };
+        Some(value)
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.map.remove(key) {
+            self.stats.bytes -= entry.size;
+        }
+    }
+
+    /// The effects of the last run were committed, its values are now the committed ones.
+    pub fn commit(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// The effects of the last run were discarded, forget the values it modified.
+    pub fn rollback(&mut self) {
+        for key in std::mem::take(&mut self.dirty) {
+            self.remove(&key);
+        }
+    }
+
+    /// Evicts clean entries, least recently used first, until the cache fits in `budget` bytes.
+    pub fn evict_to(&mut self, budget: usize) {
+        while self.stats.bytes > budget && self.evict_lru() {}
+    }
+
+    /// When the least recently used clean entry was last used.
+    pub(crate) fn lru_tick(&self) -> Option<u64> {
+        self.clean_entries().map(|(_, entry)| entry.last_used).min()
+    }
+
+    /// Evicts the least recently used clean entry. Returns `false` if there is none.
+    pub(crate) fn evict_lru(&mut self) -> bool {
+        let lru = self
+            .clean_entries()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        match lru {
+            Some(key) => {
+                self.remove(&key);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn clean_entries(&self) -> impl Iterator<Item = (&String, &CacheEntry)> {
+        self.map
+            .iter()
+            .filter(move |(key, _)| !self.dirty.contains(*key))
+    }
+
+    fn insert_entry(&mut self, key: String, value: Box<dyn CachedValue>, size: usize) {
+        let entry = CacheEntry {
+            value,
+            size,
+            last_used: tick(),
+        };
+        self.stats.bytes += size;
+        if let Some(old) = self.map.insert(key, entry) {
+            self.stats.bytes -= old.size;
+        }
+    }
+
+    fn get_bytes(&self, key: &str) -> Option<Bytes> {
+        Some(self.map.get(key)?.value.to_bytes())
+    }
+
+    /// Serializes the value of `key` for a commit, sizing its entry with the result.
+    fn serialize(&mut self, key: &str) -> Option<Bytes> {
+        let entry = self.map.get_mut(key)?;
+        let bytes = entry.value.to_bytes();
+        self.stats.bytes = self.stats.bytes - entry.size + bytes.len();
+        entry.size = bytes.len();
+        Some(bytes)
+    }
+}
+
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, other: Self) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Default for CacheBudget {
+    fn default() -> Self {
+        Self {
+            per_actor: usize::MAX,
+            per_runtime: usize::MAX,
+        }
+    }
+}
+
+/// Logical clock ordering cache accesses across every cache in the process, so entries of
+/// different actors can be compared when enforcing the runtime budget.
+fn tick() -> u64 {
+    static CLOCK: AtomicU64 = AtomicU64::new(0);
+    CLOCK.fetch_add(1, Ordering::Relaxed)
+}
+
+impl<V> CachedValue for V
+where
+    V: PersistentValue,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        schema::encode(VALUE_CODEC, V::VERSION, self)
+            .unwrap_or_else(|_| std::panic::panic_any(StorageError::Value))
+    }
+}
+
+impl CachedValue for Encoded {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+}
+
+impl Deref for CacheRef<'_> {
+    type Target = GlobalStorageCache;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            CacheRef::Borrowed(cache) => cache,
+            CacheRef::Owned(cache) => cache,
+        }
+    }
+}
+
+impl DerefMut for CacheRef<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            CacheRef::Borrowed(cache) => cache,
+            CacheRef::Owned(cache) => cache,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{GlobalEffect, GlobalStorage, GlobalStorageCache, Namespace};
+    use crate::{
+        actor::{AnyActorId, PersistentActor, PersistentActorId},
+        context::Effects,
+        database::{Database, VALUE_CODEC},
+        schema,
+    };
+
+    struct Owner;
+
+    impl PersistentActor for Owner {
+        const NAME: &'static str = "Owner";
+    }
+
+    /// Named so that its ids start like the ids of [`Owner`].
+    struct Nested;
+
+    impl PersistentActor for Nested {
+        const NAME: &'static str = "Owner/1";
+    }
+
+    fn owner() -> AnyActorId {
+        PersistentActorId::<Owner>::new(1).into()
+    }
+
+    fn store(db: &mut Database, key: &str, value: u32) {
+        let bytes = schema::encode(VALUE_CODEC, 0, &value).unwrap();
+        let effects = HashMap::from([(key.to_string(), GlobalEffect::Modified(bytes))]);
+        assert!(db.commit(&HashMap::new(), &HashMap::new(), effects).is_ok());
+    }
+
+    #[test]
+    fn namespaces_do_not_collide() {
+        let nested = PersistentActorId::<Nested>::new(2).into();
+        let keys: HashSet<String> = [
+            Namespace::Shared("a/b".to_string()).backend_key("c"),
+            Namespace::Shared("a".to_string()).backend_key("b/c"),
+            Namespace::Actor(owner()).backend_key("callback/1"),
+            Namespace::Runtime(owner()).backend_key("callback/1"),
+            Namespace::Actor(owner()).backend_key("2/a"),
+            Namespace::Actor(nested).backend_key("a"),
+            Namespace::Runtime(owner()).backend_key("2/a"),
+            Namespace::Runtime(nested).backend_key("a"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(keys.len(), 8);
+    }
+
+    #[test]
+    fn removed_keys_are_gone_for_the_run() {
+        let mut db = Database::new();
+        let backend_key = Namespace::Actor(owner()).backend_key("a");
+        store(&mut db, &backend_key, 1);
+        let mut cache = GlobalStorageCache::new();
+        let mut storage = GlobalStorage::private(owner(), &db, &mut cache);
+        assert_eq!(storage.take::<u32, _>("b"), None);
+        storage.remove("a");
+        assert_eq!(storage.take::<u32, _>("a"), None);
+        let mut effects = Effects::default();
+        storage.into_effects(&mut effects);
+        assert!(matches!(
+            effects.global_effects[&backend_key],
+            GlobalEffect::Deleted
+        ));
+    }
+
+    #[test]
+    fn entries_are_sized_by_their_bytes() {
+        let mut db = Database::new();
+        let backend_key = Namespace::Actor(owner()).backend_key("a");
+        store(&mut db, &backend_key, 1);
+        let mut cache = GlobalStorageCache::new();
+        let mut storage = GlobalStorage::private(owner(), &db, &mut cache);
+        let _: &mut u32 = storage.borrow_mut("a");
+        drop(storage);
+        assert_eq!(cache.stats().bytes, db.stored_size(&backend_key));
+        let mut storage = GlobalStorage::private(owner(), &db, &mut cache);
+        *storage.borrow_mut::<u32, _>("a") = u32::MAX;
+        storage.put("b", u64::MAX);
+        let mut effects = Effects::default();
+        storage.into_effects(&mut effects);
+        cache.commit();
+        let written: usize = effects
+            .global_effects
+            .values()
+            .map(|effect| match effect {
+                GlobalEffect::Modified(bytes) => bytes.len(),
+                _ => 0,
+            })
+            .sum();
+        assert!(written > db.stored_size(&backend_key));
+        assert_eq!(cache.stats().bytes, written);
+    }
+
+    #[test]
+    fn removed_keys_are_evicted() {
+        let mut db = Database::new();
+        store(&mut db, &Namespace::Actor(owner()).backend_key("a"), 1);
+        let mut cache = GlobalStorageCache::new();
+        let mut storage = GlobalStorage::private(owner(), &db, &mut cache);
+        let _: &mut u32 = storage.borrow_mut("a");
+        storage.remove("a");
+        storage.into_effects(&mut Effects::default());
+        cache.commit();
+        assert!(!cache.contains_key("a"));
+        assert_eq!(cache.stats().bytes, 0);
+    }
 }
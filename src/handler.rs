@@ -1,5 +1,39 @@
+use std::{fmt, future::Future};
+
 use super::{actor::PersistentActor, context::Context, message::Message};
 
 pub trait Handler<M: Message>: PersistentActor {
-    fn handle(&self, cx: &mut Context<Self>, message: M);
+    /// Returned to give up on a message. Nothing the handler did is committed, and the message
+    /// goes through the same supervision as when the handler panics, see
+    /// [`crate::retry::RetryPolicy`].
+    type Error: fmt::Debug + Send + Sync + 'static;
+
+    fn handle(&self, cx: &mut Context<Self>, message: M) -> Result<(), Self::Error>;
+}
+
+/// A handler that can wait for replies with [`Context::ask`]. While waiting the handler is
+/// suspended durably. When a reply arrives it is run again from the start, asks that already got
+/// a reply completing right away, so it must make the same asks in the same order every time.
+/// Its effects, except for the requests of its asks, are only committed once it completes.
+pub trait AsyncHandler<M: Message>: Receives<M> {
+    /// See [`Handler::Error`]. An error discards the effects of the last run only, the requests
+    /// of asks made by earlier runs were already sent.
+    type Error: fmt::Debug + Send + Sync + 'static;
+
+    fn handle(
+        &self,
+        cx: &mut Context<Self>,
+        message: M,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// Actors that can be sent `M`. It is implemented for every [`Handler`], an [`AsyncHandler`] has
+/// to implement it by hand as the two blanket implementations would overlap.
+pub trait Receives<M: Message>: PersistentActor {}
+
+impl<A, M> Receives<M> for A
+where
+    A: Handler<M>,
+    M: Message,
+{
 }
@@ -1,17 +1,55 @@
-use std::marker::PhantomData;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
 
-use super::{actor::AnyActorId, message::AnyMessage};
+use super::{
+    actor::AnyActorId,
+    codec::{Codec, CodecId, MessagePack},
+    message::AnyMessage,
+};
 
 pub struct LogEntry<L>
 where
     L: Log,
 {
     pub sender_id: AnyActorId,
+    /// Position of the message among the messages from `sender_id` to the owner of the log,
+    /// starting at 1. The runtime appends the messages of a sender to a receiver in order.
+    pub seq: u64,
     pub message: AnyMessage,
     pub next_idx: L::LogIndex,
     _marker: PhantomData<L>,
 }
 
+impl<L> LogEntry<L>
+where
+    L: Log,
+{
+    pub fn new(
+        sender_id: AnyActorId,
+        seq: u64,
+        message: AnyMessage,
+        next_idx: L::LogIndex,
+    ) -> Self {
+        Self {
+            sender_id,
+            seq,
+            message,
+            next_idx,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Codec the message of this entry was encoded with. It may differ from [`Log::Codec`] if
+    /// the log was written while another codec was configured.
+    pub fn codec(&self) -> CodecId {
+        self.message.codec
+    }
+}
+
 pub trait LogIndex: Copy {
     const ZERO: Self;
 }
@@ -20,6 +58,14 @@ pub trait LogIndex: Copy {
 pub trait Log: Sized {
     type LogIndex: LogIndex;
     type Error;
+    /// Codec used to encode the messages sent by actors running with this log. Implementations
+    /// must store the codec id and the envelope of every appended message next to its bytes, and
+    /// give both back when reading it, see [`AnyMessage::with_envelope`]. The envelope routes
+    /// replies to callbacks, asks and gathers, a log dropping it loses them on restart.
+    type Codec: Codec;
+    /// Whether entries outlive the process. Messages for a log that is not durable are never
+    /// encoded, they go from the sender to the handler of the receiver as typed values.
+    const DURABLE: bool = true;
 
     async fn read(
         &mut self,
@@ -31,12 +77,30 @@ pub trait Log: Sized {
         &mut self,
         from: AnyActorId,
         to: AnyActorId,
+        seq: u64,
         msg: AnyMessage,
     ) -> Result<Self::LogIndex, Self::Error>;
 }
 
 pub struct DummyLog;
 
+/// A log keeping its entries in memory. Nothing survives a restart, in exchange messages between
+/// actors of the runtime are never encoded. Clones share their entries, so one log can back
+/// several actors, each reading only the messages sent to it.
+#[derive(Clone, Default)]
+pub struct MemoryLog {
+    entries: Arc<Mutex<HashMap<AnyActorId, Vec<MemoryEntry>>>>,
+}
+
+/// Sender, sequence number and message of an entry of a [`MemoryLog`].
+type MemoryEntry = (AnyActorId, u64, AnyMessage);
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
 impl LogIndex for u32 {
     const ZERO: Self = 0;
 }
@@ -45,6 +109,7 @@ impl LogIndex for u32 {
 impl Log for DummyLog {
     type LogIndex = u32;
     type Error = Box<dyn std::error::Error>;
+    type Codec = MessagePack;
 
     async fn read(
         &mut self,
@@ -58,8 +123,95 @@ impl Log for DummyLog {
         &mut self,
         from: AnyActorId,
         to: AnyActorId,
+        seq: u64,
         msg: AnyMessage,
     ) -> Result<u32, Self::Error> {
         todo!();
     }
 }
+
+#[async_trait::async_trait]
+impl Log for MemoryLog {
+    type LogIndex = u32;
+    type Error = Infallible;
+    type Codec = MessagePack;
+    const DURABLE: bool = false;
+
+    async fn read(
+        &mut self,
+        actor_id: AnyActorId,
+        idx: u32,
+    ) -> Result<Option<LogEntry<Self>>, Self::Error> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get(&actor_id)
+            .and_then(|entries| entries.get(idx as usize))
+            .map(|(from, seq, msg)| LogEntry::new(*from, *seq, msg.clone(), idx + 1));
+        Ok(entry)
+    }
+
+    async fn append(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        seq: u64,
+        msg: AnyMessage,
+    ) -> Result<u32, Self::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.entry(to).or_default();
+        entries.push((from, seq, msg));
+        Ok(entries.len() as u32 - 1)
+    }
+}
+
+/// A [`MemoryLog`] that encodes the messages appended to it with `C`, like a durable log would.
+#[cfg(test)]
+pub(crate) struct EncodingLog<C = MessagePack>(MemoryLog, PhantomData<fn() -> C>);
+
+#[cfg(test)]
+impl<C> Clone for EncodingLog<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+#[cfg(test)]
+impl<C> Default for EncodingLog<C> {
+    fn default() -> Self {
+        Self(MemoryLog::default(), PhantomData)
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl<C> Log for EncodingLog<C>
+where
+    C: Codec + 'static,
+{
+    type LogIndex = u32;
+    type Error = Infallible;
+    type Codec = C;
+
+    async fn read(
+        &mut self,
+        actor_id: AnyActorId,
+        idx: u32,
+    ) -> Result<Option<LogEntry<Self>>, Self::Error> {
+        let entry = self.0.read(actor_id, idx).await?;
+        Ok(entry
+            .map(|entry| LogEntry::new(entry.sender_id, entry.seq, entry.message, entry.next_idx)))
+    }
+
+    async fn append(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        seq: u64,
+        msg: AnyMessage,
+    ) -> Result<u32, Self::Error> {
+        let bytes = msg.bytes().unwrap().into_owned();
+        let encoded = AnyMessage::from_parts(msg.name, msg.codec, bytes)
+            .with_envelope(msg.envelope().clone());
+        self.0.append(from, to, seq, encoded).await
+    }
+}
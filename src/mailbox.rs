@@ -0,0 +1,72 @@
+use std::ops::AddAssign;
+
+/// How many messages can wait in the log of an actor, and what happens to the ones sent once
+/// it is full. Actors without a limit have unbounded mailboxes.
+#[derive(Clone, Copy, Debug)]
+pub struct MailboxLimit {
+    pub capacity: u64,
+    pub overflow: Overflow,
+}
+
+/// What happens to a message sent to an actor whose mailbox is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// The send fails with [`crate::dyn_table::DispatchError::MailboxFull`], the sender can retry
+    /// it later, e.g. through a [`crate::retry::RetryPolicy`]. Messages that are not sent by a
+    /// handler, e.g. by timers or to the subscribers of a topic, go to the dead letters.
+    Reject,
+    /// The oldest message waiting in the mailbox is dropped to make room. While the actor is
+    /// quarantined, or holds a message an operator released or fixed, that message is kept and
+    /// new messages are rejected instead.
+    DropOldest,
+    /// The message is appended anyway, but its sender is not scheduled again until the mailbox
+    /// has room. Messages an actor sends to itself, or that are not sent by an actor, never
+    /// delay anyone. Actors sending to each other can delay each other forever.
+    Delay,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MailboxStats {
+    /// Messages waiting to be handled.
+    pub depth: u64,
+    /// Highest `depth` since the runtime started.
+    pub peak_depth: u64,
+    /// Messages rejected because the mailbox was full, see [`Overflow::Reject`] and
+    /// [`Overflow::DropOldest`].
+    pub rejected: u64,
+    /// Messages dropped to make room, see [`Overflow::DropOldest`].
+    pub dropped: u64,
+    /// Times a sender was delayed because the mailbox was full, see [`Overflow::Delay`].
+    pub delayed: u64,
+}
+
+impl MailboxLimit {
+    pub fn new(capacity: u64, overflow: Overflow) -> Self {
+        Self { capacity, overflow }
+    }
+
+    pub(crate) fn is_full(&self, stats: &MailboxStats) -> bool {
+        stats.depth >= self.capacity
+    }
+}
+
+impl MailboxStats {
+    pub(crate) fn push(&mut self) {
+        self.depth += 1;
+        self.peak_depth = self.peak_depth.max(self.depth);
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+}
+
+impl AddAssign for MailboxStats {
+    fn add_assign(&mut self, other: Self) {
+        self.depth += other.depth;
+        self.peak_depth = self.peak_depth.max(other.peak_depth);
+        self.rejected += other.rejected;
+        self.dropped += other.dropped;
+        self.delayed += other.delayed;
+    }
+}
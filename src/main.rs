@@ -1,15 +1,35 @@
+#![cfg_attr(test, feature(test))]
+
 mod actor;
+mod ask;
 mod client_server;
+mod codec;
 mod context;
+mod crdt;
 mod database;
+mod dead_letter;
+mod dedup;
 mod dispatcher;
 mod dyn_table;
+mod envelope;
 mod errors;
+mod gather;
 mod global_storage;
 mod handler;
 mod log;
+mod mailbox;
 mod message;
+mod quarantine;
+mod reminder;
+mod retry;
 mod runtime;
+mod scheduler;
+mod schema;
+mod sequence;
+mod timer;
+mod topic;
+
+use std::convert::Infallible;
 
 use actor::PersistentActor;
 use handler::Handler;
@@ -29,11 +49,11 @@ impl PersistentActor for Counter {
         if self.i > 0 {
             cx.storage.put("i", self.i);
         }
-        cx.dispatcher.send(cx.actor_id, Dec);
+        cx.dispatcher.send(cx.actor_id, Dec).unwrap();
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Dec;
 
 impl Message for Dec {
@@ -41,16 +61,19 @@ impl Message for Dec {
 }
 
 impl Handler<Dec> for Counter {
-    fn handle(&self, cx: &mut context::Context<Self>, _: Dec) {
+    type Error = Infallible;
+
+    fn handle(&self, cx: &mut context::Context<Self>, _: Dec) -> Result<(), Infallible> {
         let counter: &mut u32 = cx.storage.borrow_mut("counter");
         if *counter > 0 {
             *counter -= 1;
-            cx.dispatcher.send(cx.actor_id, Dec);
+            cx.dispatcher.send(cx.actor_id, Dec).unwrap();
         }
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Inc {
     value: u32,
 }
@@ -60,21 +83,28 @@ impl Message for Inc {
 }
 
 impl Handler<Inc> for Counter {
-    fn handle(&self, cx: &mut context::Context<Self>, Inc { value }: Inc) {
+    type Error = Infallible;
+
+    fn handle(
+        &self,
+        cx: &mut context::Context<Self>,
+        Inc { value }: Inc,
+    ) -> Result<(), Infallible> {
         let counter: &mut u32 = cx.storage.borrow_mut("counter");
         *counter -= value;
-        cx.dispatcher.send(cx.actor_id, Inc { value: 1 });
+        cx.dispatcher.send(cx.actor_id, Inc { value: 1 }).unwrap();
+        Ok(())
     }
 }
 
-fn do_thing(mut runtime: Runtime<DummyLog>) {
+async fn do_thing(mut runtime: Runtime<DummyLog>) {
     runtime.register_actor::<Counter>();
     runtime.register_handler::<Counter, Inc>();
     runtime.register_handler::<Counter, Dec>();
 
     runtime.add_actor(Counter { i: 0 }, DummyLog);
 
-    runtime.run();
+    let _ = runtime.run().await;
 }
 
 fn main() {}
@@ -1,5 +1,16 @@
-use rmp_serde as rmps;
-use serde::{Deserialize, Serialize};
+use std::{any::Any, borrow::Cow, fmt, sync::Arc};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    actor,
+    codec::CodecId,
+    database::Bytes,
+    dyn_table::{DispatchError, DispatchResult},
+    envelope::Envelope,
+    scheduler::Priority,
+    schema::{self, DecodeError, SchemaVersion, Upcasters},
+};
 
 // I think a better requirement here is for a Message to be Sendable/Receivable instead
 // of Serializable/Deserializable. For the most part these are equivalent but Sendable/Receivable
@@ -8,11 +19,21 @@ use serde::{Deserialize, Serialize};
 // to be called directly, then it is not serializable. But it is "sendable" as the runtime should have
 // enough information to "receive" it. Put it other way, being Sendable/Receivable only requires
 // the fields that cannot be recovered by the runtime to be serializable.
-pub trait Message: Serialize + for<'a> Deserialize<'a> {
+//
+// Messages between actors of the same runtime can skip serialization altogether, see
+// `AnyMessage::local`. Such a message is shared between the log and the runtime, so it has to be
+// `Sync`, and `Clone` so the handler can still get its own copy while the log keeps the original.
+pub trait Message: 'static + Clone + Send + Sync + Serialize + for<'a> Deserialize<'a> {
     const NAME: &'static str;
+    /// Bump when the encoding of the message changes, registering an upcaster from the previous
+    /// version so messages already in logs can still be delivered.
+    const VERSION: SchemaVersion = 0;
+    /// Priority messages of this type are sent with, unless the sender sets another one on the
+    /// envelope. The runtime serves actors with higher priority messages more often.
+    const PRIORITY: Priority = Priority::Normal;
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct MessageName(&'static str);
 
 impl MessageName {
@@ -24,33 +45,336 @@ impl MessageName {
     }
 }
 
+#[derive(Clone)]
 pub struct AnyMessage {
     pub name: MessageName,
-    bytes: Vec<u8>,
+    /// Codec the message is, or will be, encoded with.
+    pub codec: CodecId,
+    pub(crate) envelope: Envelope,
+    payload: Payload,
+}
+
+#[derive(Clone)]
+enum Payload {
+    Encoded(Bytes),
+    /// A typed message that is only encoded if it has to leave the process. Cloning the message,
+    /// e.g. each time a log hands it out, only clones the `Arc`.
+    Local(Arc<dyn LocalMessage>),
+}
+
+trait LocalMessage: Send + Sync {
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+
+    fn encode(&self, codec: CodecId) -> DispatchResult<Bytes>;
 }
 
 impl AnyMessage {
-    pub fn downcast<M>(&self) -> Option<M>
+    pub fn encode<M>(message: &M, codec: CodecId) -> DispatchResult<Self>
     where
         M: Message,
     {
-        if self.name == MessageName::name_for::<M>() {
-            // TODO: What serialization format should we use here?, it'd be nice if we can
-            // guarantee absence of errors when serializing.
-            rmps::from_read_ref(&self.bytes).ok()
-        } else {
-            None
+        Ok(Self {
+            name: MessageName::name_for::<M>(),
+            codec,
+            envelope: Envelope::new(M::PRIORITY),
+            payload: Payload::Encoded(encode(message, codec)?),
+        })
+    }
+
+    /// Wraps `message` without encoding it. Delivering it to an actor in the same process is
+    /// then just a move, `codec` is only used if the message ends up being written somewhere.
+    pub fn local<M>(message: M, codec: CodecId) -> Self
+    where
+        M: Message,
+    {
+        Self {
+            name: MessageName::name_for::<M>(),
+            codec,
+            envelope: Envelope::new(M::PRIORITY),
+            payload: Payload::Local(Arc::new(message)),
         }
     }
-}
 
-impl<M: Message> From<M> for AnyMessage {
-    fn from(message: M) -> Self {
+    /// Rebuilds a message from its parts, e.g. when reading it back from a log. The message gets
+    /// a fresh envelope, a log must restore the one it stored with [`AnyMessage::with_envelope`].
+    pub fn from_parts(name: MessageName, codec: CodecId, bytes: Bytes) -> Self {
         Self {
-            name: MessageName(M::NAME),
-            // TODO: we should ensure that everything that implements Message
-            // is serializable (if not we should at least handle the error in Dispatcher)
-            bytes: rmps::to_vec(&message).unwrap(),
+            name,
+            codec,
+            envelope: Envelope::new(Priority::Normal),
+            payload: Payload::Encoded(bytes),
+        }
+    }
+
+    /// The encoded message, encoding it now if it was local. Durable logs and anything sending
+    /// messages out of the process must go through here.
+    pub fn bytes(&self) -> DispatchResult<Cow<'_, [u8]>> {
+        match &self.payload {
+            Payload::Encoded(bytes) => Ok(Cow::Borrowed(bytes)),
+            Payload::Local(message) => Ok(Cow::Owned(message.encode(self.codec)?)),
+        }
+    }
+
+    /// Tags the message with `key`. A recipient drops messages with the key of a message it
+    /// already handled, so a client retrying a send can reuse the key to deliver it at most once.
+    /// Recipients only remember the keys of their last messages, see
+    /// [`crate::runtime::Runtime::set_dedup_window`].
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.envelope.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.envelope.idempotency_key.as_deref()
+    }
+
+    /// Replaces the envelope of the message, e.g. with the one stored next to it in a log.
+    pub fn with_envelope(mut self, envelope: Envelope) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    /// The envelope of the message, e.g. to add headers or a deadline before appending it to a
+    /// log. Messages built outside of a handler start a new trace.
+    pub fn envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
+    }
+
+    pub fn is_local(&self) -> bool {
+        matches!(self.payload, Payload::Local(_))
+    }
+
+    pub fn downcast<M>(self) -> DispatchResult<M>
+    where
+        M: Message,
+    {
+        if self.name != MessageName::name_for::<M>() {
+            return Err(DispatchError::TypeMissmatch);
+        }
+        match self.payload {
+            Payload::Encoded(bytes) => {
+                schema::decode(self.codec, M::VERSION, &bytes).map_err(|error| {
+                    DispatchError::Decode {
+                        message: self.name,
+                        error,
+                    }
+                })
+            }
+            // The message is only cloned if someone else, e.g. the log, still holds it.
+            Payload::Local(message) => message
+                .into_any()
+                .downcast::<M>()
+                .map(|message| Arc::try_unwrap(message).unwrap_or_else(|shared| (*shared).clone()))
+                .map_err(|_| DispatchError::TypeMissmatch),
+        }
+    }
+}
+
+impl AnyMessage {
+    /// Migrates the message to version `current` of its type. Local messages are always at the
+    /// current version.
+    pub(crate) fn upcast(
+        self,
+        upcasters: &Upcasters<MessageName>,
+        current: SchemaVersion,
+    ) -> Result<Self, DecodeError> {
+        let payload = match &self.payload {
+            Payload::Encoded(bytes) => upcasters.upcast(self.name, self.codec, current, bytes)?,
+            Payload::Local(_) => return Ok(self),
+        };
+        let bytes = match payload {
+            Cow::Borrowed(_) => return Ok(self),
+            Cow::Owned(payload) => schema::with_version(current, &payload),
+        };
+        Ok(Self {
+            payload: Payload::Encoded(bytes),
+            ..self
+        })
+    }
+}
+
+impl<M> LocalMessage for M
+where
+    M: Message,
+{
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn encode(&self, codec: CodecId) -> DispatchResult<Bytes> {
+        encode(self, codec)
+    }
+}
+
+fn encode<M>(message: &M, codec: CodecId) -> DispatchResult<Bytes>
+where
+    M: Message,
+{
+    schema::encode(codec, M::VERSION, message).map_err(|error| DispatchError::Encode {
+        message: MessageName::name_for::<M>(),
+        error,
+    })
+}
+
+// A plain `TryFrom<M>` would overlap with the blanket `TryFrom<U> for T where U: Into<T>`, the
+// codec is a better fit for the second component anyway.
+impl<M: Message> TryFrom<(M, CodecId)> for AnyMessage {
+    type Error = DispatchError;
+
+    fn try_from((message, codec): (M, CodecId)) -> DispatchResult<Self> {
+        AnyMessage::encode(&message, codec)
+    }
+}
+
+impl Serialize for MessageName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Ok(MessageName(actor::intern(name)))
+    }
+}
+
+impl fmt::Display for MessageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod benches {
+    extern crate test;
+
+    use serde::{Deserialize, Serialize};
+    use test::Bencher;
+
+    use super::{AnyMessage, Message};
+    use crate::{
+        actor::{AnyActorId, PersistentActor, PersistentActorId},
+        codec::CodecId,
+        context::Context,
+        database::Database,
+        handler::Handler,
+        log::{EncodingLog, Log, MemoryLog},
+        runtime::{tests::block_on, Runtime},
+    };
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Sample {
+        id: u64,
+        name: String,
+        values: Vec<u64>,
+    }
+
+    impl Message for Sample {
+        const NAME: &'static str = "Sample";
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            id: 42,
+            name: "sample".repeat(8),
+            values: (0..64).collect(),
+        }
+    }
+
+    fn bench_delivery(b: &mut Bencher, wrap: fn(Sample) -> AnyMessage) {
+        let sample = sample();
+        b.iter(|| {
+            let message = wrap(test::black_box(sample.clone()));
+            test::black_box(message.downcast::<Sample>().ok().unwrap())
+        });
+    }
+
+    #[bench]
+    fn encoded_delivery_messagepack(b: &mut Bencher) {
+        bench_delivery(b, |sample| {
+            AnyMessage::encode(&sample, CodecId::MessagePack).unwrap()
+        });
+    }
+
+    #[bench]
+    fn encoded_delivery_bincode(b: &mut Bencher) {
+        bench_delivery(b, |sample| {
+            AnyMessage::encode(&sample, CodecId::Bincode).unwrap()
+        });
+    }
+
+    #[bench]
+    fn local_delivery(b: &mut Bencher) {
+        bench_delivery(b, |sample| AnyMessage::local(sample, CodecId::MessagePack));
+    }
+
+    /// Messages an end-to-end delivery bench sends through the runtime.
+    const MESSAGES: usize = 100;
+
+    struct Producer(PersistentActorId<Consumer>);
+
+    impl PersistentActor for Producer {
+        const NAME: &'static str = "Producer";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            let sample = sample();
+            for _ in 0..MESSAGES {
+                cx.dispatcher.send(self.0, sample.clone()).unwrap();
+            }
+        }
+    }
+
+    struct Consumer;
+
+    impl PersistentActor for Consumer {
+        const NAME: &'static str = "Consumer";
+    }
+
+    impl Handler<Sample> for Consumer {
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, sample: Sample) -> Result<(), ()> {
+            test::black_box(sample);
+            Ok(())
         }
     }
+
+    /// Sends [`MESSAGES`] messages from one actor to another through the logs and handlers of
+    /// a runtime.
+    fn bench_runtime_delivery<L>(b: &mut Bencher)
+    where
+        L: Log + Default + Clone,
+    {
+        b.iter(|| {
+            let mut runtime = Runtime::<L>::new(Database::new());
+            runtime.register_actor::<Producer>();
+            runtime.register_actor::<Consumer>();
+            runtime.register_handler::<Consumer, Sample>();
+            let log = L::default();
+            let consumer = runtime.add_actor(Consumer, log.clone());
+            runtime.add_actor(Producer(consumer), log);
+            assert!(block_on(runtime.run()).is_ok());
+        });
+    }
+
+    #[bench]
+    fn runtime_local_delivery(b: &mut Bencher) {
+        bench_runtime_delivery::<MemoryLog>(b);
+    }
+
+    #[bench]
+    fn runtime_encoded_delivery(b: &mut Bencher) {
+        bench_runtime_delivery::<EncodingLog>(b);
+    }
 }
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    codec::CodecId,
+    database::Bytes,
+    dead_letter::Failure,
+    dyn_table::DispatchResult,
+    message::{AnyMessage, Message, MessageName},
+};
+
+/// Topic [`ActorQuarantined`] events are published to. Operators subscribe to it like to any
+/// other topic, see [`crate::context::Context::subscribe`].
+pub const QUARANTINE_TOPIC: &str = "$runtime/quarantine";
+
+/// Runs of a message whose effects may be rejected in a row before its actor is quarantined,
+/// see [`crate::runtime::Runtime::set_rerun_limit`].
+pub const DEFAULT_RERUN_LIMIT: u32 = 16;
+
+/// Published when an actor is quarantined because a message kept failing, see
+/// [`crate::retry::Fallback::Quarantine`], or because its effects kept being rejected, see
+/// [`crate::runtime::Runtime::set_rerun_limit`]. The actor is not scheduled again until it is
+/// released with [`crate::context::Context::release_actor`],
+/// [`crate::context::Context::skip_quarantined_message`] or
+/// [`crate::context::Context::fix_quarantined_message`], or their equivalents on
+/// [`crate::runtime::Runtime`], its mailbox keeps filling meanwhile.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ActorQuarantined {
+    pub actor_id: AnyActorId,
+    /// Sender of the failing message.
+    pub sender: AnyActorId,
+    pub failure: Failure,
+    /// How many times handling the message was attempted.
+    pub attempts: u32,
+    pub message: MessageName,
+    codec: CodecId,
+    bytes: Bytes,
+}
+
+impl Message for ActorQuarantined {
+    const NAME: &'static str = "ActorQuarantined";
+}
+
+/// An operator decision about a quarantined actor, applied when the effects of the handler that
+/// took it are committed.
+pub(crate) enum QuarantineCommand {
+    /// Retry the failing message.
+    Release(AnyActorId),
+    /// Move the failing message to the dead letters.
+    Skip(AnyActorId),
+    /// Retry with this message instead of the failing one.
+    Fix(AnyActorId, Box<AnyMessage>),
+}
+
+impl ActorQuarantined {
+    pub(crate) fn new(
+        actor_id: AnyActorId,
+        sender: AnyActorId,
+        message: &AnyMessage,
+        failure: Failure,
+    ) -> DispatchResult<Self> {
+        Ok(Self {
+            actor_id,
+            sender,
+            failure,
+            attempts: message.envelope.attempt,
+            message: message.name,
+            codec: message.codec,
+            bytes: message.bytes()?.into_owned(),
+        })
+    }
+
+    /// The failing message, e.g. to fix it up and pass it to
+    /// [`crate::context::Context::fix_quarantined_message`].
+    pub fn decode<M>(&self) -> DispatchResult<M>
+    where
+        M: Message,
+    {
+        AnyMessage::from_parts(self.message, self.codec, self.bytes.clone()).downcast()
+    }
+}
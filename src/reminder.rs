@@ -0,0 +1,549 @@
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    database::{Database, PersistentValue},
+    message::Message,
+    timer::{DueQueue, Timestamp},
+};
+
+/// Prefix of the keys reminders are stored under, in the runtime's part of the database.
+const REMINDERS_PREFIX: &str = "runtime/reminders/";
+
+const MINUTE: u64 = 60 * 1000;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// How far ahead to look for the next firing of a cron schedule before giving up, enough for
+/// schedules like "Feb 29 on a Monday".
+const CRON_HORIZON_DAYS: u64 = 30 * 366;
+/// Most missed firings of a reminder delivered at once with [`CatchUp::All`]. Older ones are
+/// only counted, in [`Reminder::missed`] of the first firing delivered.
+const MAX_CATCH_UP: usize = 100;
+
+/// Delivered to an actor through its `Handler<Reminder>` every time one of its reminders fires.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reminder {
+    /// The name the reminder was registered with.
+    pub name: String,
+    /// When this firing was scheduled for.
+    pub scheduled: Timestamp,
+    /// Firings missed while the runtime was down that were folded into this one, see
+    /// [`CatchUp::Once`]. With [`CatchUp::All`], the firings too old to be delivered.
+    pub missed: u32,
+}
+
+impl Message for Reminder {
+    const NAME: &'static str = "Reminder";
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Every `period`, starting one period after the reminder is registered.
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// What to do with the firings of a reminder that were due while the runtime was down. Both
+/// kinds of [`Schedule`] catch up the same way: the missed firings are the ones the schedule had
+/// from the last firing delivered until the runtime started again, in UTC for a
+/// [`Schedule::Cron`]. Only the last [`MAX_CATCH_UP`] of them are delivered with
+/// [`CatchUp::All`], all of them are counted in [`Reminder::missed`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CatchUp {
+    /// Deliver a single [`Reminder`] for all of them.
+    Once,
+    /// Deliver a [`Reminder`] for each of them.
+    All,
+    /// Drop them, the reminder fires again at its next scheduled time.
+    Skip,
+}
+
+/// A cron expression with the five standard fields: minute, hour, day of month, month and day of
+/// week. Fields accept `*`, values, ranges `a-b`, steps `*/n` or `a-b/n` and comma separated
+/// lists of those. Times are in UTC.
+///
+/// As in vixie cron, if neither the day of month nor the day of week starts with `*` a day
+/// matching either of them matches, e.g. `0 0 13 * 5` fires on the 13th and on Fridays.
+/// Otherwise a day has to match both, e.g. `0 0 */2 * 1` fires on Mondays with an odd day of
+/// month.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month and day of week fields do not start with `*`. If both are
+    /// restricted a day matching either of them matches.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+#[derive(Debug)]
+pub struct CronError {
+    pub expression: String,
+}
+
+/// A reminder as stored in the database.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredReminder {
+    pub(crate) actor_id: AnyActorId,
+    name: String,
+    schedule: Schedule,
+    catch_up: CatchUp,
+    /// When the reminder was registered, `Schedule::Every` periods are counted from it.
+    registered: Timestamp,
+    pub(crate) next: Option<Timestamp>,
+}
+
+impl PersistentValue for StoredReminder {}
+
+pub(crate) type ReminderQueue = DueQueue<String>;
+
+impl Schedule {
+    /// The first firing strictly after `after`, for a reminder registered at `registered`.
+    fn next_after(&self, registered: Timestamp, after: Timestamp) -> Option<Timestamp> {
+        match self {
+            Schedule::Every(period) => {
+                let period = period_millis(*period);
+                let elapsed = after.as_millis().saturating_sub(registered.as_millis());
+                let periods = elapsed / period + 1;
+                Some(Timestamp::from_millis(
+                    registered
+                        .as_millis()
+                        .saturating_add(periods.saturating_mul(period)),
+                ))
+            }
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, CronError> {
+        let error = || CronError {
+            expression: expression.to_string(),
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(error());
+        };
+        let mut weekdays_bits = parse_field(weekdays, 0, 7).ok_or_else(error)?;
+        // Both 0 and 7 are Sunday
+        if weekdays_bits & (1 << 7) != 0 {
+            weekdays_bits |= 1;
+        }
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59).ok_or_else(error)?,
+            hours: parse_field(hours, 0, 23).ok_or_else(error)?,
+            days: parse_field(days, 1, 31).ok_or_else(error)?,
+            months: parse_field(months, 1, 12).ok_or_else(error)?,
+            weekdays: weekdays_bits,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+
+    fn next_after(&self, after: Timestamp) -> Option<Timestamp> {
+        // Minutes since the epoch, starting at the first whole minute after `after`
+        let mut minute = after.as_millis() / MINUTE + 1;
+        let horizon = minute + CRON_HORIZON_DAYS * MINUTES_PER_DAY;
+        while minute < horizon {
+            let day = minute / MINUTES_PER_DAY;
+            if !self.matches_day(day) {
+                minute = (day + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            let hour = minute % MINUTES_PER_DAY / 60;
+            if self.hours & (1 << hour) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1 << (minute % 60)) != 0 {
+                return Some(Timestamp::from_millis(minute * MINUTE));
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    /// Whether the schedule fires on `day`, counted in days since the epoch.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        // The epoch was a Thursday
+        let weekday = (day + 4) % 7;
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day_matches = self.days & (1 << day_of_month) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+}
+
+impl StoredReminder {
+    pub(crate) fn new(
+        actor_id: AnyActorId,
+        name: String,
+        schedule: Schedule,
+        catch_up: CatchUp,
+        now: Timestamp,
+    ) -> Self {
+        let next = schedule.next_after(now, now);
+        Self {
+            actor_id,
+            name,
+            schedule,
+            catch_up,
+            registered: now,
+            next,
+        }
+    }
+
+    /// Whether `other` fires at the same times and catches up the same way, registering it
+    /// again then keeps this one.
+    pub(crate) fn same_schedule(&self, other: &StoredReminder) -> bool {
+        self.actor_id == other.actor_id
+            && self.schedule == other.schedule
+            && self.catch_up == other.catch_up
+    }
+
+    /// Advances the reminder past `now`, returning the reminders to deliver. Firings due before
+    /// `started`, when the runtime started, were missed and follow the catch-up policy.
+    pub(crate) fn fire(&mut self, now: Timestamp, started: Timestamp) -> Vec<Reminder> {
+        let mut skipped = self.fast_forward(now);
+        let mut missed = VecDeque::new();
+        let mut on_time = Vec::new();
+        while let Some(scheduled) = self.next.filter(|next| *next <= now) {
+            if scheduled < started {
+                if missed.len() == MAX_CATCH_UP {
+                    missed.pop_front();
+                    skipped = skipped.saturating_add(1);
+                }
+                missed.push_back(scheduled);
+            } else {
+                on_time.push(scheduled);
+            }
+            self.next = self.schedule.next_after(self.registered, scheduled);
+        }
+        let reminder = |scheduled, missed| Reminder {
+            name: self.name.clone(),
+            scheduled,
+            missed,
+        };
+        let mut reminders = match (self.catch_up, missed.back()) {
+            (CatchUp::All, _) => missed
+                .iter()
+                .enumerate()
+                .map(|(i, at)| reminder(*at, if i == 0 { skipped } else { 0 }))
+                .collect(),
+            (CatchUp::Once, Some(last)) => {
+                let count = skipped.saturating_add(missed.len() as u32 - 1);
+                vec![reminder(*last, count)]
+            }
+            (CatchUp::Once, None) | (CatchUp::Skip, _) => vec![],
+        };
+        reminders.extend(on_time.into_iter().map(|at| reminder(at, 0)));
+        reminders
+    }
+
+    /// Jumps over the firings of a [`Schedule::Every`] due up to `now` but the last
+    /// [`MAX_CATCH_UP`], so a short period is not walked one firing at a time after a long
+    /// downtime. Returns how many firings were jumped over.
+    fn fast_forward(&mut self, now: Timestamp) -> u32 {
+        let (Schedule::Every(period), Some(next)) = (&self.schedule, self.next) else {
+            return 0;
+        };
+        if next > now {
+            return 0;
+        }
+        let period = period_millis(*period);
+        let due = (now.as_millis() - next.as_millis()) / period + 1;
+        let skip = due.saturating_sub(MAX_CATCH_UP as u64);
+        self.next = Some(Timestamp::from_millis(next.as_millis() + skip * period));
+        skip.min(u32::MAX as u64) as u32
+    }
+}
+
+impl ReminderQueue {
+    /// Rebuilds the index from the reminders stored in `db`.
+    /// Returns the key of a reminder that cannot be read, it would never fire again otherwise.
+    pub(crate) fn load(db: &Database) -> Result<Self, String> {
+        let mut queue = DueQueue::default();
+        for key in db.keys_with_prefix(REMINDERS_PREFIX) {
+            match db.get_resource::<StoredReminder>(key) {
+                Ok(Some(StoredReminder {
+                    next: Some(next), ..
+                })) => queue.insert(key.to_string(), next),
+                Ok(_) => {}
+                Err(_) => return Err(key.to_string()),
+            }
+        }
+        Ok(queue)
+    }
+}
+
+/// `period` in milliseconds, at least 1 so a schedule always moves forward.
+fn period_millis(period: Duration) -> u64 {
+    u64::try_from(period.as_millis()).unwrap_or(u64::MAX).max(1)
+}
+
+/// Key the reminder `name` of `actor_id` is stored under.
+pub(crate) fn reminder_key(actor_id: AnyActorId, name: &str) -> String {
+    format!("{}{}", reminders_prefix(actor_id), name)
+}
+
+/// Prefix of the keys the reminders of `actor_id` are stored under.
+pub(crate) fn reminders_prefix(actor_id: AnyActorId) -> String {
+    format!("{}{}/", REMINDERS_PREFIX, actor_id)
+}
+
+/// Parses one field of a cron expression into a bit set of the values it matches.
+fn parse_field(field: &str, min: u64, max: u64) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok()?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            // `a/n` runs from `a` to the end of the range
+            None if step > 1 => (range.parse().ok()?, max),
+            None => {
+                let value = range.parse().ok()?;
+                (value, value)
+            }
+        };
+        if step == 0 || start < min || start > end || end > max {
+            return None;
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+/// Month (1 to 12) and day of the month of `day`, counted in days since the epoch.
+fn month_and_day(day: u64) -> (u64, u64) {
+    // Shifted so years start in March, see http://howardhinnant.github.io/date_algorithms.html
+    let day = day + 719_468;
+    let day_of_era = day % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month, day_of_month)
+}
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cron expression `{}`", self.expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        month_and_day, parse_field, CatchUp, Cron, Reminder, Schedule, StoredReminder,
+        MAX_CATCH_UP, MINUTE, MINUTES_PER_DAY,
+    };
+    use crate::{
+        actor::{PersistentActor, PersistentActorId},
+        timer::Timestamp,
+    };
+
+    struct Owner;
+
+    impl PersistentActor for Owner {
+        const NAME: &'static str = "Owner";
+    }
+
+    /// Minute `minute` of hour `hour` of `day`, counted in days since the epoch.
+    fn at(day: u64, hour: u64, minute: u64) -> Timestamp {
+        Timestamp::from_millis((day * MINUTES_PER_DAY + hour * 60 + minute) * MINUTE)
+    }
+
+    fn next(expression: &str, after: Timestamp) -> Option<Timestamp> {
+        Cron::parse(expression).unwrap().next_after(after)
+    }
+
+    fn bits(values: &[u64]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    #[test]
+    fn parses_values_ranges_steps_and_lists() {
+        assert_eq!(
+            parse_field("*", 1, 12),
+            Some(bits(&(1..=12).collect::<Vec<_>>()))
+        );
+        assert_eq!(parse_field("5", 0, 59), Some(bits(&[5])));
+        assert_eq!(parse_field("2-4", 0, 59), Some(bits(&[2, 3, 4])));
+        assert_eq!(parse_field("*/15", 0, 59), Some(bits(&[0, 15, 30, 45])));
+        assert_eq!(parse_field("10-20/5", 0, 59), Some(bits(&[10, 15, 20])));
+        assert_eq!(parse_field("50/3", 0, 59), Some(bits(&[50, 53, 56, 59])));
+        assert_eq!(
+            parse_field("1,3-4,*/20", 0, 59),
+            Some(bits(&[0, 1, 3, 4, 20, 40]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert_eq!(parse_field("60", 0, 59), None);
+        assert_eq!(parse_field("0", 1, 31), None);
+        assert_eq!(parse_field("5-2", 0, 59), None);
+        assert_eq!(parse_field("*/0", 0, 59), None);
+        assert_eq!(parse_field("a", 0, 59), None);
+        assert_eq!(parse_field("1,", 0, 59), None);
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("* * * * * *").is_err());
+    }
+
+    #[test]
+    fn sunday_is_zero_and_seven() {
+        // 1970-01-04 was the first Sunday
+        assert_eq!(next("0 0 * * 0", at(0, 0, 0)), Some(at(3, 0, 0)));
+        assert_eq!(next("0 0 * * 7", at(0, 0, 0)), Some(at(3, 0, 0)));
+        assert_eq!(next("0 0 * * 6-7", at(3, 0, 0)), Some(at(9, 0, 0)));
+    }
+
+    #[test]
+    fn finds_the_next_minute_and_hour() {
+        assert_eq!(next("0 12 * * *", at(0, 0, 0)), Some(at(0, 12, 0)));
+        assert_eq!(next("0 12 * * *", at(0, 12, 0)), Some(at(1, 12, 0)));
+        assert_eq!(next("*/15 * * * *", at(0, 0, 7)), Some(at(0, 0, 15)));
+        assert_eq!(next("30 23 * * *", at(0, 23, 31)), Some(at(1, 23, 30)));
+        // Seconds past the minute still move to the next one
+        let after = Timestamp::from_millis(at(0, 0, 15).as_millis() + 1);
+        assert_eq!(next("*/15 * * * *", after), Some(at(0, 0, 30)));
+    }
+
+    #[test]
+    fn matches_day_of_month_or_day_of_week() {
+        // 1970-01-01 was a Thursday
+        assert_eq!(next("0 0 * * 1", at(0, 0, 0)), Some(at(4, 0, 0)));
+        assert_eq!(next("0 0 13 * *", at(0, 0, 0)), Some(at(12, 0, 0)));
+        // Restricting both matches either, Friday the 2nd comes first
+        assert_eq!(next("0 0 13 * 5", at(0, 0, 0)), Some(at(1, 0, 0)));
+        assert_eq!(next("0 0 13 * 5", at(1, 0, 0)), Some(at(8, 0, 0)));
+        // A step over the whole month is not a restriction, Monday the 5th comes first and
+        // Monday the 12th is skipped
+        assert_eq!(next("0 0 */2 * 1", at(0, 0, 0)), Some(at(4, 0, 0)));
+        assert_eq!(next("0 0 */2 * 1", at(4, 0, 0)), Some(at(18, 0, 0)));
+        // Tuesday the 13th
+        assert_eq!(next("0 0 13 * */2", at(0, 0, 0)), Some(at(12, 0, 0)));
+    }
+
+    #[test]
+    fn skips_months_without_the_day() {
+        // From January 31st 1970 to March 31st
+        assert_eq!(next("0 0 31 * *", at(30, 0, 0)), Some(at(89, 0, 0)));
+        // February 29th 1972
+        assert_eq!(next("0 0 29 2 *", at(0, 0, 0)), Some(at(789, 0, 0)));
+        assert_eq!(next("0 0 1 1 *", at(0, 0, 0)), Some(at(365, 0, 0)));
+    }
+
+    #[test]
+    fn gives_up_on_impossible_schedules() {
+        assert_eq!(next("0 0 30 2 *", at(0, 0, 0)), None);
+    }
+
+    #[test]
+    fn converts_days_to_months() {
+        assert_eq!(month_and_day(0), (1, 1));
+        assert_eq!(month_and_day(30), (1, 31));
+        assert_eq!(month_and_day(31), (2, 1));
+        assert_eq!(month_and_day(58), (2, 28));
+        assert_eq!(month_and_day(59), (3, 1));
+        assert_eq!(month_and_day(364), (12, 31));
+        assert_eq!(month_and_day(365), (1, 1));
+    }
+
+    #[test]
+    fn handles_leap_years() {
+        // 1972 is a leap year
+        assert_eq!(month_and_day(789), (2, 29));
+        assert_eq!(month_and_day(790), (3, 1));
+        // 2000 is one too, as a multiple of 400
+        assert_eq!(month_and_day(11_016), (2, 29));
+        assert_eq!(month_and_day(11_017), (3, 1));
+        // 2100 is not, as a multiple of 100
+        assert_eq!(month_and_day(47_540), (2, 28));
+        assert_eq!(month_and_day(47_541), (3, 1));
+    }
+
+    /// A reminder following `expression` registered at the start of the epoch, fired when the
+    /// runtime starts at `now`.
+    fn catch_up(
+        expression: &str,
+        catch_up: CatchUp,
+        now: Timestamp,
+    ) -> (StoredReminder, Vec<Reminder>) {
+        let owner = PersistentActorId::<Owner>::new(1).into();
+        let schedule = Schedule::Cron(Cron::parse(expression).unwrap());
+        let mut reminder =
+            StoredReminder::new(owner, "cron".to_string(), schedule, catch_up, at(0, 0, 0));
+        let reminders = reminder.fire(now, now);
+        (reminder, reminders)
+    }
+
+    fn fired(reminders: &[Reminder]) -> Vec<(Timestamp, u32)> {
+        reminders
+            .iter()
+            .map(|reminder| (reminder.scheduled, reminder.missed))
+            .collect()
+    }
+
+    #[test]
+    fn cron_schedules_catch_up_on_the_firings_missed_while_down() {
+        let now = at(3, 13, 0);
+        let (reminder, reminders) = catch_up("0 12 * * *", CatchUp::All, now);
+        let days = (0..4).map(|day| (at(day, 12, 0), 0)).collect::<Vec<_>>();
+        assert_eq!(fired(&reminders), days);
+        assert_eq!(reminder.next, Some(at(4, 12, 0)));
+        let (reminder, reminders) = catch_up("0 12 * * *", CatchUp::Once, now);
+        assert_eq!(fired(&reminders), vec![(at(3, 12, 0), 3)]);
+        assert_eq!(reminder.next, Some(at(4, 12, 0)));
+        let (reminder, reminders) = catch_up("0 12 * * *", CatchUp::Skip, now);
+        assert!(reminders.is_empty());
+        assert_eq!(reminder.next, Some(at(4, 12, 0)));
+    }
+
+    #[test]
+    fn cron_schedules_deliver_the_last_missed_firings_only() {
+        // Every minute of the first day was missed, the start of the second one is on time
+        let now = at(1, 0, 0);
+        let (_, reminders) = catch_up("* * * * *", CatchUp::All, now);
+        assert_eq!(reminders.len(), MAX_CATCH_UP + 1);
+        let older = 1_439 - MAX_CATCH_UP as u32;
+        assert_eq!(fired(&reminders[..1]), vec![(at(0, 22, 20), older)]);
+        assert_eq!(fired(&reminders[MAX_CATCH_UP..]), vec![(now, 0)]);
+        let (_, reminders) = catch_up("* * * * *", CatchUp::Once, now);
+        assert_eq!(fired(&reminders), vec![(at(0, 23, 59), 1_438), (now, 0)]);
+    }
+
+    #[test]
+    fn long_periods_saturate() {
+        let schedule = Schedule::Every(Duration::MAX);
+        let registered = at(0, 0, 0);
+        let next = schedule.next_after(registered, registered);
+        assert_eq!(next, Some(Timestamp::from_millis(u64::MAX)));
+    }
+}
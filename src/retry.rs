@@ -0,0 +1,171 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    actor::AnyActorId,
+    dead_letter::{Failure, FailureKind},
+    dyn_table::HandlerError,
+};
+
+/// How to retry messages whose handler failed, set per handler or per actor type with
+/// [`crate::runtime::Runtime::set_retry_policy`] and
+/// [`crate::runtime::Runtime::set_actor_retry_policy`]. Without a policy a failed message goes
+/// straight to the dead letters.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Attempts before giving up on the message, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Factor the delay grows by after every retry.
+    pub multiplier: u32,
+    pub max_backoff: Duration,
+    /// Failures worth retrying, the message goes to the fallback on any other.
+    pub retryable: Vec<FailureKind>,
+    /// Handler errors worth retrying when [`FailureKind::Handler`] is not `retryable`, see
+    /// [`RetryPolicy::retry_handler_error`].
+    pub retryable_errors: Vec<ErrorFilter>,
+    /// Where the message goes once it is given up on.
+    pub fallback: Fallback,
+}
+
+/// Decides whether a handler error is worth retrying, see [`RetryPolicy::retry_handler_error`].
+pub type ErrorFilter = Arc<dyn Fn(&HandlerError) -> bool + Send + Sync>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// Store the message in the dead letters, see [`crate::runtime::Runtime::dead_letters`].
+    DeadLetter,
+    Drop,
+    /// Send the message to another actor, with its original sender.
+    Forward(AnyActorId),
+    /// Keep the message at the head of the mailbox and stop running the actor until an operator
+    /// steps in, see [`crate::quarantine::ActorQuarantined`].
+    Quarantine,
+}
+
+impl RetryPolicy {
+    /// Also retries the handler errors of type `E` for which `retry` returns `true`, e.g. the
+    /// transient variants of the handler's error enum.
+    pub fn retry_handler_error<E>(mut self, retry: fn(&E) -> bool) -> Self
+    where
+        E: 'static,
+    {
+        self.retryable_errors.push(Arc::new(move |error| {
+            error.downcast_ref::<E>().is_some_and(retry)
+        }));
+        self
+    }
+
+    pub(crate) fn is_retryable(&self, failure: &Failure) -> bool {
+        if self.retryable.contains(&failure.kind) {
+            return true;
+        }
+        failure.handler_error().is_some_and(|error| {
+            self.retryable_errors
+                .iter()
+                .any(|is_retryable| is_retryable(error))
+        })
+    }
+
+    /// Delay before retrying a message that failed its `attempt`-th attempt.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+// Three attempts, for panics, storage errors and full mailboxes which may be transient. A message
+// that could not be dispatched would fail the same way again.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2,
+            max_backoff: Duration::from_secs(60),
+            retryable: vec![
+                FailureKind::Panic,
+                FailureKind::Storage,
+                FailureKind::MailboxFull,
+            ],
+            fallback: Fallback::DeadLetter,
+            retryable_errors: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use crate::{
+        dead_letter::{Failure, FailureKind},
+        dyn_table::{DispatchError, HandlerError},
+    };
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 3,
+            max_backoff: Duration::from_secs(2),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[derive(Debug)]
+    enum Error {
+        Timeout,
+        Invalid,
+    }
+
+    fn handler_failure(error: Error) -> Failure {
+        DispatchError::Handler(HandlerError::new(error)).into()
+    }
+
+    #[test]
+    fn backoff_grows_by_the_multiplier() {
+        let policy = policy();
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+        assert_eq!(policy.backoff(3), Duration::from_millis(900));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = policy();
+        assert_eq!(policy.backoff(4), Duration::from_secs(2));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::MAX,
+            multiplier: u32::MAX,
+            max_backoff: Duration::MAX,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::MAX);
+        assert_eq!(policy.backoff(100), Duration::MAX);
+    }
+
+    #[test]
+    fn failures_are_retried_by_kind() {
+        let policy = policy();
+        assert!(policy.is_retryable(&Failure::new(FailureKind::Panic, "panicked")));
+        assert!(!policy.is_retryable(&Failure::new(FailureKind::Codec, "corrupt")));
+        assert!(!policy.is_retryable(&handler_failure(Error::Timeout)));
+    }
+
+    #[test]
+    fn handler_errors_are_retried_by_filter() {
+        let policy = policy().retry_handler_error(|error| matches!(error, Error::Timeout));
+        assert!(policy.is_retryable(&handler_failure(Error::Timeout)));
+        assert!(!policy.is_retryable(&handler_failure(Error::Invalid)));
+        let other = DispatchError::Handler(HandlerError::new("timeout")).into();
+        assert!(!policy.is_retryable(&other));
+    }
+}
@@ -1,22 +1,41 @@
 use std::{
     any::Any,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    ops::AddAssign,
     panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
     actor::PersistentActorId,
-    context::AnyContext,
-    database::Database,
+    context::{AnyContext, RunConfig},
+    database::{CommitError, Database, DbResult, PersistentValue, VALUE_CODEC},
+    dead_letter::{self, DeadLetter, DeadLetterId, Failure, FailureKind},
+    dedup::{self, DedupWindow, DEFAULT_DEDUP_WINDOW},
     dyn_table::{DispatchError, DispatchResult, DynTable},
-    global_storage::{GlobalStorageCache, StorageError},
+    envelope::TraceIds,
+    global_storage::{
+        CacheBudget, CacheStats, GlobalEffect, GlobalStorageCache, Namespace, StorageError,
+    },
+    quarantine::{ActorQuarantined, QuarantineCommand, DEFAULT_RERUN_LIMIT, QUARANTINE_TOPIC},
+    reminder::{self, ReminderQueue, StoredReminder},
+    retry::{Fallback, RetryPolicy},
+    scheduler::{Priority, Scheduler},
+    schema::{self, SchemaVersion},
+    sequence::{self, Order, ReceivedSequences},
+    timer::{self, Clock, SystemClock, Timer, TimerId, TimerQueue, Timestamp},
+    topic::TopicIndex,
 };
 
 use super::{
     actor::{AnyActorId, PersistentActor},
+    codec::Codec,
     context::Effects,
-    handler::Handler,
+    dispatcher::Callback,
+    handler::{AsyncHandler, Handler, Receives},
     log::{Log, LogIndex},
+    mailbox::{MailboxLimit, MailboxStats, Overflow},
     message::{AnyMessage, Message},
 };
 
@@ -28,9 +47,40 @@ where
     actor: Box<dyn Any + RefUnwindSafe>,
     cache: GlobalStorageCache,
     curr_log_index: L::LogIndex,
+    /// Message to handle instead of the one at `curr_log_index`, when a quarantined message is
+    /// retried or was fixed up.
+    head: Option<AnyMessage>,
+    /// Priority of the message at `curr_log_index` once it was read, so the scheduler does not
+    /// read the log again until the actor moves past it.
+    head_priority: Option<Priority>,
+    /// Why the actor is quarantined, it is not scheduled until an operator releases it.
+    quarantined: Option<Failure>,
+    /// Runs of the message at `curr_log_index` whose effects were rejected in a row, see
+    /// [`Runtime::set_rerun_limit`].
+    reruns: u32,
+    /// Where the sequence of messages from every sender is at.
+    received: ReceivedSequences,
+    stats: DeliveryStats,
+    mailbox: MailboxStats,
     log: L,
 }
 
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DeliveryStats {
+    /// Messages dropped because a message with the same idempotency key was already handled.
+    pub duplicates_dropped: u64,
+    /// Messages received after a gap in the sequence of messages from their sender.
+    pub sequence_gaps: u64,
+    /// Messages sent to the dead letters because a later message from their sender was already
+    /// received.
+    pub out_of_order: u64,
+    /// Messages dropped because their deadline passed before they were handled.
+    pub expired: u64,
+    /// Messages that could neither be delivered nor stored in the dead letters, e.g. because
+    /// they could not be encoded.
+    pub lost: u64,
+}
+
 pub struct Runtime<L>
 where
     L: Log,
@@ -38,8 +88,41 @@ where
     table: DynTable,
     actors: HashMap<AnyActorId, ActorData<L>>,
     next_id: u32,
-    // TODO: The same database is used across all actors, should we ensure isolation?
+    /// Actors only see their own keys unless they explicitly open a shared namespace,
+    /// see [`crate::global_storage::Namespace`].
     db: Database,
+    cache_budget: CacheBudget,
+    clock: Box<dyn Clock>,
+    /// Ids of the traces started by the runtime rather than by a message being handled.
+    traces: TraceIds,
+    /// Index of the timers stored in `db`, rebuilt when the runtime starts.
+    timers: TimerQueue,
+    next_timer: u64,
+    /// Index of the reminders stored in `db`, rebuilt when the runtime starts.
+    reminders: ReminderQueue,
+    /// When [`Runtime::run`] started, reminder firings due before it were missed.
+    started: Timestamp,
+    /// Index of the subscriptions stored in `db`, rebuilt when the runtime starts.
+    topics: TopicIndex,
+    /// Idempotency keys remembered per actor.
+    dedup_window: usize,
+    rerun_limit: u32,
+    /// Last sequence number sent between pairs of actors, see [`crate::log::LogEntry::seq`].
+    sequences: HashMap<(AnyActorId, AnyActorId), u64>,
+    scheduler: Scheduler,
+    mailbox_limits: HashMap<AnyActorId, MailboxLimit>,
+    /// Senders, with the actors whose full mailbox delays them, see [`Overflow::Delay`]. A
+    /// sender is not scheduled until all of them have room.
+    delayed: HashSet<(AnyActorId, AnyActorId)>,
+    /// Actors that stopped themselves, removed once their step is over.
+    stopping: Vec<AnyActorId>,
+    /// Messages lost on their way to an actor that is not in the runtime, see
+    /// [`DeliveryStats::lost`].
+    lost: u64,
+    /// Writes applied between a handler run and its commit, as if another runtime sharing the
+    /// database committed meanwhile.
+    #[cfg(test)]
+    interleaved_writes: Option<InterleavedWrites>,
 }
 
 impl<L> Runtime<L>
@@ -52,7 +135,125 @@ where
             actors: HashMap::new(),
             next_id: 0,
             db,
+            cache_budget: CacheBudget::default(),
+            clock: Box::new(SystemClock),
+            traces: TraceIds::new(SystemClock.now().as_millis()),
+            timers: TimerQueue::default(),
+            next_timer: 0,
+            reminders: ReminderQueue::default(),
+            started: Timestamp::from_millis(0),
+            topics: TopicIndex::default(),
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            rerun_limit: DEFAULT_RERUN_LIMIT,
+            sequences: HashMap::new(),
+            scheduler: Scheduler::new(),
+            mailbox_limits: HashMap::new(),
+            delayed: HashSet::new(),
+            stopping: Vec::new(),
+            lost: 0,
+            #[cfg(test)]
+            interleaved_writes: None,
+        }
+    }
+
+    /// Replaces the wall clock timers are driven by, e.g. with a [`crate::timer::VirtualClock`]
+    /// in simulations. Trace ids are seeded with the time of `clock`, so simulations starting at
+    /// the same time start the same traces.
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + 'static,
+    {
+        self.traces = TraceIds::new(clock.now().as_millis());
+        self.clock = Box::new(clock);
+    }
+
+    /// Seeds the ids of the traces the runtime starts, e.g. messages sent from `init` or by
+    /// reminders. By default they are seeded with the time of the runtime's clock.
+    pub fn set_trace_seed(&mut self, seed: u64) {
+        self.traces = TraceIds::new(seed);
+    }
+
+    pub fn set_cache_budget(&mut self, budget: CacheBudget) {
+        self.cache_budget = budget;
+    }
+
+    /// Cache statistics aggregated over every actor.
+    pub fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for actor_data in self.actors.values() {
+            stats += actor_data.cache.stats();
+        }
+        stats
+    }
+
+    pub fn actor_cache_stats(&self, actor_id: AnyActorId) -> Option<CacheStats> {
+        Some(self.actors.get(&actor_id)?.cache.stats())
+    }
+
+    /// How many of the last idempotency keys every actor remembers, older keys are forgotten and
+    /// a duplicate of their message is handled again.
+    pub fn set_dedup_window(&mut self, size: usize) {
+        self.dedup_window = size;
+    }
+
+    /// How many times in a row the effects of a message may be rejected, e.g. because they keep
+    /// conflicting with other writes, before its actor is quarantined with it. An actor whose
+    /// init keeps being rejected fails the run instead.
+    pub fn set_rerun_limit(&mut self, limit: u32) {
+        self.rerun_limit = limit;
+    }
+
+    /// Delivery statistics aggregated over every actor.
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        let mut stats = DeliveryStats {
+            lost: self.lost,
+            ..DeliveryStats::default()
+        };
+        for actor_data in self.actors.values() {
+            stats += actor_data.stats;
+        }
+        stats
+    }
+
+    pub fn actor_delivery_stats(&self, actor_id: AnyActorId) -> Option<DeliveryStats> {
+        Some(self.actors.get(&actor_id)?.stats)
+    }
+
+    /// Bounds the mailbox of `actor_id`, it is unbounded by default.
+    pub fn set_mailbox_limit(&mut self, actor_id: AnyActorId, limit: MailboxLimit) {
+        self.mailbox_limits.insert(actor_id, limit);
+    }
+
+    /// Mailbox statistics aggregated over every actor, the peak depth is the highest of any
+    /// actor.
+    pub fn mailbox_stats(&self) -> MailboxStats {
+        let mut stats = MailboxStats::default();
+        for actor_data in self.actors.values() {
+            stats += actor_data.mailbox;
         }
+        stats
+    }
+
+    pub fn actor_mailbox_stats(&self, actor_id: AnyActorId) -> Option<MailboxStats> {
+        Some(self.actors.get(&actor_id)?.mailbox)
+    }
+
+    /// Sets the share of the scheduler `actor_id` gets relative to other actors, 1 by default.
+    /// An actor of weight 2 handles twice as many messages as an actor of weight 1 when both
+    /// have messages of the same priority waiting.
+    pub fn set_actor_weight(&mut self, actor_id: AnyActorId, weight: u32) {
+        self.scheduler.set_weight(actor_id, weight);
+    }
+
+    /// How many messages every actor handles at most per scheduling turn, timers and reminders
+    /// fire in between turns.
+    pub fn set_turn_limit(&mut self, limit: u32) {
+        self.scheduler.set_turn_limit(limit);
+    }
+
+    /// Like [`Runtime::set_turn_limit`], only for `actor_id`.
+    pub fn set_actor_turn_limit(&mut self, actor_id: AnyActorId, limit: u32) {
+        self.scheduler.set_actor_turn_limit(actor_id, limit);
     }
 
     pub fn register_actor<A>(&mut self)
@@ -70,6 +271,68 @@ where
         self.table.register_handler::<A, M>();
     }
 
+    pub fn register_async_handler<A, M>(&mut self)
+    where
+        A: AsyncHandler<M>,
+        M: Message,
+    {
+        self.table.register_async_handler::<A, M>();
+    }
+
+    pub fn register_callback<A, M>(&mut self)
+    where
+        A: Callback<M>,
+        M: Message,
+    {
+        self.table.register_callback::<A, M>();
+    }
+
+    /// Retries messages `M` whose handler in actors `A` fails according to `policy`.
+    pub fn set_retry_policy<A, M>(&mut self, policy: RetryPolicy)
+    where
+        A: Receives<M>,
+        M: Message,
+    {
+        self.table.set_retry_policy::<A, M>(policy);
+    }
+
+    /// Sets the retry policy of every handler and callback of actors `A` without a policy of
+    /// their own.
+    pub fn set_actor_retry_policy<A>(&mut self, policy: RetryPolicy)
+    where
+        A: PersistentActor,
+    {
+        self.table.set_actor_retry_policy::<A>(policy);
+    }
+
+    /// Registers `upcast` to migrate messages `M` written at schema version `from` to the next
+    /// version.
+    pub fn register_message_upcaster<M, Old, New>(
+        &mut self,
+        from: SchemaVersion,
+        upcast: fn(Old) -> New,
+    ) where
+        M: Message,
+        Old: 'static + for<'a> Deserialize<'a>,
+        New: 'static + Serialize,
+    {
+        self.table.register_upcaster::<M, Old, New>(from, upcast);
+    }
+
+    /// Registers `upcast` to migrate values `V` stored at schema version `from` to the next
+    /// version.
+    pub fn register_value_upcaster<V, Old, New>(
+        &mut self,
+        from: SchemaVersion,
+        upcast: fn(Old) -> New,
+    ) where
+        V: PersistentValue,
+        Old: 'static + for<'a> Deserialize<'a>,
+        New: 'static + Serialize,
+    {
+        self.db.register_upcaster::<V, Old, New>(from, upcast);
+    }
+
     pub fn add_actor<A>(&mut self, actor: A, log: L) -> PersistentActorId<A>
     where
         A: PersistentActor,
@@ -82,88 +345,2666 @@ where
         id
     }
 
-    pub fn run(mut self) {
-        for actor in self.actors.values_mut() {
-            let result = actor.run_init(&self.table, &mut self.db);
-            // TODO: apply_effects
+    /// Messages that could not be delivered or handled, oldest first. Fails if any of them
+    /// cannot be read, it can still be discarded by id.
+    pub fn dead_letters(&self) -> DbResult<Vec<DeadLetter>> {
+        dead_letter::load(&self.db)
+    }
+
+    pub fn dead_letter(&self, id: DeadLetterId) -> DbResult<Option<DeadLetter>> {
+        self.db.get_resource(&id.key())
+    }
+
+    /// Appends a dead letter back to the log of its recipient, with its original sender, and
+    /// removes it from the dead letters. Returns `false` if there is no such dead letter or its
+    /// recipient is not in the runtime.
+    pub async fn replay_dead_letter(&mut self, id: DeadLetterId) -> RunResult<bool, L> {
+        let dead_letter = self
+            .dead_letter(id)
+            .map_err(|_| RunError::storage(id.key(), "dead letter could not be read"))?;
+        let Some(dead_letter) = dead_letter else {
+            return Ok(false);
+        };
+        if !self.actors.contains_key(&dead_letter.to) {
+            return Ok(false);
+        }
+        // The dead letter is only removed once its message is back in the log, if that fails
+        // the message is replayed twice rather than lost
+        let (from, to) = (dead_letter.from, dead_letter.to);
+        self.deliver(from, to, dead_letter.message()).await?;
+        if !self.discard_dead_letter(id) {
+            return Err(RunError::storage(
+                id.key(),
+                "dead letter could not be removed",
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Drops a dead letter for good, even one that cannot be read. Returns `false` if there is
+    /// no such dead letter.
+    pub fn discard_dead_letter(&mut self, id: DeadLetterId) -> bool {
+        let key = id.key();
+        if !self.db.contains_key(&key) {
+            return false;
+        }
+        let version = self.db.version(&key);
+        let reads = HashMap::from([(key.clone(), version)]);
+        let effects = HashMap::from([(key, GlobalEffect::Deleted)]);
+        self.db.commit(&reads, &HashMap::new(), effects).is_ok()
+    }
+
+    /// Why `actor_id` is quarantined, `None` if it is not.
+    pub fn quarantined(&self, actor_id: AnyActorId) -> Option<&Failure> {
+        self.actors.get(&actor_id)?.quarantined.as_ref()
+    }
+
+    /// Like [`crate::context::Context::release_actor`], for operators outside of the runtime. Returns `false`
+    /// if `actor_id` is not quarantined.
+    pub async fn release_actor(&mut self, actor_id: AnyActorId) -> RunResult<bool, L> {
+        self.apply_quarantine_command(QuarantineCommand::Release(actor_id))
+            .await
+    }
+
+    /// Like [`crate::context::Context::skip_quarantined_message`], for operators outside of the runtime.
+    /// Returns `false` if `actor_id` is not quarantined.
+    pub async fn skip_quarantined_message(&mut self, actor_id: AnyActorId) -> RunResult<bool, L> {
+        self.apply_quarantine_command(QuarantineCommand::Skip(actor_id))
+            .await
+    }
+
+    /// Like [`crate::context::Context::fix_quarantined_message`], for operators outside of the runtime.
+    /// Returns `false` if `actor_id` is not quarantined.
+    pub async fn fix_quarantined_message<M>(
+        &mut self,
+        actor_id: AnyActorId,
+        message: M,
+    ) -> RunResult<bool, L>
+    where
+        M: Message,
+    {
+        // The message replaces the head of the log without being appended to it, it is only
+        // encoded if it fails again
+        let mut message = AnyMessage::local(message, L::Codec::ID);
+        message.envelope.trace_id = self.traces.next();
+        message.envelope.created_at = self.clock.now();
+        let command = QuarantineCommand::Fix(actor_id, Box::new(message));
+        self.apply_quarantine_command(command).await
+    }
+
+    /// Runs the actors until none of them has a message left and no timer or reminder is
+    /// pending. The runtime can then be inspected, e.g. to replay its dead letters, and run
+    /// again, its actors are initialized again as after a restart.
+    pub async fn run(&mut self) -> RunResult<(), L> {
+        self.started = self.clock.now();
+        self.reminders = ReminderQueue::load(&self.db)
+            .map_err(|key| RunError::storage(key, "reminder could not be read"))?;
+        self.topics = TopicIndex::load(&self.db)
+            .map_err(|key| RunError::storage(key, "subscription could not be read"))?;
+        self.timers = TimerQueue::load(&self.db)
+            .map_err(|key| RunError::storage(key, "timer could not be read"))?;
+        // Starting over from 0 would overwrite the timers already stored
+        let next_timer = self
+            .db
+            .get_resource::<i64>(timer::NEXT_TIMER_KEY)
+            .map_err(|_| RunError::storage(timer::NEXT_TIMER_KEY, "could not be read"))?;
+        self.next_timer = next_timer.unwrap_or(0) as u64;
+
+        let mut actor_ids: Vec<AnyActorId> = self.actors.keys().copied().collect();
+        // Actors are stepped in a fixed order so runs are reproducible
+        actor_ids.sort();
+        for &actor_id in &actor_ids {
+            self.init_actor(actor_id).await?;
+        }
+        self.stop_actors().await?;
+
+        // TODO: all this actors should run in parallel. Effects are committed optimistically,
+        // so concurrent handlers touching the same keys would be detected and retried.
+        loop {
+            let mut done = !self.fire_timers().await?;
+            if self.fire_reminders().await? {
+                done = false;
+            }
+            actor_ids.retain(|actor_id| self.actors.contains_key(actor_id));
+            if self.run_turn(&actor_ids).await? {
+                done = false;
+            }
+            if done {
+                let next_due = [self.timers.next_due(), self.reminders.next_due()];
+                match next_due.into_iter().flatten().min() {
+                    Some(due) => self.clock.sleep_until(due).await,
+                    None => return Ok(()),
+                }
+            }
         }
+    }
 
-        // TODO: all this actors should run in parallel, that would require
-        // transactionallity support in the database
+    /// Lets the actors handle their messages until they are all done or reached their turn
+    /// limit, in the order the [`Scheduler`] picks. Returns whether any message was handled.
+    async fn run_turn(&mut self, actor_ids: &[AnyActorId]) -> RunResult<bool, L> {
+        self.scheduler.start_turn();
+        let mut progress = false;
         loop {
-            let mut done = true;
-            for actor in self.actors.values_mut() {}
+            let mut ready = Vec::new();
+            for &actor_id in actor_ids {
+                if !self.actors.contains_key(&actor_id) || !self.scheduler.can_run(actor_id) {
+                    continue;
+                }
+                if let Some(priority) = self.next_priority(actor_id).await? {
+                    ready.push((actor_id, priority));
+                }
+            }
+            let Some(actor_id) = self.scheduler.pick(&ready) else {
+                return Ok(progress);
+            };
+            if self.do_step(actor_id).await? {
+                progress = true;
+            }
+            self.stop_actors().await?;
+        }
+    }
+
+    /// Removes the actors that stopped themselves, along with the state of their pending
+    /// callbacks, gathers and asks, the timers expiring them, their reminders, subscriptions,
+    /// deduplication window and sequence numbers. The messages left in their logs go to the dead
+    /// letters.
+    async fn stop_actors(&mut self) -> RunResult<(), L> {
+        for actor_id in std::mem::take(&mut self.stopping) {
+            let Some(mut actor_data) = self.actors.remove(&actor_id) else {
+                continue;
+            };
+            while let Some(entry) = actor_data
+                .log
+                .read(actor_id, actor_data.curr_log_index)
+                .await
+                .map_err(RunError::Log)?
+            {
+                let message = actor_data.head.take().unwrap_or(entry.message);
+                actor_data.advance(entry.next_idx);
+                let failure = Failure::new(
+                    FailureKind::ActorNotFound,
+                    format!("{} was stopped", actor_id),
+                );
+                let attempt = message.envelope.attempt;
+                self.dead_letter_or_lose(entry.sender_id, actor_id, &message, failure, attempt);
+            }
+            self.delayed
+                .retain(|&(from, to)| from != actor_id && to != actor_id);
+            self.scheduler.remove(actor_id);
+            self.mailbox_limits.remove(&actor_id);
+            self.sequences
+                .retain(|&(from, to), _| from != actor_id && to != actor_id);
+            let mut effects = HashMap::new();
+            let prefix = Namespace::Runtime(actor_id).backend_key("");
+            for key in self.db.keys_with_prefix(&prefix) {
+                effects.insert(key.to_string(), GlobalEffect::Deleted);
+            }
+            for id in TimerQueue::timeouts_of(&self.db, actor_id) {
+                self.timers.remove(&id);
+                effects.insert(id.key(), GlobalEffect::Deleted);
+            }
+            for key in self
+                .db
+                .keys_with_prefix(&reminder::reminders_prefix(actor_id))
+            {
+                self.reminders.remove(&key.to_string());
+                effects.insert(key.to_string(), GlobalEffect::Deleted);
+            }
+            for subscription in TopicIndex::subscriptions_of(&self.db, actor_id) {
+                effects.insert(subscription.key(), GlobalEffect::Deleted);
+                self.topics.remove(&subscription);
+            }
+            for key in sequence::keys_of(&self.db, actor_id) {
+                effects.insert(key, GlobalEffect::Deleted);
+            }
+            let window_key = dedup::window_key(actor_id);
+            if self.db.contains_key(&window_key) {
+                effects.insert(window_key, GlobalEffect::Deleted);
+            }
+            if self
+                .db
+                .commit(&HashMap::new(), &HashMap::new(), effects)
+                .is_err()
+            {
+                let description = "state of the stopped actor could not be removed";
+                return Err(RunError::storage(prefix, description));
+            }
         }
+        Ok(())
     }
 
-    async fn do_step(&mut self, log: &mut L, actor_id: AnyActorId) -> Result<bool, L::Error> {
+    /// Priority of the next message `actor_id` has to handle, `None` if it has none or cannot
+    /// run.
+    async fn next_priority(&mut self, actor_id: AnyActorId) -> RunResult<Option<Priority>, L> {
+        let delaying: Vec<AnyActorId> = self
+            .delayed
+            .iter()
+            .filter(|&&(from, _)| from == actor_id)
+            .map(|&(_, to)| to)
+            .collect();
+        for to in delaying {
+            if self.is_mailbox_full(to) {
+                return Ok(None);
+            }
+            self.delayed.remove(&(actor_id, to));
+        }
         let actor_data = self.actors.get_mut(&actor_id).unwrap();
-        let entry = log.read(actor_id, actor_data.curr_log_index).await?;
-        match entry {
-            Some(entry) => {
-                actor_data.curr_log_index = entry.next_idx;
-                Ok(true)
+        if actor_data.quarantined.is_some() {
+            return Ok(None);
+        }
+        if let Some(message) = &actor_data.head {
+            return Ok(Some(message.envelope.priority));
+        }
+        if actor_data.head_priority.is_none() {
+            let entry = actor_data
+                .log
+                .read(actor_id, actor_data.curr_log_index)
+                .await
+                .map_err(RunError::Log)?;
+            actor_data.head_priority = entry.map(|entry| entry.message.envelope.priority);
+        }
+        Ok(actor_data.head_priority)
+    }
+
+    /// Settings of a run, starting a new trace the run keeps unless it handles a message.
+    fn run_config(&mut self) -> RunConfig {
+        RunConfig {
+            codec: L::Codec::ID,
+            local_delivery: !L::DURABLE,
+            now: self.clock.now(),
+            next_timer: self.next_timer,
+            trace_id: self.traces.next(),
+            mailbox_room: self.mailbox_room(),
+        }
+    }
+
+    /// See [`RunConfig::mailbox_room`].
+    fn mailbox_room(&self) -> HashMap<AnyActorId, u64> {
+        self.mailbox_limits
+            .iter()
+            .filter(|(_, limit)| limit.overflow == Overflow::Reject)
+            .filter_map(|(actor_id, limit)| {
+                let depth = self.actors.get(actor_id)?.mailbox.depth;
+                Some((*actor_id, limit.capacity.saturating_sub(depth)))
+            })
+            .collect()
+    }
+
+    /// Delivers the reminders that are due and schedules their next firing. Returns whether any
+    /// reminder was delivered.
+    async fn fire_reminders(&mut self) -> RunResult<bool, L> {
+        let now = self.clock.now();
+        let mut fired = false;
+        while let Some(key) = self.reminders.pop_due(now) {
+            // A reminder that cannot be read or stored again is left as it is for a later run,
+            // rather than being lost
+            let (version, mut reminder) = match self.db.get_versioned::<StoredReminder>(&key) {
+                Ok((version, Some(reminder))) => (version, reminder),
+                Ok((_, None)) => continue,
+                Err(_) => return Err(RunError::storage(key, "reminder could not be read")),
+            };
+            let reminders = reminder.fire(now, self.started);
+            let effect = match reminder.next {
+                Some(_) => match schema::encode(VALUE_CODEC, StoredReminder::VERSION, &reminder) {
+                    Ok(bytes) => GlobalEffect::Modified(bytes),
+                    Err(_) => return Err(RunError::storage(key, "reminder could not be encoded")),
+                },
+                None => GlobalEffect::Deleted,
+            };
+            let reads = HashMap::from([(key.clone(), version)]);
+            let effects = HashMap::from([(key.clone(), effect)]);
+            // It changed since it was read, its new schedule is already in the queue
+            if self.db.commit(&reads, &HashMap::new(), effects).is_err() {
+                continue;
+            }
+            if let Some(next) = reminder.next {
+                self.reminders.insert(key, next);
+            }
+            // TODO: the update of the reminder and the appends should happen atomically
+            let actor_id = reminder.actor_id;
+            for message in reminders {
+                let mut message = match AnyMessage::encode(&message, L::Codec::ID) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        let message = AnyMessage::local(message, L::Codec::ID);
+                        self.dead_letter_or_lose(actor_id, actor_id, &message, err.into(), 0);
+                        continue;
+                    }
+                };
+                message.envelope.trace_id = self.traces.next();
+                message.envelope.created_at = self.clock.now();
+                self.deliver(actor_id, actor_id, message).await?;
+                fired = true;
             }
-            None => Ok(false),
         }
+        Ok(fired)
     }
-}
 
-enum RuntimeError {
-    StorageError(StorageError),
-    Dispatch(DispatchError),
-    Other(Box<dyn Any>),
-}
+    /// Appends the messages of the timers that are due to the logs of their recipients. Returns
+    /// whether any timer fired.
+    async fn fire_timers(&mut self) -> RunResult<bool, L> {
+        let now = self.clock.now();
+        let mut fired = false;
+        while let Some(id) = self.timers.pop_due(now) {
+            let key = id.key();
+            // A timer that cannot be read is left as it is for a later run, rather than lost
+            let (version, timer) = match self.db.get_versioned::<Timer>(&key) {
+                Ok((version, Some(timer))) => (version, timer),
+                Ok((_, None)) => continue,
+                Err(_) => return Err(RunError::storage(key, "timer could not be read")),
+            };
+            // The timer is deleted before its message is delivered so it fires at most once
+            let reads = HashMap::from([(key.clone(), version)]);
+            let effects = HashMap::from([(key, GlobalEffect::Deleted)]);
+            // It changed since it was read, its new due time is already in the queue
+            if self.db.commit(&reads, &HashMap::new(), effects).is_err() {
+                continue;
+            }
+            // TODO: the deletion and the append should happen atomically
+            let (from, to) = (timer.from, timer.to);
+            self.deliver(from, to, timer.message()).await?;
+            fired = true;
+        }
+        Ok(fired)
+    }
 
-impl<L> ActorData<L>
-where
-    L: Log,
-{
-    fn new(id: AnyActorId, actor: Box<dyn Any + RefUnwindSafe>, log: L) -> Self {
-        Self {
-            id,
-            actor,
-            log,
-            curr_log_index: L::LogIndex::ZERO,
-            cache: GlobalStorageCache::new(),
+    async fn init_actor(&mut self, actor_id: AnyActorId) -> RunResult<(), L> {
+        for _ in 0..self.rerun_limit {
+            let config = self.run_config();
+            let actor_data = self.actors.get_mut(&actor_id).unwrap();
+            let effects = match actor_data.run_init(&self.table, &self.db, config) {
+                Ok(effects) => effects,
+                Err(err) => {
+                    actor_data.cache.rollback();
+                    let failure = err.into_failure();
+                    return Err(RunError::Init { actor_id, failure });
+                }
+            };
+            match self.commit(actor_id, effects, None).await? {
+                Ok(()) => return Ok(()),
+                Err(CommitError::Conflict { .. } | CommitError::Guard { .. }) => continue,
+                Err(CommitError::Value { key }) => {
+                    let description = format!("invalid commutative effect on `{}`", key);
+                    let failure = Failure::new(FailureKind::Storage, description);
+                    return Err(RunError::Init { actor_id, failure });
+                }
+            }
         }
+        let description = format!("effects were rejected {} times in a row", self.rerun_limit);
+        let failure = Failure::new(FailureKind::Storage, description);
+        Err(RunError::Init { actor_id, failure })
     }
 
-    fn run_init(&mut self, table: &DynTable, db: &Database) -> Result<Effects, RuntimeError> {
-        let mut cx = AnyContext::new(self.id, db, &mut self.cache);
-        catch_unwind_and_dispatch_errors(AssertUnwindSafe(|| {
-            table.dispatch_init(self.id.name, &self.actor, cx)
-        }))
+    async fn do_step(&mut self, actor_id: AnyActorId) -> RunResult<bool, L> {
+        loop {
+            let config = self.run_config();
+            let actor_data = self.actors.get_mut(&actor_id).unwrap();
+            if actor_data.quarantined.is_some() {
+                return Ok(false);
+            }
+            // The entry is read again on every attempt, so the handler can take ownership of
+            // the message
+            let entry = match actor_data
+                .log
+                .read(actor_id, actor_data.curr_log_index)
+                .await
+                .map_err(RunError::Log)?
+            {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            let message = match &actor_data.head {
+                Some(message) => message.clone(),
+                None => entry.message,
+            };
+            match actor_data.received.receive(entry.sender_id, entry.seq) {
+                Order::Expected => {}
+                // TODO: report the gap, the messages may come later but would be out of order
+                Order::Gap => actor_data.stats.sequence_gaps += 1,
+                Order::Late => {
+                    actor_data.stats.out_of_order += 1;
+                    let failure = Failure::new(
+                        FailureKind::OutOfOrder,
+                        format!(
+                            "a later message from {} was already received",
+                            entry.sender_id
+                        ),
+                    );
+                    self.dead_letter_head(
+                        actor_id,
+                        entry.sender_id,
+                        entry.next_idx,
+                        message,
+                        failure,
+                    )
+                    .await?;
+                    return Ok(true);
+                }
+            }
+            if message.envelope.is_expired(config.now) {
+                actor_data.advance(entry.next_idx);
+                actor_data.stats.expired += 1;
+                return Ok(true);
+            }
+            let dedup = match &message.envelope.idempotency_key {
+                Some(key) => {
+                    let window_key = dedup::window_key(actor_id);
+                    // Handling the message without its window could handle it twice
+                    let Ok((version, window)) = self.db.get_versioned::<DedupWindow>(&window_key)
+                    else {
+                        let failure = Failure::new(
+                            FailureKind::Storage,
+                            "deduplication window could not be read",
+                        );
+                        self.fail_head(actor_id, failure).await?;
+                        return Ok(true);
+                    };
+                    let mut window = window.unwrap_or_default();
+                    if window.contains(key) {
+                        actor_data.advance(entry.next_idx);
+                        actor_data.stats.duplicates_dropped += 1;
+                        return Ok(true);
+                    }
+                    window.insert(key.clone(), self.dedup_window);
+                    let Ok(bytes) = schema::encode(VALUE_CODEC, DedupWindow::VERSION, &window)
+                    else {
+                        let failure = Failure::new(
+                            FailureKind::Storage,
+                            "deduplication window could not be encoded",
+                        );
+                        self.fail_head(actor_id, failure).await?;
+                        return Ok(true);
+                    };
+                    Some((window_key, version, bytes))
+                }
+                None => None,
+            };
+            let mut effects = match actor_data.run_handler(
+                &self.table,
+                entry.sender_id,
+                message,
+                &self.db,
+                config,
+            ) {
+                Ok(effects) => effects,
+                Err(err) => {
+                    actor_data.cache.rollback();
+                    self.fail_head(actor_id, err.into_failure()).await?;
+                    return Ok(true);
+                }
+            };
+            // The key is committed with the effects of the handler, so the message counts as
+            // handled if and only if they are
+            if let Some((window_key, version, bytes)) = dedup {
+                effects.reads.insert(window_key.clone(), version);
+                effects
+                    .global_effects
+                    .insert(window_key, GlobalEffect::Modified(bytes));
+            }
+            #[cfg(test)]
+            if let Some(write) = &mut self.interleaved_writes {
+                write(&mut self.db);
+            }
+            match self.commit(actor_id, effects, Some(entry.next_idx)).await? {
+                Ok(()) => return Ok(true),
+                // The handler is re-run, a failed guard is now visible to it as a version
+                // mismatch. An actor that cannot get past the message is quarantined with it,
+                // whatever its retry policy
+                Err(CommitError::Conflict { .. } | CommitError::Guard { .. }) => {
+                    let actor_data = self.actors.get_mut(&actor_id).unwrap();
+                    actor_data.reruns += 1;
+                    if actor_data.reruns >= self.rerun_limit {
+                        let failure = Failure::new(
+                            FailureKind::Storage,
+                            format!("effects were rejected {} times in a row", actor_data.reruns),
+                        );
+                        self.quarantine_head(actor_id, failure).await?;
+                        return Ok(true);
+                    }
+                }
+                Err(CommitError::Value { key }) => {
+                    let description = format!("invalid commutative effect on `{}`", key);
+                    let failure = Failure::new(FailureKind::Storage, description);
+                    self.fail_head(actor_id, failure).await?;
+                    return Ok(true);
+                }
+            }
+        }
     }
 
-    fn run_handler(
+    /// Commits the effects of a run of `actor_id`. If the database rejects them nothing is
+    /// applied, on a [`CommitError::Conflict`] the run must be retried. Otherwise the actor moves
+    /// to `next_idx` if the run handled a message, before the messages it sent are delivered, so
+    /// a message it sent itself never finds the handled one in its mailbox.
+    async fn commit(
         &mut self,
-        table: &DynTable,
+        actor_id: AnyActorId,
+        mut effects: Effects,
+        next_idx: Option<L::LogIndex>,
+    ) -> RunResult<Result<(), CommitError>, L> {
+        // The sequence numbers of the messages sent are committed with the effects, and taken
+        // back if they are rejected
+        let sends = self.sends(&mut effects);
+        let mut reserved = HashMap::new();
+        let mut seqs = Vec::with_capacity(sends.len());
+        for (to, _) in &sends {
+            if !self.actors.contains_key(to) {
+                seqs.push(None);
+                continue;
+            }
+            reserved
+                .entry(*to)
+                .or_insert_with(|| self.sequences.get(&(actor_id, *to)).copied());
+            match self.reserve_sequence(actor_id, *to) {
+                Ok((key, seq)) => {
+                    effects
+                        .global_effects
+                        .insert(key, GlobalEffect::MaxRegister(seq as i64));
+                    seqs.push(Some(seq));
+                }
+                // Delivering it fails the same way, and dead-letters it
+                Err(_) => seqs.push(None),
+            }
+        }
+        let actor_data = self.actors.get_mut(&actor_id).unwrap();
+        if let Err(err) = self
+            .db
+            .commit(&effects.reads, &effects.guards, effects.global_effects)
+        {
+            actor_data.cache.rollback();
+            for (to, last) in reserved {
+                match last {
+                    Some(last) => self.sequences.insert((actor_id, to), last),
+                    None => self.sequences.remove(&(actor_id, to)),
+                };
+            }
+            return Ok(Err(err));
+        }
+        actor_data.cache.commit();
+        if let Some(next_idx) = next_idx {
+            actor_data.advance(next_idx);
+        }
+        self.enforce_cache_budget(actor_id);
+        self.next_timer = self.next_timer.max(effects.next_timer);
+        for (id, due) in effects.timers {
+            self.timers.insert(id, due);
+        }
+        for id in effects.cancelled_timers {
+            self.timers.remove(&id);
+        }
+        for (key, next) in effects.reminders {
+            match next {
+                Some(next) => self.reminders.insert(key, next),
+                None => self.reminders.remove(&key),
+            }
+        }
+        for (subscription, subscribed) in effects.subscriptions {
+            match subscribed {
+                true => self.topics.insert(subscription),
+                false => self.topics.remove(&subscription),
+            }
+        }
+        for to in effects.rejected {
+            if let Some(recipient) = self.actors.get_mut(&to) {
+                recipient.mailbox.rejected += 1;
+            }
+        }
+        // TODO: the database commit and the appends to the logs should happen atomically
+        for ((to, message), seq) in sends.into_iter().zip(seqs) {
+            self.deliver_sequenced(actor_id, to, message, seq).await?;
+        }
+        for command in effects.quarantine {
+            self.apply_quarantine_command(command).await?;
+        }
+        if effects.stop {
+            self.stopping.push(actor_id);
+        }
+        Ok(Ok(()))
+    }
+
+    /// Takes the messages sent and published by a run out of `effects`, in the order they are
+    /// delivered. Published messages go to the subscribers of their topic once the
+    /// subscriptions made and dropped by the run apply.
+    fn sends(&self, effects: &mut Effects) -> Vec<(AnyActorId, AnyMessage)> {
+        let mut sends = std::mem::take(&mut effects.messages);
+        for (topic, message) in std::mem::take(&mut effects.published) {
+            let mut subscribers: Vec<AnyActorId> =
+                self.topics.subscribers(&topic, message.name).collect();
+            for (subscription, subscribed) in &effects.subscriptions {
+                if subscription.topic == topic && subscription.message == message.name {
+                    subscribers.retain(|actor_id| *actor_id != subscription.actor_id);
+                    if *subscribed {
+                        subscribers.push(subscription.actor_id);
+                    }
+                }
+            }
+            sends.extend(subscribers.into_iter().map(|to| (to, message.clone())));
+        }
+        sends
+    }
+
+    async fn publish(
+        &mut self,
+        from: AnyActorId,
+        topic: &str,
         message: AnyMessage,
-        db: &Database,
-    ) -> Result<Effects, RuntimeError>
-    where
-        L: Log,
-    {
-        let mut cx = AnyContext::new(self.id, db, &mut self.cache);
-        catch_unwind_and_dispatch_errors(AssertUnwindSafe(|| {
-            table.dispatch_handler(self.id.name, &self.actor, cx, message)
-        }))
+    ) -> RunResult<(), L> {
+        let subscribers: Vec<AnyActorId> = self.topics.subscribers(topic, message.name).collect();
+        for to in subscribers {
+            self.deliver(from, to, message.clone()).await?;
+        }
+        Ok(())
     }
-}
 
-fn catch_unwind_and_dispatch_errors<T, F>(f: F) -> Result<T, RuntimeError>
-where
-    F: FnOnce() -> DispatchResult<T> + UnwindSafe,
-{
-    match std::panic::catch_unwind(f) {
-        Ok(result) => result.map_err(RuntimeError::Dispatch),
-        Err(err) => {
-            let err = err
-                .downcast::<StorageError>()
-                .map(|err| RuntimeError::StorageError(*err))
-                .unwrap_or_else(|err| RuntimeError::Other(err));
-            Err(err)
+    /// Applies an operator decision about a quarantined actor. Returns `false` if `actor_id` is
+    /// not quarantined.
+    async fn apply_quarantine_command(&mut self, command: QuarantineCommand) -> RunResult<bool, L> {
+        let actor_id = match &command {
+            QuarantineCommand::Release(actor_id)
+            | QuarantineCommand::Skip(actor_id)
+            | QuarantineCommand::Fix(actor_id, _) => *actor_id,
+        };
+        let Some(actor_data) = self.actors.get_mut(&actor_id) else {
+            return Ok(false);
+        };
+        let Some(failure) = actor_data.quarantined.take() else {
+            return Ok(false);
+        };
+        actor_data.reruns = 0;
+        match command {
+            QuarantineCommand::Release(_) => {
+                if let Some(message) = &mut actor_data.head {
+                    message.envelope.attempt += 1;
+                }
+            }
+            QuarantineCommand::Skip(_) => {
+                let Some(entry) = actor_data
+                    .log
+                    .read(actor_id, actor_data.curr_log_index)
+                    .await
+                    .map_err(RunError::Log)?
+                else {
+                    return Ok(true);
+                };
+                let message = actor_data.head.take().unwrap_or(entry.message);
+                let from = entry.sender_id;
+                self.dead_letter_head(actor_id, from, entry.next_idx, message, failure)
+                    .await?;
+            }
+            QuarantineCommand::Fix(_, message) => actor_data.head = Some(*message),
+        }
+        Ok(true)
+    }
+
+    /// Appends `message` to the log of `to`, or to the dead letters if there is no such actor.
+    /// If the mailbox of `to` is full its [`Overflow`] applies.
+    async fn deliver(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: AnyMessage,
+    ) -> RunResult<(), L> {
+        self.deliver_sequenced(from, to, message, None).await
+    }
+
+    /// Delivers `message` like [`Runtime::deliver`] with its sequence number `seq`, if one was
+    /// already reserved and committed for it.
+    async fn deliver_sequenced(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: AnyMessage,
+        seq: Option<u64>,
+    ) -> RunResult<(), L> {
+        if !self.actors.contains_key(&to) {
+            let failure = Failure::new(FailureKind::ActorNotFound, format!("no actor {}", to));
+            self.dead_letter_or_lose(from, to, &message, failure, 0);
+            return Ok(());
+        }
+        if self.is_mailbox_full(to) {
+            match self.mailbox_limits[&to].overflow {
+                Overflow::Reject => {
+                    self.actors.get_mut(&to).unwrap().mailbox.rejected += 1;
+                    let failure = Failure::new(FailureKind::MailboxFull, format!("{} is full", to));
+                    self.dead_letter_or_lose(from, to, &message, failure, 0);
+                    return Ok(());
+                }
+                Overflow::DropOldest => {
+                    let recipient = self.actors.get_mut(&to).unwrap();
+                    // The head of a quarantined actor, or one an operator released or fixed, is
+                    // waiting for the actor and is never dropped
+                    if recipient.quarantined.is_some() || recipient.head.is_some() {
+                        recipient.mailbox.rejected += 1;
+                        let description = format!("{} is full and its head is held", to);
+                        let failure = Failure::new(FailureKind::MailboxFull, description);
+                        self.dead_letter_or_lose(from, to, &message, failure, 0);
+                        return Ok(());
+                    }
+                    if let Some(entry) = recipient
+                        .log
+                        .read(to, recipient.curr_log_index)
+                        .await
+                        .map_err(RunError::Log)?
+                    {
+                        recipient.advance(entry.next_idx);
+                        recipient.mailbox.dropped += 1;
+                    }
+                }
+                Overflow::Delay => {
+                    if from != to && self.actors.contains_key(&from) {
+                        self.delayed.insert((from, to));
+                        self.actors.get_mut(&to).unwrap().mailbox.delayed += 1;
+                    }
+                }
+            }
+        }
+        let seq = match seq {
+            Some(seq) => seq,
+            None => match self.next_sequence(from, to) {
+                Ok(seq) => seq,
+                Err(failure) => {
+                    self.dead_letter_or_lose(from, to, &message, failure, 0);
+                    return Ok(());
+                }
+            },
+        };
+        let recipient = self.actors.get_mut(&to).unwrap();
+        recipient
+            .log
+            .append(from, to, seq, message)
+            .await
+            .map_err(RunError::Log)?;
+        recipient.mailbox.push();
+        Ok(())
+    }
+
+    fn is_mailbox_full(&self, actor_id: AnyActorId) -> bool {
+        match (
+            self.mailbox_limits.get(&actor_id),
+            self.actors.get(&actor_id),
+        ) {
+            (Some(limit), Some(actor_data)) => limit.is_full(&actor_data.mailbox),
+            _ => false,
+        }
+    }
+
+    /// Sequence number of the next message from `from` to `to`. The last one is stored so
+    /// sequences keep growing across restarts, the message cannot be sent if it is not.
+    fn next_sequence(&mut self, from: AnyActorId, to: AnyActorId) -> Result<u64, Failure> {
+        let last = self.sequences.get(&(from, to)).copied();
+        let (key, seq) = self.reserve_sequence(from, to)?;
+        // A max register, so it never conflicts
+        let effects = HashMap::from([(key, GlobalEffect::MaxRegister(seq as i64))]);
+        if self
+            .db
+            .commit(&HashMap::new(), &HashMap::new(), effects)
+            .is_err()
+        {
+            match last {
+                Some(last) => self.sequences.insert((from, to), last),
+                None => self.sequences.remove(&(from, to)),
+            };
+            let description = "sequence number could not be stored";
+            return Err(Failure::new(FailureKind::Storage, description));
+        }
+        Ok(seq)
+    }
+
+    /// Takes the next sequence number from `from` to `to` without storing it, returning it with
+    /// the key it must be stored under before the message is appended.
+    fn reserve_sequence(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+    ) -> Result<(String, u64), Failure> {
+        let key = sequence::sequence_key(from, to);
+        let last = match self.sequences.get(&(from, to)) {
+            Some(last) => *last,
+            None => {
+                let last = self.db.get_resource::<i64>(&key).map_err(|_| {
+                    Failure::new(FailureKind::Storage, "sequence number could not be read")
+                })?;
+                last.unwrap_or(0) as u64
+            }
+        };
+        let seq = last + 1;
+        self.sequences.insert((from, to), seq);
+        Ok((key, seq))
+    }
+
+    /// Takes the message at the head of the log of `actor_id` out of the way after its handler
+    /// failed, so the actor moves on to the next one. The message is retried later if the
+    /// [`RetryPolicy`] of the handler allows it, otherwise it goes to the policy's fallback, or to
+    /// the dead letters if there is no policy.
+    async fn fail_head(&mut self, actor_id: AnyActorId, mut failure: Failure) -> RunResult<(), L> {
+        let actor_data = self.actors.get_mut(&actor_id).unwrap();
+        // The handler took ownership of the message, it is read again
+        let Some(entry) = actor_data
+            .log
+            .read(actor_id, actor_data.curr_log_index)
+            .await
+            .map_err(RunError::Log)?
+        else {
+            return Ok(());
+        };
+        let from = entry.sender_id;
+        let mut message = actor_data.head.take().unwrap_or(entry.message);
+        let attempt = message.envelope.attempt;
+        let Some(policy) = self
+            .table
+            .retry_policy(actor_id.name, message.name)
+            .cloned()
+        else {
+            return self
+                .dead_letter_head(actor_id, from, entry.next_idx, message, failure)
+                .await;
+        };
+        if attempt < policy.max_attempts && policy.is_retryable(&failure) {
+            let due = self.clock.now() + policy.backoff(attempt);
+            let mut retry = message.clone();
+            retry.envelope.attempt += 1;
+            match self.schedule_retry(from, actor_id, &retry, due) {
+                Ok(()) => {
+                    self.actors
+                        .get_mut(&actor_id)
+                        .unwrap()
+                        .advance(entry.next_idx);
+                    return Ok(());
+                }
+                // The message goes to the fallback, which is told why it was not retried
+                Err(err) => {
+                    failure.description = format!(
+                        "{}, the retry could not be scheduled: {}",
+                        failure.description, err.description
+                    );
+                }
+            }
+        }
+        match policy.fallback {
+            Fallback::Quarantine => return self.quarantine(actor_id, from, message, failure).await,
+            Fallback::DeadLetter => {
+                return self
+                    .dead_letter_head(actor_id, from, entry.next_idx, message, failure)
+                    .await;
+            }
+            Fallback::Drop | Fallback::Forward(_) => {}
+        }
+        self.actors
+            .get_mut(&actor_id)
+            .unwrap()
+            .advance(entry.next_idx);
+        if let Fallback::Forward(to) = policy.fallback {
+            message.envelope.attempt = 1;
+            self.deliver(from, to, message).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the message at the head of the log of `actor_id` to the dead letters. If it cannot
+    /// be stored the actor is quarantined with it instead, so the message is not lost.
+    async fn dead_letter_head(
+        &mut self,
+        actor_id: AnyActorId,
+        from: AnyActorId,
+        next_idx: L::LogIndex,
+        message: AnyMessage,
+        failure: Failure,
+    ) -> RunResult<(), L> {
+        let attempt = message.envelope.attempt;
+        match self.store_dead_letter(from, actor_id, &message, failure, attempt) {
+            Ok(()) => {
+                self.actors.get_mut(&actor_id).unwrap().advance(next_idx);
+                Ok(())
+            }
+            Err(failure) => self.quarantine(actor_id, from, message, failure).await,
+        }
+    }
+
+    /// Stores `message` in the dead letters when there is nowhere else to keep it, e.g. because
+    /// its recipient does not exist. It is counted as lost if it cannot be stored.
+    fn dead_letter_or_lose(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: &AnyMessage,
+        failure: Failure,
+        attempts: u32,
+    ) {
+        if self
+            .store_dead_letter(from, to, message, failure, attempts)
+            .is_err()
+        {
+            match self.actors.get_mut(&to) {
+                Some(actor_data) => actor_data.stats.lost += 1,
+                None => self.lost += 1,
+            }
+        }
+    }
+
+    /// Stops scheduling `actor_id`, keeping `message` at the head of its log, and tells the
+    /// subscribers of [`QUARANTINE_TOPIC`].
+    async fn quarantine(
+        &mut self,
+        actor_id: AnyActorId,
+        sender: AnyActorId,
+        message: AnyMessage,
+        failure: Failure,
+    ) -> RunResult<(), L> {
+        let event = ActorQuarantined::new(actor_id, sender, &message, failure.clone())
+            .and_then(|event| AnyMessage::encode(&event, L::Codec::ID))
+            .map(|mut event| {
+                // The event continues the trace of the message that got the actor quarantined
+                event.envelope.trace_id = message.envelope.trace_id;
+                event.envelope.created_at = self.clock.now();
+                event
+            });
+        let actor_data = self.actors.get_mut(&actor_id).unwrap();
+        actor_data.head = Some(message);
+        actor_data.quarantined = Some(failure);
+        match event {
+            Ok(event) => self.publish(actor_id, QUARANTINE_TOPIC, event).await,
+            // The actor stays quarantined, operators can still find it with
+            // `Runtime::quarantined`
+            Err(_) => {
+                actor_data.stats.lost += 1;
+                Ok(())
+            }
         }
     }
+
+    /// Quarantines `actor_id` with the message at the head of its log, see
+    /// [`Runtime::quarantine`].
+    async fn quarantine_head(
+        &mut self,
+        actor_id: AnyActorId,
+        failure: Failure,
+    ) -> RunResult<(), L> {
+        let actor_data = self.actors.get_mut(&actor_id).unwrap();
+        // The handler took ownership of the message, it is read again
+        let Some(entry) = actor_data
+            .log
+            .read(actor_id, actor_data.curr_log_index)
+            .await
+            .map_err(RunError::Log)?
+        else {
+            return Ok(());
+        };
+        let message = actor_data.head.take().unwrap_or(entry.message);
+        self.quarantine(actor_id, entry.sender_id, message, failure)
+            .await
+    }
+
+    /// Stores a timer delivering `message` to `to` again at `due`. Returns why it could not be
+    /// stored otherwise.
+    fn schedule_retry(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: &AnyMessage,
+        due: Timestamp,
+    ) -> Result<(), Failure> {
+        let timer = Timer::new(due, from, to, message)?;
+        let bytes = schema::encode(VALUE_CODEC, Timer::VERSION, &timer)
+            .map_err(|_| Failure::new(FailureKind::Storage, "retry timer could not be encoded"))?;
+        let id = TimerId::new(self.next_timer);
+        let effects = HashMap::from([
+            (id.key(), GlobalEffect::Modified(bytes)),
+            (
+                timer::NEXT_TIMER_KEY.to_string(),
+                GlobalEffect::MaxRegister(self.next_timer as i64 + 1),
+            ),
+        ]);
+        if self
+            .db
+            .commit(&HashMap::new(), &HashMap::new(), effects)
+            .is_err()
+        {
+            let description = "retry timer could not be stored";
+            return Err(Failure::new(FailureKind::Storage, description));
+        }
+        self.next_timer += 1;
+        self.timers.insert(id, due);
+        Ok(())
+    }
+
+    /// Stores `message` in the dead letters, where it stays until it is replayed or discarded.
+    /// Returns why it could not be stored otherwise, the caller has to keep the message or count
+    /// it as lost.
+    fn store_dead_letter(
+        &mut self,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: &AnyMessage,
+        failure: Failure,
+        attempts: u32,
+    ) -> Result<(), Failure> {
+        let storage_failure = |what: &str| {
+            Failure::new(
+                FailureKind::Storage,
+                format!("dead letter could not be stored: {}", what),
+            )
+        };
+        let next = self
+            .db
+            .get_resource::<i64>(dead_letter::NEXT_DEAD_LETTER_KEY)
+            .map_err(|_| storage_failure("next id could not be read"))?
+            .unwrap_or(0);
+        let id = DeadLetterId::new(next as u64);
+        let now = self.clock.now();
+        let dead_letter = DeadLetter::new(id, from, to, message, failure, attempts, now)?;
+        let bytes = schema::encode(VALUE_CODEC, DeadLetter::VERSION, &dead_letter)
+            .map_err(|_| storage_failure("it could not be encoded"))?;
+        let effects = HashMap::from([
+            (id.key(), GlobalEffect::Modified(bytes)),
+            (
+                dead_letter::NEXT_DEAD_LETTER_KEY.to_string(),
+                GlobalEffect::MaxRegister(next + 1),
+            ),
+        ]);
+        self.db
+            .commit(&HashMap::new(), &HashMap::new(), effects)
+            .map_err(|_| storage_failure("commit failed"))
+    }
+
+    /// Evicts clean cache entries until `actor_id` fits in its budget and all actors together fit
+    /// in the runtime budget, evicting the least recently used entries across actors first.
+    fn enforce_cache_budget(&mut self, actor_id: AnyActorId) {
+        let actor_data = self.actors.get_mut(&actor_id).unwrap();
+        actor_data.cache.evict_to(self.cache_budget.per_actor);
+
+        let mut bytes: usize = self.actors.values().map(|a| a.cache.stats().bytes).sum();
+        while bytes > self.cache_budget.per_runtime {
+            let lru = self
+                .actors
+                .values_mut()
+                .filter_map(|actor_data| Some((actor_data.cache.lru_tick()?, actor_data)))
+                .min_by_key(|(tick, _)| *tick);
+            let Some((_, actor_data)) = lru else { break };
+            let before = actor_data.cache.stats().bytes;
+            actor_data.cache.evict_lru();
+            bytes -= before - actor_data.cache.stats().bytes;
+        }
+    }
+}
+
+/// Why [`Runtime::run`] stopped before its actors were done.
+#[derive(Debug)]
+pub enum RunError<E> {
+    /// The log of an actor could not be read or appended to.
+    Log(E),
+    /// State the runtime keeps in the database could not be read or written. It is left as it
+    /// is, so a run started once it is fixed, e.g. by registering an upcaster, picks it up.
+    Storage { key: String, description: String },
+    /// The `init` of an actor failed, nothing it did was committed.
+    Init {
+        actor_id: AnyActorId,
+        failure: Failure,
+    },
+}
+
+type RunResult<T, L> = Result<T, RunError<<L as Log>::Error>>;
+
+#[cfg(test)]
+type InterleavedWrites = Box<dyn FnMut(&mut Database)>;
+
+enum RuntimeError {
+    StorageError(StorageError),
+    Dispatch(DispatchError),
+    Other(Box<dyn Any>),
+}
+
+impl<E> RunError<E> {
+    fn storage(key: impl Into<String>, description: impl Into<String>) -> Self {
+        RunError::Storage {
+            key: key.into(),
+            description: description.into(),
+        }
+    }
+}
+
+impl AddAssign for DeliveryStats {
+    fn add_assign(&mut self, other: Self) {
+        self.duplicates_dropped += other.duplicates_dropped;
+        self.sequence_gaps += other.sequence_gaps;
+        self.out_of_order += other.out_of_order;
+        self.expired += other.expired;
+        self.lost += other.lost;
+    }
+}
+
+impl RuntimeError {
+    fn into_failure(self) -> Failure {
+        match self {
+            RuntimeError::Dispatch(err) => err.into(),
+            RuntimeError::StorageError(err) => {
+                let description = match err {
+                    StorageError::Db(_) => "database error",
+                    StorageError::Value => "value could not be decoded",
+                    StorageError::KeyNotFound => "key not found",
+                };
+                Failure::new(FailureKind::Storage, description)
+            }
+            RuntimeError::Other(payload) => {
+                let description = match payload.downcast::<String>() {
+                    Ok(message) => *message,
+                    Err(payload) => match payload.downcast::<&str>() {
+                        Ok(message) => message.to_string(),
+                        Err(_) => "handler panicked".to_string(),
+                    },
+                };
+                Failure::new(FailureKind::Panic, description)
+            }
+        }
+    }
+}
+
+impl<L> ActorData<L>
+where
+    L: Log,
+{
+    fn new(id: AnyActorId, actor: Box<dyn Any + RefUnwindSafe>, log: L) -> Self {
+        Self {
+            id,
+            actor,
+            log,
+            curr_log_index: L::LogIndex::ZERO,
+            head: None,
+            head_priority: None,
+            quarantined: None,
+            reruns: 0,
+            received: ReceivedSequences::default(),
+            stats: DeliveryStats::default(),
+            mailbox: MailboxStats::default(),
+            cache: GlobalStorageCache::new(),
+        }
+    }
+
+    /// Moves past the message at the head of the log, whether it was handled or not.
+    fn advance(&mut self, next_idx: L::LogIndex) {
+        self.curr_log_index = next_idx;
+        self.head = None;
+        self.head_priority = None;
+        self.reruns = 0;
+        self.mailbox.pop();
+    }
+
+    fn run_init(
+        &mut self,
+        table: &DynTable,
+        db: &Database,
+        config: RunConfig,
+    ) -> Result<Effects, RuntimeError> {
+        let mut cx = AnyContext::new(self.id, table, db, &mut self.cache, config);
+        catch_unwind_and_dispatch_errors(AssertUnwindSafe(|| {
+            table.dispatch_init(self.id.name, &*self.actor, cx)
+        }))
+    }
+
+    fn run_handler(
+        &mut self,
+        table: &DynTable,
+        sender_id: AnyActorId,
+        message: AnyMessage,
+        db: &Database,
+        config: RunConfig,
+    ) -> Result<Effects, RuntimeError>
+    where
+        L: Log,
+    {
+        let config = RunConfig {
+            trace_id: message.envelope.trace_id,
+            ..config
+        };
+        let mut cx = AnyContext::new(self.id, table, db, &mut self.cache, config);
+        cx.sender = Some(sender_id);
+        cx.reply_to = message.envelope.reply_to;
+        cx.attempt = message.envelope.attempt;
+        cx.envelope = Some(message.envelope.clone());
+        catch_unwind_and_dispatch_errors(AssertUnwindSafe(|| {
+            table.dispatch_handler(self.id.name, &*self.actor, cx, message)
+        }))
+    }
+}
+
+fn catch_unwind_and_dispatch_errors<T, F>(f: F) -> Result<T, RuntimeError>
+where
+    F: FnOnce() -> DispatchResult<T> + UnwindSafe,
+{
+    match std::panic::catch_unwind(f) {
+        Ok(result) => result.map_err(RuntimeError::Dispatch),
+        Err(err) => {
+            let err = err
+                .downcast::<StorageError>()
+                .map(|err| RuntimeError::StorageError(*err))
+                .unwrap_or_else(|err| RuntimeError::Other(err));
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context as TaskContext, Poll, Waker},
+        thread,
+        time::Duration,
+    };
+
+    use serde::{ser, Deserialize, Serialize, Serializer};
+
+    use super::{RunError, Runtime};
+    use crate::{
+        actor::{AnyActorId, PersistentActor, PersistentActorId},
+        codec::{CodecId, Json},
+        context::Context,
+        database::{Database, VALUE_CODEC},
+        dead_letter::{DeadLetterId, FailureKind},
+        dedup,
+        dispatcher::Callback,
+        dyn_table::DispatchError,
+        envelope::Envelope,
+        gather::{GatherPolicy, Gathered},
+        global_storage::{GlobalEffect, Namespace},
+        handler::{AsyncHandler, Handler, Receives},
+        log::{EncodingLog, Log, MemoryLog},
+        mailbox::{MailboxLimit, Overflow},
+        message::{AnyMessage, Message, MessageName},
+        reminder::{self, CatchUp, Reminder, Schedule},
+        retry::{Fallback, RetryPolicy},
+        scheduler::Priority,
+        schema, sequence,
+        timer::{self, Timestamp, VirtualClock},
+        topic::{TopicIndex, TOPICS_PREFIX},
+    };
+
+    /// Polls `future` to completion. It spins while the future is pending, tests drive the
+    /// runtime with a [`crate::timer::VirtualClock`] so it never waits for long.
+    pub(crate) fn block_on<F>(future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        let mut future = pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Job(u32);
+
+    impl Message for Job {
+        const NAME: &'static str = "Job";
+    }
+
+    /// Records the jobs it handles.
+    struct Worker {
+        handled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl PersistentActor for Worker {
+        const NAME: &'static str = "Worker";
+    }
+
+    impl Handler<Job> for Worker {
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, Job(job): Job) -> Result<(), ()> {
+            self.handled.lock().unwrap().push(job);
+            Ok(())
+        }
+    }
+
+    /// Fails its jobs until it is ready.
+    struct Picky {
+        ready: Arc<AtomicBool>,
+        handled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl PersistentActor for Picky {
+        const NAME: &'static str = "Picky";
+    }
+
+    impl Handler<Job> for Picky {
+        type Error = &'static str;
+
+        fn handle(&self, _: &mut Context<Self>, Job(job): Job) -> Result<(), &'static str> {
+            if !self.ready.load(Ordering::SeqCst) {
+                return Err("not ready");
+            }
+            self.handled.lock().unwrap().push(job);
+            Ok(())
+        }
+    }
+
+    /// Sends its jobs from `init`, the first time it starts only.
+    struct Boss<A>
+    where
+        A: PersistentActor,
+    {
+        jobs: Vec<(PersistentActorId<A>, u32)>,
+    }
+
+    impl<A> PersistentActor for Boss<A>
+    where
+        A: Receives<Job>,
+    {
+        const NAME: &'static str = "Boss";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_none() {
+                for &(worker, job) in &self.jobs {
+                    cx.dispatcher.send(worker, Job(job)).unwrap();
+                }
+            }
+            cx.storage.put("started", true);
+        }
+    }
+
+    fn runtime() -> Runtime<MemoryLog> {
+        let mut runtime = Runtime::new(Database::new());
+        runtime.register_actor::<Worker>();
+        runtime.register_actor::<Picky>();
+        runtime.register_handler::<Worker, Job>();
+        runtime.register_handler::<Picky, Job>();
+        runtime
+    }
+
+    fn add_worker(
+        runtime: &mut Runtime<MemoryLog>,
+        log: MemoryLog,
+    ) -> (PersistentActorId<Worker>, Arc<Mutex<Vec<u32>>>) {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let worker = Worker {
+            handled: handled.clone(),
+        };
+        (runtime.add_actor(worker, log), handled)
+    }
+
+    #[test]
+    fn actors_sharing_a_log_only_read_their_messages() {
+        let mut runtime = runtime();
+        let log = MemoryLog::new();
+        let (first, first_handled) = add_worker(&mut runtime, log.clone());
+        let (second, second_handled) = add_worker(&mut runtime, log.clone());
+        let jobs = vec![(first, 1), (second, 2), (first, 3)];
+        runtime.register_actor::<Boss<Worker>>();
+        runtime.add_actor(Boss { jobs }, log);
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*first_handled.lock().unwrap(), vec![1, 3]);
+        assert_eq!(*second_handled.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn dead_letters_are_replayed_after_a_run() {
+        let mut runtime = runtime();
+        let ready = Arc::new(AtomicBool::new(false));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let picky = Picky {
+            ready: ready.clone(),
+            handled: handled.clone(),
+        };
+        let picky = runtime.add_actor(picky, MemoryLog::new());
+        runtime.register_actor::<Boss<Picky>>();
+        let boss = runtime.add_actor(
+            Boss {
+                jobs: vec![(picky, 7)],
+            },
+            MemoryLog::new(),
+        );
+
+        assert!(block_on(runtime.run()).is_ok());
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter = &dead_letters[0];
+        assert_eq!(dead_letter.from, boss.into_any());
+        assert_eq!(dead_letter.to, picky.into_any());
+        assert_eq!(dead_letter.failure.kind, FailureKind::Handler);
+        assert_eq!(
+            dead_letter.decode::<Job>().ok().map(|Job(job)| job),
+            Some(7)
+        );
+        assert!(matches!(runtime.dead_letter(dead_letter.id), Ok(Some(_))));
+
+        ready.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            block_on(runtime.replay_dead_letter(dead_letter.id)),
+            Ok(true)
+        ));
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*handled.lock().unwrap(), vec![7]);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    /// A database with `key` holding something that is not an `i64`.
+    fn corrupt(key: &str) -> Database {
+        let mut db = Database::new();
+        let bytes = schema::encode(VALUE_CODEC, 0, &"corrupt").ok().unwrap();
+        let effects = HashMap::from([(key.to_string(), GlobalEffect::Modified(bytes))]);
+        assert!(db.commit(&HashMap::new(), &HashMap::new(), effects).is_ok());
+        db
+    }
+
+    #[test]
+    fn unreadable_next_timer_fails_the_run() {
+        let mut runtime = Runtime::<MemoryLog>::new(corrupt(timer::NEXT_TIMER_KEY));
+        let error = block_on(runtime.run()).unwrap_err();
+        assert!(matches!(error, RunError::Storage { key, .. } if key == timer::NEXT_TIMER_KEY));
+    }
+
+    #[test]
+    fn unreadable_dead_letters_can_be_discarded() {
+        let id = DeadLetterId::new(0);
+        let mut runtime = Runtime::<MemoryLog>::new(corrupt(&id.key()));
+        assert!(runtime.dead_letters().is_err());
+        assert!(runtime.dead_letter(id).is_err());
+        assert!(runtime.discard_dead_letter(id));
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+        assert!(!runtime.discard_dead_letter(id));
+    }
+
+    struct Broken;
+
+    impl PersistentActor for Broken {
+        const NAME: &'static str = "Broken";
+
+        fn init(&self, _: &mut Context<Self>) {
+            panic!("cannot start");
+        }
+    }
+
+    #[test]
+    fn failed_init_fails_the_run() {
+        let mut runtime = runtime();
+        runtime.register_actor::<Broken>();
+        let broken = runtime.add_actor(Broken, MemoryLog::new());
+        let error = block_on(runtime.run()).unwrap_err();
+        assert!(matches!(
+            error,
+            RunError::Init { actor_id, failure }
+                if actor_id == broken.into_any() && failure.kind == FailureKind::Panic
+        ));
+    }
+
+    #[test]
+    fn sends_without_a_sequence_number_are_dead_lettered() {
+        let worker = PersistentActorId::<Worker>::new(1);
+        let boss = PersistentActorId::<Boss<Worker>>::new(2);
+        let key = sequence::sequence_key(boss.into_any(), worker.into_any());
+        let mut runtime = Runtime::new(corrupt(&key));
+        runtime.register_actor::<Worker>();
+        runtime.register_actor::<Boss<Worker>>();
+        runtime.register_handler::<Worker, Job>();
+        let (worker, handled) = add_worker(&mut runtime, MemoryLog::new());
+        let jobs = vec![(worker, 1)];
+        let added = runtime.add_actor(Boss { jobs }, MemoryLog::new());
+        assert_eq!(added.into_any(), boss.into_any());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert!(handled.lock().unwrap().is_empty());
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Storage);
+        assert_eq!(dead_letters[0].to, worker.into_any());
+    }
+
+    /// Records its jobs and sends itself the next one up to 3.
+    struct Echo {
+        handled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl PersistentActor for Echo {
+        const NAME: &'static str = "Echo";
+    }
+
+    impl Handler<Job> for Echo {
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, Job(job): Job) -> Result<(), ()> {
+            self.handled.lock().unwrap().push(job);
+            if job < 3 {
+                cx.dispatcher.send(cx.actor_id, Job(job + 1)).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn messages_sent_to_a_full_mailbox_by_its_owner_keep_the_handled_one() {
+        let mut runtime = runtime();
+        runtime.register_actor::<Echo>();
+        runtime.register_actor::<Boss<Echo>>();
+        runtime.register_handler::<Echo, Job>();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let echo = Echo {
+            handled: handled.clone(),
+        };
+        let echo = runtime.add_actor(echo, MemoryLog::new());
+        let limit = MailboxLimit::new(2, Overflow::DropOldest);
+        runtime.set_mailbox_limit(echo.into_any(), limit);
+        let jobs = vec![(echo, 0), (echo, 10)];
+        runtime.add_actor(Boss { jobs }, MemoryLog::new());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*handled.lock().unwrap(), vec![0, 10, 1, 2, 3]);
+        let stats = runtime.actor_mailbox_stats(echo.into_any()).unwrap();
+        assert_eq!(stats.dropped, 0);
+        assert_eq!(stats.depth, 0);
+        assert_eq!(stats.peak_depth, 2);
+    }
+
+    /// Answers jobs with the next job.
+    struct Replier;
+
+    impl PersistentActor for Replier {
+        const NAME: &'static str = "Replier";
+    }
+
+    impl Handler<Job> for Replier {
+        type Error = DispatchError;
+
+        fn handle(&self, cx: &mut Context<Self>, Job(job): Job) -> Result<(), DispatchError> {
+            cx.reply(Job(job + 1))
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Plan {
+        /// Wait for a callback that is never called.
+        Expire,
+        /// Ask for a reply to a callback and cancel it.
+        Cancel,
+        /// Ask for a reply to a callback, register a reminder, subscribe to a topic and stop
+        /// when a job arrives.
+        Stop,
+    }
+
+    /// Creates a callback when it starts and records what becomes of it.
+    struct Waiter {
+        replier: PersistentActorId<Replier>,
+        plan: Plan,
+        events: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl PersistentActor for Waiter {
+        const NAME: &'static str = "Waiter";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_some() {
+                cx.storage.put("started", true);
+                return;
+            }
+            cx.storage.put("started", true);
+            match self.plan {
+                Plan::Expire => {
+                    let expiry = Duration::from_millis(100);
+                    cx.create_expiring_callback::<Job>(expiry).unwrap();
+                }
+                Plan::Cancel => {
+                    let callback = cx.create_callback::<Job>();
+                    let replier = self.replier;
+                    cx.dispatcher
+                        .send_with_callback(replier, Job(1), callback)
+                        .unwrap();
+                    cx.dispatcher.cancel(callback).unwrap();
+                }
+                Plan::Stop => {
+                    let expiry = Duration::from_secs(60);
+                    let callback = cx.create_expiring_callback::<Job>(expiry).unwrap();
+                    let replier = self.replier;
+                    cx.dispatcher
+                        .send_with_callback(replier, Job(1), callback)
+                        .unwrap();
+                    let schedule = Schedule::Every(Duration::from_secs(1));
+                    cx.dispatcher
+                        .register_reminder(cx.actor_id, "tick", schedule, CatchUp::Skip)
+                        .unwrap();
+                    cx.subscribe::<Job>("jobs");
+                    let key = |envelope: &mut Envelope| {
+                        envelope.idempotency_key = Some("stop".to_string());
+                    };
+                    cx.dispatcher
+                        .send_with_envelope(cx.actor_id, Job(0), key)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    impl Callback<Job> for Waiter {
+        type Env = ();
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, _: (), _: Job) -> Result<(), ()> {
+            self.events.lock().unwrap().push("called");
+            Ok(())
+        }
+
+        fn expired(&self, _: &mut Context<Self>, _: ()) -> Result<(), ()> {
+            self.events.lock().unwrap().push("expired");
+            Ok(())
+        }
+    }
+
+    impl Handler<Job> for Waiter {
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, _: Job) -> Result<(), ()> {
+            self.events.lock().unwrap().push("job");
+            cx.stop();
+            Ok(())
+        }
+    }
+
+    impl Handler<Reminder> for Waiter {
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, _: Reminder) -> Result<(), ()> {
+            self.events.lock().unwrap().push("reminded");
+            Ok(())
+        }
+    }
+
+    /// Runs a [`Waiter`] following `plan`, returning the runtime and what the waiter recorded.
+    fn wait(plan: Plan) -> (Runtime<MemoryLog>, AnyActorId, Vec<&'static str>) {
+        let mut runtime = runtime();
+        runtime.set_clock(VirtualClock::new(Timestamp::from_millis(1_000)));
+        runtime.register_actor::<Replier>();
+        runtime.register_actor::<Waiter>();
+        runtime.register_handler::<Replier, Job>();
+        runtime.register_handler::<Waiter, Job>();
+        runtime.register_handler::<Waiter, Reminder>();
+        runtime.register_callback::<Waiter, Job>();
+        let replier = runtime.add_actor(Replier, MemoryLog::new());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let waiter = Waiter {
+            replier,
+            plan,
+            events: events.clone(),
+        };
+        let waiter = runtime.add_actor(waiter, MemoryLog::new()).into_any();
+        assert!(block_on(runtime.run()).is_ok());
+        let events = events.lock().unwrap().clone();
+        (runtime, waiter, events)
+    }
+
+    /// Keys `actor_id` has in the runtime's namespace.
+    fn runtime_keys(runtime: &Runtime<MemoryLog>, actor_id: AnyActorId) -> Vec<String> {
+        let prefix = Namespace::Runtime(actor_id).backend_key("");
+        let keys = runtime.db.keys_with_prefix(&prefix);
+        keys.map(|key| key[prefix.len()..].to_string()).collect()
+    }
+
+    #[test]
+    fn expired_callbacks_run_their_expiry_and_are_removed() {
+        let (runtime, waiter, events) = wait(Plan::Expire);
+        assert_eq!(events, vec!["expired"]);
+        assert_eq!(runtime_keys(&runtime, waiter), vec!["callback/next"]);
+        assert_eq!(runtime.timers.next_due(), None);
+    }
+
+    #[test]
+    fn replies_to_cancelled_callbacks_are_dead_lettered() {
+        let (runtime, waiter, events) = wait(Plan::Cancel);
+        assert!(events.is_empty());
+        assert_eq!(runtime_keys(&runtime, waiter), vec!["callback/next"]);
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].to, waiter);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Dispatch);
+    }
+
+    #[test]
+    fn stopped_actors_leave_no_state_behind() {
+        let (runtime, waiter, events) = wait(Plan::Stop);
+        assert_eq!(events, vec!["job"]);
+        assert!(runtime_keys(&runtime, waiter).is_empty());
+        let reminders = reminder::reminders_prefix(waiter);
+        assert_eq!(runtime.db.keys_with_prefix(&reminders).count(), 0);
+        assert_eq!(runtime.reminders.next_due(), None);
+        assert_eq!(runtime.timers.next_due(), None);
+        assert!(TopicIndex::subscriptions_of(&runtime.db, waiter).is_empty());
+        let job = MessageName::name_for::<Job>();
+        assert_eq!(runtime.topics.subscribers("jobs", job).count(), 0);
+        assert!(sequence::keys_of(&runtime.db, waiter).is_empty());
+        assert!(!runtime.db.contains_key(&dedup::window_key(waiter)));
+        assert!(runtime
+            .sequences
+            .keys()
+            .all(|&(from, to)| from != waiter && to != waiter));
+    }
+
+    /// Fails the first `fails` attempts of every job, recording when each attempt was made.
+    struct Flaky {
+        fails: u32,
+        attempts: Arc<Mutex<Vec<(u32, u64)>>>,
+    }
+
+    impl PersistentActor for Flaky {
+        const NAME: &'static str = "Flaky";
+    }
+
+    impl Handler<Job> for Flaky {
+        type Error = &'static str;
+
+        fn handle(&self, cx: &mut Context<Self>, _: Job) -> Result<(), &'static str> {
+            let attempt = cx.attempt();
+            let now = cx.dispatcher.now().as_millis();
+            self.attempts.lock().unwrap().push((attempt, now));
+            match attempt <= self.fails {
+                true => Err("flaky"),
+                false => Ok(()),
+            }
+        }
+    }
+
+    /// Sends a job to a [`Flaky`] failing its first `fails` attempts, returning the runtime and
+    /// the attempts made.
+    fn retry(fails: u32) -> (Runtime<MemoryLog>, Vec<(u32, u64)>) {
+        let mut runtime = runtime();
+        runtime.set_clock(VirtualClock::new(Timestamp::from_millis(1_000)));
+        runtime.register_actor::<Flaky>();
+        runtime.register_actor::<Boss<Flaky>>();
+        runtime.register_handler::<Flaky, Job>();
+        let policy = RetryPolicy {
+            retryable: vec![FailureKind::Handler],
+            ..RetryPolicy::default()
+        };
+        runtime.set_retry_policy::<Flaky, Job>(policy);
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let flaky = Flaky {
+            fails,
+            attempts: attempts.clone(),
+        };
+        let flaky = runtime.add_actor(flaky, MemoryLog::new());
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(flaky, 1)],
+            },
+            MemoryLog::new(),
+        );
+        assert!(block_on(runtime.run()).is_ok());
+        let attempts = attempts.lock().unwrap().clone();
+        (runtime, attempts)
+    }
+
+    #[test]
+    fn failed_messages_are_retried_after_their_backoff() {
+        let (runtime, attempts) = retry(2);
+        assert_eq!(attempts, vec![(1, 1_000), (2, 1_100), (3, 1_300)]);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+        assert_eq!(runtime.timers.next_due(), None);
+    }
+
+    #[test]
+    fn messages_failing_every_attempt_go_to_the_fallback() {
+        let (runtime, attempts) = retry(u32::MAX);
+        assert_eq!(attempts, vec![(1, 1_000), (2, 1_100), (3, 1_300)]);
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 3);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Handler);
+    }
+
+    /// Counts its jobs in a shared key, recording the count every run saw.
+    struct Counter {
+        seen: Arc<Mutex<Vec<i64>>>,
+    }
+
+    impl PersistentActor for Counter {
+        const NAME: &'static str = "Counter";
+    }
+
+    impl Handler<Job> for Counter {
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, _: Job) -> Result<(), ()> {
+            let counters = cx.shared_storage("counters");
+            let hits = counters.take::<i64, _>("hits").unwrap_or(0);
+            counters.put("hits", hits + 1);
+            self.seen.lock().unwrap().push(hits);
+            Ok(())
+        }
+    }
+
+    fn hits_key() -> String {
+        Namespace::Shared("counters".to_string()).backend_key("hits")
+    }
+
+    /// Sends a job to a [`Counter`] whose hits are bumped by 10 between its first `writes` runs
+    /// and their commit. Returns the runtime, the counter and the counts its runs saw.
+    fn contended(writes: usize) -> (Runtime<MemoryLog>, AnyActorId, Arc<Mutex<Vec<i64>>>) {
+        let mut runtime = Runtime::new(Database::new());
+        runtime.register_actor::<Counter>();
+        runtime.register_actor::<Boss<Counter>>();
+        runtime.register_handler::<Counter, Job>();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let counter = runtime.add_actor(Counter { seen: seen.clone() }, MemoryLog::new());
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(counter, 1)],
+            },
+            MemoryLog::new(),
+        );
+        interleave(&mut runtime, hits_key(), writes);
+        (runtime, counter.into_any(), seen)
+    }
+
+    /// Bumps `key` by 10 between the first `writes` runs of a handler and their commit.
+    fn interleave(runtime: &mut Runtime<MemoryLog>, key: String, mut writes: usize) {
+        runtime.interleaved_writes = Some(Box::new(move |db: &mut Database| {
+            if writes > 0 {
+                writes -= 1;
+                let effects = HashMap::from([(key.clone(), GlobalEffect::Increment(10))]);
+                assert!(db.commit(&HashMap::new(), &HashMap::new(), effects).is_ok());
+            }
+        }));
+    }
+
+    #[test]
+    fn handlers_are_rerun_when_their_reads_conflict() {
+        let (mut runtime, counter, seen) = contended(1);
+        assert!(block_on(runtime.run()).is_ok());
+        // The second run sees the concurrent write, which is not lost
+        assert_eq!(*seen.lock().unwrap(), vec![0, 10]);
+        assert_eq!(
+            runtime.db.get_resource::<i64>(&hits_key()).ok().unwrap(),
+            Some(11)
+        );
+        assert!(runtime.quarantined(counter).is_none());
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    #[test]
+    fn actors_whose_effects_keep_being_rejected_are_quarantined() {
+        let (mut runtime, counter, seen) = contended(usize::MAX);
+        runtime.set_rerun_limit(3);
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*seen.lock().unwrap(), vec![0, 10, 20]);
+        let failure = runtime.quarantined(counter).unwrap();
+        assert_eq!(failure.kind, FailureKind::Storage);
+
+        runtime.interleaved_writes = None;
+        assert!(matches!(block_on(runtime.release_actor(counter)), Ok(true)));
+        assert!(matches!(
+            block_on(runtime.release_actor(counter)),
+            Ok(false)
+        ));
+        assert!(block_on(runtime.run()).is_ok());
+        assert!(runtime.quarantined(counter).is_none());
+        assert_eq!(*seen.lock().unwrap(), vec![0, 10, 20, 30]);
+        assert_eq!(
+            runtime.db.get_resource::<i64>(&hits_key()).ok().unwrap(),
+            Some(31)
+        );
+    }
+
+    #[test]
+    fn quarantined_messages_can_be_skipped_or_fixed() {
+        let mut runtime = runtime();
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            fallback: Fallback::Quarantine,
+            ..RetryPolicy::default()
+        };
+        runtime.set_retry_policy::<Picky, Job>(policy);
+        let ready = Arc::new(AtomicBool::new(false));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let mut add_picky = |runtime: &mut Runtime<MemoryLog>| {
+            let picky = Picky {
+                ready: ready.clone(),
+                handled: handled.clone(),
+            };
+            runtime.add_actor(picky, MemoryLog::new())
+        };
+        let skipped = add_picky(&mut runtime);
+        let fixed = add_picky(&mut runtime);
+        runtime.register_actor::<Boss<Picky>>();
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(skipped, 1), (fixed, 2)],
+            },
+            MemoryLog::new(),
+        );
+        let (skipped, fixed) = (skipped.into_any(), fixed.into_any());
+
+        assert!(block_on(runtime.run()).is_ok());
+        for actor_id in [skipped, fixed] {
+            let failure = runtime.quarantined(actor_id).unwrap();
+            assert_eq!(failure.kind, FailureKind::Handler);
+        }
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+
+        ready.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            block_on(runtime.skip_quarantined_message(skipped)),
+            Ok(true)
+        ));
+        assert!(matches!(
+            block_on(runtime.fix_quarantined_message(fixed, Job(3))),
+            Ok(true)
+        ));
+        assert!(block_on(runtime.run()).is_ok());
+        assert!(runtime.quarantined(skipped).is_none());
+        assert!(runtime.quarantined(fixed).is_none());
+        assert_eq!(*handled.lock().unwrap(), vec![3]);
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].to, skipped);
+        assert_eq!(
+            dead_letters[0].decode::<Job>().ok().map(|Job(job)| job),
+            Some(1)
+        );
+    }
+
+    /// Takes an item from the stock, provided neither it nor the prices changed while it ran.
+    struct Reserver {
+        runs: Arc<Mutex<u32>>,
+    }
+
+    impl PersistentActor for Reserver {
+        const NAME: &'static str = "Reserver";
+    }
+
+    impl Handler<Job> for Reserver {
+        type Error = &'static str;
+
+        fn handle(&self, cx: &mut Context<Self>, _: Job) -> Result<(), &'static str> {
+            *self.runs.lock().unwrap() += 1;
+            let stock = cx.shared_storage("stock");
+            stock.require_unchanged(&["prices"]);
+            let version = stock.version("items");
+            stock
+                .put_if_version("items", version, 1_i64)
+                .map_err(|_| "stock changed")
+        }
+    }
+
+    /// Runs a [`Reserver`] while `key` of its stock is written once, returning the runtime and
+    /// how many times it ran.
+    fn reserve(key: &str) -> (Runtime<MemoryLog>, u32) {
+        let mut runtime = Runtime::new(Database::new());
+        runtime.register_actor::<Reserver>();
+        runtime.register_actor::<Boss<Reserver>>();
+        runtime.register_handler::<Reserver, Job>();
+        let runs = Arc::new(Mutex::new(0));
+        let reserver = runtime.add_actor(Reserver { runs: runs.clone() }, MemoryLog::new());
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(reserver, 1)],
+            },
+            MemoryLog::new(),
+        );
+        let key = Namespace::Shared("stock".to_string()).backend_key(key);
+        interleave(&mut runtime, key, 1);
+        assert!(block_on(runtime.run()).is_ok());
+        let runs = *runs.lock().unwrap();
+        (runtime, runs)
+    }
+
+    #[test]
+    fn handlers_are_rerun_when_their_guards_fail() {
+        let items = Namespace::Shared("stock".to_string()).backend_key("items");
+        for key in ["items", "prices"] {
+            let (runtime, runs) = reserve(key);
+            assert_eq!(runs, 2, "{key}");
+            // Only the second run was committed
+            assert_eq!(
+                runtime.db.get_resource::<i64>(&items).ok().unwrap(),
+                Some(1)
+            );
+            assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+        }
+    }
+
+    /// Where a reply arrived, the job it carried and who sent it.
+    type Reply = (&'static str, u32, Option<AnyActorId>);
+
+    /// Sends jobs to a [`Replier`] when it starts, recording the replies.
+    struct Asker {
+        replier: PersistentActorId<Replier>,
+        replies: Arc<Mutex<Vec<Reply>>>,
+    }
+
+    impl PersistentActor for Asker {
+        const NAME: &'static str = "Asker";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_none() {
+                cx.dispatcher.send(self.replier, Job(1)).unwrap();
+                let callback = cx.create_callback::<Job>();
+                cx.dispatcher
+                    .send_with_callback(self.replier, Job(10), callback)
+                    .unwrap();
+            }
+            cx.storage.put("started", true);
+        }
+    }
+
+    impl Handler<Job> for Asker {
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, Job(job): Job) -> Result<(), ()> {
+            let reply = ("handler", job, cx.sender());
+            self.replies.lock().unwrap().push(reply);
+            Ok(())
+        }
+    }
+
+    impl Callback<Job> for Asker {
+        type Env = ();
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, _: (), Job(job): Job) -> Result<(), ()> {
+            let reply = ("callback", job, cx.sender());
+            self.replies.lock().unwrap().push(reply);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replies_go_to_the_callback_or_the_handler_of_the_sender() {
+        let mut runtime = runtime();
+        runtime.register_actor::<Replier>();
+        runtime.register_actor::<Asker>();
+        runtime.register_actor::<Boss<Replier>>();
+        runtime.register_handler::<Replier, Job>();
+        runtime.register_handler::<Asker, Job>();
+        runtime.register_callback::<Asker, Job>();
+        let replier = runtime.add_actor(Replier, MemoryLog::new());
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let asker = Asker {
+            replier,
+            replies: replies.clone(),
+        };
+        runtime.add_actor(asker, MemoryLog::new());
+        // Cannot receive a reply
+        let boss = runtime.add_actor(
+            Boss {
+                jobs: vec![(replier, 20)],
+            },
+            MemoryLog::new(),
+        );
+
+        assert!(block_on(runtime.run()).is_ok());
+        let replier = replier.into_any();
+        assert_eq!(
+            *replies.lock().unwrap(),
+            vec![
+                ("handler", 2, Some(replier)),
+                ("callback", 11, Some(replier))
+            ]
+        );
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].from, boss.into_any());
+        assert_eq!(dead_letters[0].to, replier);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Handler);
+    }
+
+    /// Asks a [`Replier`] twice for every job, recording the answers every run got.
+    struct Adder {
+        replier: PersistentActorId<Replier>,
+        runs: Arc<Mutex<Vec<Vec<u32>>>>,
+    }
+
+    impl PersistentActor for Adder {
+        const NAME: &'static str = "Adder";
+    }
+
+    impl Receives<Job> for Adder {}
+
+    impl AsyncHandler<Job> for Adder {
+        type Error = DispatchError;
+
+        async fn handle(
+            &self,
+            cx: &mut Context<'_, Self>,
+            Job(job): Job,
+        ) -> Result<(), DispatchError> {
+            self.runs.lock().unwrap().push(Vec::new());
+            let Job(first) = cx.ask(self.replier, Job(job)).await?;
+            self.runs.lock().unwrap().last_mut().unwrap().push(first);
+            let Job(second) = cx.ask(self.replier, Job(first * 10)).await?;
+            self.runs.lock().unwrap().last_mut().unwrap().push(second);
+            cx.storage.put("result", second);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn async_handlers_resume_when_their_responses_arrive() {
+        let mut runtime = runtime();
+        runtime.register_actor::<Replier>();
+        runtime.register_actor::<Adder>();
+        runtime.register_actor::<Boss<Adder>>();
+        runtime.register_handler::<Replier, Job>();
+        runtime.register_async_handler::<Adder, Job>();
+        let replier = runtime.add_actor(Replier, MemoryLog::new());
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let adder = Adder {
+            replier,
+            runs: runs.clone(),
+        };
+        let adder = runtime.add_actor(adder, MemoryLog::new());
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(adder, 1)],
+            },
+            MemoryLog::new(),
+        );
+
+        assert!(block_on(runtime.run()).is_ok());
+        // Every response runs the handler again from the start, with the responses so far
+        assert_eq!(*runs.lock().unwrap(), vec![vec![], vec![2], vec![2, 21]]);
+        let adder = adder.into_any();
+        let result = Namespace::Actor(adder).backend_key("result");
+        assert_eq!(
+            runtime.db.get_resource::<u32>(&result).ok().unwrap(),
+            Some(21)
+        );
+        // The suspended run is gone once the handler completes
+        assert_eq!(runtime_keys(&runtime, adder), vec!["callback/next"]);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    /// Subscribes to the jobs published to `topic` when it starts, recording them.
+    struct Subscriber {
+        topic: &'static str,
+        handled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl PersistentActor for Subscriber {
+        const NAME: &'static str = "Subscriber";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            cx.subscribe::<Job>(self.topic);
+        }
+    }
+
+    impl Handler<Job> for Subscriber {
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, Job(job): Job) -> Result<(), ()> {
+            self.handled.lock().unwrap().push(job);
+            Ok(())
+        }
+    }
+
+    /// Publishes the jobs it is sent to `"jobs"`.
+    struct Publisher;
+
+    impl PersistentActor for Publisher {
+        const NAME: &'static str = "Publisher";
+    }
+
+    impl Handler<Job> for Publisher {
+        type Error = DispatchError;
+
+        fn handle(&self, cx: &mut Context<Self>, job: Job) -> Result<(), DispatchError> {
+            cx.publish("jobs", job)
+        }
+    }
+
+    #[test]
+    fn published_messages_reach_every_subscriber_of_their_topic() {
+        let mut runtime = runtime();
+        runtime.register_actor::<Subscriber>();
+        runtime.register_actor::<Publisher>();
+        runtime.register_actor::<Boss<Publisher>>();
+        runtime.register_handler::<Subscriber, Job>();
+        runtime.register_handler::<Publisher, Job>();
+        let mut subscribe = |topic| {
+            let handled = Arc::new(Mutex::new(Vec::new()));
+            let subscriber = Subscriber {
+                topic,
+                handled: handled.clone(),
+            };
+            runtime.add_actor(subscriber, MemoryLog::new());
+            handled
+        };
+        let subscribers = [subscribe("jobs"), subscribe("jobs"), subscribe("other")];
+        let publisher = runtime.add_actor(Publisher, MemoryLog::new());
+        let jobs = vec![(publisher, 1), (publisher, 2)];
+        runtime.add_actor(Boss { jobs }, MemoryLog::new());
+        assert!(block_on(runtime.run()).is_ok());
+
+        // The subscriptions are read back from the database when the runtime runs again
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(publisher, 3)],
+            },
+            MemoryLog::new(),
+        );
+        assert!(block_on(runtime.run()).is_ok());
+        let handled = subscribers.map(|handled| handled.lock().unwrap().clone());
+        assert_eq!(handled, [vec![1, 2, 3], vec![1, 2, 3], vec![]]);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    #[test]
+    fn unreadable_reminders_fail_the_run() {
+        let owner = PersistentActorId::<Worker>::new(1).into_any();
+        let key = reminder::reminder_key(owner, "corrupt");
+        let mut runtime = Runtime::<MemoryLog>::new(corrupt(&key));
+        let error = block_on(runtime.run()).unwrap_err();
+        assert!(matches!(error, RunError::Storage { key: found, .. } if found == key));
+    }
+
+    #[test]
+    fn unreadable_subscriptions_fail_the_run() {
+        let key = format!("{}jobs/corrupt", TOPICS_PREFIX);
+        let mut runtime = Runtime::<MemoryLog>::new(corrupt(&key));
+        let error = block_on(runtime.run()).unwrap_err();
+        assert!(matches!(error, RunError::Storage { key: found, .. } if found == key));
+    }
+
+    #[derive(Debug)]
+    enum Refusal {
+        Busy,
+        Never,
+    }
+
+    /// Asks a [`Replier`] for jobs when it starts. Its callback stores and forwards every job to
+    /// `worker`, but refuses job 2 once and job 6 for good.
+    struct Refuser {
+        replier: PersistentActorId<Replier>,
+        worker: PersistentActorId<Worker>,
+    }
+
+    impl PersistentActor for Refuser {
+        const NAME: &'static str = "Refuser";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_none() {
+                for job in [1, 5] {
+                    let callback = cx.create_callback::<Job>();
+                    cx.dispatcher
+                        .send_with_callback(self.replier, Job(job), callback)
+                        .unwrap();
+                }
+            }
+            cx.storage.put("started", true);
+        }
+    }
+
+    impl Callback<Job> for Refuser {
+        type Env = ();
+        type Error = Refusal;
+
+        fn handle(&self, cx: &mut Context<Self>, _: (), Job(job): Job) -> Result<(), Refusal> {
+            let attempt = cx.attempt();
+            cx.storage.put(format!("job/{job}"), attempt);
+            cx.dispatcher.send(self.worker, Job(job)).unwrap();
+            match (job, attempt) {
+                (2, 1) => Err(Refusal::Busy),
+                (6, _) => Err(Refusal::Never),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn typed_callback_errors_discard_their_effects_and_reach_supervision() {
+        let mut runtime = runtime();
+        runtime.set_clock(VirtualClock::new(Timestamp::from_millis(1_000)));
+        runtime.register_actor::<Replier>();
+        runtime.register_actor::<Refuser>();
+        runtime.register_handler::<Replier, Job>();
+        runtime.register_callback::<Refuser, Job>();
+        let policy = RetryPolicy {
+            retryable: Vec::new(),
+            ..RetryPolicy::default()
+        }
+        .retry_handler_error::<Refusal>(|refusal| matches!(refusal, Refusal::Busy));
+        runtime.set_actor_retry_policy::<Refuser>(policy);
+        let replier = runtime.add_actor(Replier, MemoryLog::new());
+        let (worker, handled) = add_worker(&mut runtime, MemoryLog::new());
+        let refuser = runtime.add_actor(Refuser { replier, worker }, MemoryLog::new());
+        let refuser = refuser.into_any();
+
+        assert!(block_on(runtime.run()).is_ok());
+        // Only the run that succeeded, on the retry of job 2, kept its effects
+        assert_eq!(*handled.lock().unwrap(), vec![2]);
+        let stored = |job: u32| {
+            let key = Namespace::Actor(refuser).backend_key(&format!("job/{job}"));
+            runtime.db.get_resource::<u32>(&key).ok().unwrap()
+        };
+        assert_eq!(stored(2), Some(2));
+        assert_eq!(stored(6), None);
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].to, refuser);
+        assert_eq!(dead_letters[0].attempts, 1);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Handler);
+        assert_eq!(dead_letters[0].failure.description, "Never");
+    }
+
+    /// Like [`Boss`], every job is sent with the headers, deadline and idempotency key of its
+    /// envelope.
+    struct Stamper<A>
+    where
+        A: PersistentActor,
+    {
+        jobs: Vec<(PersistentActorId<A>, u32, Envelope)>,
+    }
+
+    impl<A> PersistentActor for Stamper<A>
+    where
+        A: Receives<Job>,
+    {
+        const NAME: &'static str = "Stamper";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_none() {
+                for (worker, job, stamp) in &self.jobs {
+                    let envelope = |envelope: &mut Envelope| {
+                        envelope.headers = stamp.headers.clone();
+                        envelope.deadline = stamp.deadline;
+                        envelope.idempotency_key = stamp.idempotency_key.clone();
+                    };
+                    cx.dispatcher
+                        .send_with_envelope(*worker, Job(*job), envelope)
+                        .unwrap();
+                }
+            }
+            cx.storage.put("started", true);
+        }
+    }
+
+    #[test]
+    fn duplicates_within_the_dedup_window_are_dropped() {
+        let mut runtime = runtime();
+        runtime.set_dedup_window(1);
+        runtime.register_actor::<Stamper<Worker>>();
+        let (worker, handled) = add_worker(&mut runtime, MemoryLog::new());
+        let keyed = |job, key: &str| {
+            let mut envelope = Envelope::new(Priority::default());
+            envelope.idempotency_key = Some(key.to_string());
+            (worker, job, envelope)
+        };
+        let unkeyed = |job| (worker, job, Envelope::new(Priority::default()));
+        let jobs = vec![
+            keyed(1, "a"),
+            keyed(1, "a"),
+            keyed(2, "b"),
+            // Forgotten by a window of one key
+            keyed(1, "a"),
+            unkeyed(3),
+            unkeyed(3),
+        ];
+        runtime.add_actor(Stamper { jobs }, MemoryLog::new());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*handled.lock().unwrap(), vec![1, 2, 1, 3, 3]);
+        let worker = worker.into_any();
+        let stats = runtime.actor_delivery_stats(worker).unwrap();
+        assert_eq!(stats.duplicates_dropped, 1);
+        assert!(runtime.db.contains_key(&dedup::window_key(worker)));
+    }
+
+    #[test]
+    fn messages_past_their_deadline_are_dropped() {
+        let mut runtime = runtime();
+        runtime.set_clock(VirtualClock::new(Timestamp::from_millis(1_000)));
+        runtime.register_actor::<Flaky>();
+        runtime.register_actor::<Stamper<Flaky>>();
+        runtime.register_handler::<Flaky, Job>();
+        let policy = RetryPolicy {
+            retryable: vec![FailureKind::Handler],
+            ..RetryPolicy::default()
+        };
+        runtime.set_retry_policy::<Flaky, Job>(policy);
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let flaky = Flaky {
+            fails: 1,
+            attempts: attempts.clone(),
+        };
+        let flaky = runtime.add_actor(flaky, MemoryLog::new());
+        let due = |job, deadline| {
+            let mut envelope = Envelope::new(Priority::default());
+            envelope.deadline = Some(Timestamp::from_millis(deadline));
+            (flaky, job, envelope)
+        };
+        // Both fail their first attempt, only the second is still due when they are retried
+        let jobs = vec![due(1, 1_050), due(2, 1_500)];
+        runtime.add_actor(Stamper { jobs }, MemoryLog::new());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(
+            *attempts.lock().unwrap(),
+            vec![(1, 1_000), (1, 1_000), (2, 1_100)]
+        );
+        let stats = runtime.actor_delivery_stats(flaky.into_any()).unwrap();
+        assert_eq!(stats.expired, 1);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    /// The jobs a gather collected, whether it timed out and when it completed.
+    type Collected = (Vec<Option<u32>>, bool, u64);
+
+    /// Gathers the replies to jobs sent to a [`Replier`] twice and to `worker` once, which never
+    /// replies.
+    struct Gatherer {
+        replier: PersistentActorId<Replier>,
+        worker: PersistentActorId<Worker>,
+        policy: GatherPolicy,
+        collected: Arc<Mutex<Vec<Collected>>>,
+    }
+
+    impl PersistentActor for Gatherer {
+        const NAME: &'static str = "Gatherer";
+
+        fn init(&self, cx: &mut Context<Self>) {
+            if cx.storage.take::<bool, _>("started").is_none() {
+                let callbacks = cx.gather::<Job>(3, self.policy).unwrap();
+                cx.dispatcher
+                    .send_with_callback(self.replier, Job(1), callbacks[0])
+                    .unwrap();
+                cx.dispatcher
+                    .send_with_callback(self.worker, Job(3), callbacks[1])
+                    .unwrap();
+                cx.dispatcher
+                    .send_with_callback(self.replier, Job(5), callbacks[2])
+                    .unwrap();
+            }
+            cx.storage.put("started", true);
+        }
+    }
+
+    impl Callback<Gathered> for Gatherer {
+        type Env = ();
+        type Error = DispatchError;
+
+        fn handle(
+            &self,
+            cx: &mut Context<Self>,
+            _: (),
+            gathered: Gathered,
+        ) -> Result<(), DispatchError> {
+            let jobs = gathered.decode::<Job>()?;
+            let jobs = jobs
+                .into_iter()
+                .map(|job| job.map(|Job(job)| job))
+                .collect();
+            let now = cx.dispatcher.now().as_millis();
+            let collected = (jobs, gathered.timed_out, now);
+            self.collected.lock().unwrap().push(collected);
+            Ok(())
+        }
+    }
+
+    /// Runs a [`Gatherer`] with `policy`, returning the runtime, the gatherer and what it
+    /// collected.
+    fn gather(policy: GatherPolicy) -> (Runtime<MemoryLog>, AnyActorId, Vec<Collected>) {
+        let mut runtime = runtime();
+        runtime.set_clock(VirtualClock::new(Timestamp::from_millis(1_000)));
+        runtime.register_actor::<Replier>();
+        runtime.register_actor::<Gatherer>();
+        runtime.register_handler::<Replier, Job>();
+        runtime.register_callback::<Gatherer, Gathered>();
+        let replier = runtime.add_actor(Replier, MemoryLog::new());
+        let (worker, _) = add_worker(&mut runtime, MemoryLog::new());
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let gatherer = Gatherer {
+            replier,
+            worker,
+            policy,
+            collected: collected.clone(),
+        };
+        let gatherer = runtime.add_actor(gatherer, MemoryLog::new()).into_any();
+        assert!(block_on(runtime.run()).is_ok());
+        let collected = collected.lock().unwrap().clone();
+        (runtime, gatherer, collected)
+    }
+
+    #[test]
+    fn gathers_complete_once_their_quorum_replied() {
+        let (runtime, gatherer, collected) = gather(GatherPolicy::quorum(2));
+        assert_eq!(
+            collected,
+            vec![(vec![Some(2), None, Some(6)], false, 1_000)]
+        );
+        assert_eq!(runtime_keys(&runtime, gatherer), vec!["callback/next"]);
+    }
+
+    #[test]
+    fn gathers_complete_with_what_they_have_when_they_time_out() {
+        let policy = GatherPolicy::all().with_timeout(Duration::from_millis(100));
+        let (runtime, gatherer, collected) = gather(policy);
+        assert_eq!(collected, vec![(vec![Some(2), None, Some(6)], true, 1_100)]);
+        assert_eq!(runtime_keys(&runtime, gatherer), vec!["callback/next"]);
+        assert_eq!(runtime.timers.next_due(), None);
+    }
+
+    #[test]
+    fn messages_are_encoded_with_the_codec_of_the_log() {
+        let mut runtime = Runtime::<EncodingLog<Json>>::new(Database::new());
+        runtime.register_actor::<Worker>();
+        runtime.register_actor::<Boss<Worker>>();
+        runtime.register_handler::<Worker, Job>();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let mut log = EncodingLog::<Json>::default();
+        let worker = Worker {
+            handled: handled.clone(),
+        };
+        let worker = runtime.add_actor(worker, log.clone());
+        let jobs = vec![(worker, 1), (worker, 2)];
+        runtime.add_actor(Boss { jobs }, log.clone());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*handled.lock().unwrap(), vec![1, 2]);
+        let entry = block_on(log.read(worker.into_any(), 0))
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(entry.message.codec, CodecId::Json);
+        let bytes = entry.message.bytes().unwrap();
+        let Job(job) = schema::decode(CodecId::Json, 0, &bytes).ok().unwrap();
+        assert_eq!(job, 1);
+    }
+
+    /// Fails to serialize, whatever the codec.
+    #[derive(Clone, Deserialize)]
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            Err(ser::Error::custom("unencodable"))
+        }
+    }
+
+    impl Message for Unencodable {
+        const NAME: &'static str = "Unencodable";
+    }
+
+    /// Sends itself an [`Unencodable`] for every job, recording whether the send failed to
+    /// encode it.
+    struct Encoder {
+        failed: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl PersistentActor for Encoder {
+        const NAME: &'static str = "Encoder";
+    }
+
+    impl Handler<Job> for Encoder {
+        type Error = ();
+
+        fn handle(&self, cx: &mut Context<Self>, _: Job) -> Result<(), ()> {
+            let result = cx.dispatcher.send(cx.actor_id, Unencodable);
+            let name = MessageName::name_for::<Unencodable>();
+            let failed =
+                matches!(result, Err(DispatchError::Encode { message, .. }) if message == name);
+            self.failed.lock().unwrap().push(failed);
+            Ok(())
+        }
+    }
+
+    impl Handler<Unencodable> for Encoder {
+        type Error = ();
+
+        fn handle(&self, _: &mut Context<Self>, _: Unencodable) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unencodable_sends_are_reported_to_the_handler() {
+        let mut runtime = Runtime::<EncodingLog>::new(Database::new());
+        runtime.register_actor::<Encoder>();
+        runtime.register_actor::<Boss<Encoder>>();
+        runtime.register_handler::<Encoder, Job>();
+        runtime.register_handler::<Encoder, Unencodable>();
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let encoder = Encoder {
+            failed: failed.clone(),
+        };
+        let encoder = runtime.add_actor(encoder, EncodingLog::default());
+        runtime.add_actor(
+            Boss {
+                jobs: vec![(encoder, 1)],
+            },
+            EncodingLog::default(),
+        );
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert_eq!(*failed.lock().unwrap(), vec![true]);
+        assert!(matches!(runtime.dead_letters(), Ok(letters) if letters.is_empty()));
+    }
+
+    #[test]
+    fn undecodable_messages_are_dead_lettered() {
+        let mut runtime = runtime();
+        let mut log = MemoryLog::new();
+        let (worker, handled) = add_worker(&mut runtime, log.clone());
+        let (sender, _) = add_worker(&mut runtime, MemoryLog::new());
+        let (worker, sender) = (worker.into_any(), sender.into_any());
+        let name = MessageName::name_for::<Job>();
+        let corrupt = AnyMessage::from_parts(name, CodecId::MessagePack, b"corrupt".to_vec());
+        assert!(block_on(log.append(sender, worker, 1, corrupt)).is_ok());
+
+        assert!(block_on(runtime.run()).is_ok());
+        assert!(handled.lock().unwrap().is_empty());
+        let dead_letters = runtime.dead_letters().ok().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].from, sender);
+        assert_eq!(dead_letters[0].to, worker);
+        assert_eq!(dead_letters[0].failure.kind, FailureKind::Codec);
+    }
 }
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::actor::AnyActorId;
+
+/// Messages an actor handles per scheduling turn unless the runtime is configured otherwise.
+pub const DEFAULT_TURN_LIMIT: u32 = 16;
+/// Virtual time the scheduler charges an actor of weight 1 for a message of the lowest priority.
+const COST: u64 = 1 << 16;
+
+/// How urgently a message should be handled, see [`crate::message::Message::PRIORITY`].
+#[derive(
+    PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default, Debug, Serialize, Deserialize,
+)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Share of the scheduler an actor gets while its next message has this priority, relative
+    /// to the other priorities.
+    fn weight(self) -> u64 {
+        match self {
+            Priority::Low => 1,
+            Priority::Normal => 2,
+            Priority::High => 4,
+        }
+    }
+}
+
+/// Decides which actor handles a message next, with weighted fair queuing across actors. Every
+/// message handled advances the virtual time of its actor by a cost inversely proportional to the
+/// weight of the actor and the priority of the message, and the actor that would be done with
+/// its next message the earliest in virtual time goes next. Actors with high priority messages
+/// are served first but actors with low priority ones still get their share.
+///
+/// The runtime schedules in turns, in between it fires timers and reminders. An actor handles at
+/// most its turn limit of messages per turn, so a chatty actor cannot hold the others back.
+pub(crate) struct Scheduler {
+    /// Earliest virtual time the actors ready at the last pick could start from. An actor that
+    /// was idle starts from here, it does not get to catch up on the turns it had nothing to do.
+    virtual_time: u64,
+    /// Virtual time every actor has been served up to.
+    finish: HashMap<AnyActorId, u64>,
+    weights: HashMap<AnyActorId, u32>,
+    turn_limit: u32,
+    actor_turn_limits: HashMap<AnyActorId, u32>,
+    /// Messages scheduled for every actor during the current turn.
+    served: HashMap<AnyActorId, u32>,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        Self {
+            virtual_time: 0,
+            finish: HashMap::new(),
+            weights: HashMap::new(),
+            turn_limit: DEFAULT_TURN_LIMIT,
+            actor_turn_limits: HashMap::new(),
+            served: HashMap::new(),
+        }
+    }
+
+    /// Sets the share of the scheduler `actor_id` gets relative to other actors, 1 by default.
+    pub(crate) fn set_weight(&mut self, actor_id: AnyActorId, weight: u32) {
+        self.weights.insert(actor_id, weight.max(1));
+    }
+
+    pub(crate) fn set_turn_limit(&mut self, limit: u32) {
+        self.turn_limit = limit.max(1);
+    }
+
+    pub(crate) fn set_actor_turn_limit(&mut self, actor_id: AnyActorId, limit: u32) {
+        self.actor_turn_limits.insert(actor_id, limit.max(1));
+    }
+
+    /// Forgets `actor_id`, e.g. once it stopped.
+    pub(crate) fn remove(&mut self, actor_id: AnyActorId) {
+        self.finish.remove(&actor_id);
+        self.weights.remove(&actor_id);
+        self.actor_turn_limits.remove(&actor_id);
+        self.served.remove(&actor_id);
+    }
+
+    pub(crate) fn start_turn(&mut self) {
+        self.served.clear();
+    }
+
+    /// Whether `actor_id` can still handle messages during the current turn.
+    pub(crate) fn can_run(&self, actor_id: AnyActorId) -> bool {
+        let limit = self
+            .actor_turn_limits
+            .get(&actor_id)
+            .copied()
+            .unwrap_or(self.turn_limit);
+        self.served.get(&actor_id).copied().unwrap_or(0) < limit
+    }
+
+    /// Picks the actor to run among `ready`, the actors with a message to handle and the
+    /// priority of that message, and charges it for the message. Ties go to the first actor.
+    pub(crate) fn pick(&mut self, ready: &[(AnyActorId, Priority)]) -> Option<AnyActorId> {
+        let candidates: Vec<_> = ready
+            .iter()
+            .map(|&(actor_id, priority)| {
+                let start = self.start(actor_id);
+                (actor_id, start, start + self.cost(actor_id, priority))
+            })
+            .collect();
+        let &(actor_id, _, finish) = candidates
+            .iter()
+            .min_by_key(|&&(_, start, finish)| (finish, start))?;
+        // An actor still waiting for its turn keeps the virtual time back, otherwise it would
+        // start over from the virtual time of the actors served before it.
+        self.virtual_time = candidates.iter().map(|&(_, start, _)| start).min()?;
+        self.finish.insert(actor_id, finish);
+        *self.served.entry(actor_id).or_default() += 1;
+        Some(actor_id)
+    }
+
+    fn start(&self, actor_id: AnyActorId) -> u64 {
+        let finish = self.finish.get(&actor_id).copied().unwrap_or(0);
+        finish.max(self.virtual_time)
+    }
+
+    fn cost(&self, actor_id: AnyActorId, priority: Priority) -> u64 {
+        let weight = self.weights.get(&actor_id).copied().unwrap_or(1) as u64;
+        COST / (weight * priority.weight())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Priority, Scheduler};
+    use crate::actor::{AnyActorId, PersistentActor, PersistentActorId};
+
+    struct Worker;
+
+    impl PersistentActor for Worker {
+        const NAME: &'static str = "Worker";
+    }
+
+    fn worker(value: u32) -> AnyActorId {
+        PersistentActorId::<Worker>::new(value).into()
+    }
+
+    /// Picks `n` times among `ready`, counting how often every actor went.
+    fn run(
+        scheduler: &mut Scheduler,
+        ready: &[(AnyActorId, Priority)],
+        n: usize,
+    ) -> HashMap<AnyActorId, usize> {
+        let mut picked = HashMap::new();
+        for _ in 0..n {
+            *picked.entry(scheduler.pick(ready).unwrap()).or_default() += 1;
+        }
+        picked
+    }
+
+    #[test]
+    fn actors_share_by_weight() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_weight(worker(1), 3);
+        let ready = [(worker(1), Priority::Normal), (worker(2), Priority::Normal)];
+        let picked = run(&mut scheduler, &ready, 40);
+        assert_eq!(picked[&worker(1)], 30);
+        assert_eq!(picked[&worker(2)], 10);
+    }
+
+    #[test]
+    fn actors_share_by_priority() {
+        let mut scheduler = Scheduler::new();
+        let ready = [(worker(1), Priority::Low), (worker(2), Priority::High)];
+        let picked = run(&mut scheduler, &ready, 50);
+        assert_eq!(picked[&worker(1)], 10);
+        assert_eq!(picked[&worker(2)], 40);
+    }
+
+    #[test]
+    fn ties_go_to_the_first_actor() {
+        let mut scheduler = Scheduler::new();
+        let ready = [(worker(2), Priority::Normal), (worker(1), Priority::Normal)];
+        assert_eq!(scheduler.pick(&ready), Some(worker(2)));
+        assert_eq!(scheduler.pick(&ready), Some(worker(1)));
+        assert_eq!(scheduler.pick(&[]), None);
+    }
+
+    #[test]
+    fn idle_actors_do_not_catch_up() {
+        let mut scheduler = Scheduler::new();
+        run(&mut scheduler, &[(worker(1), Priority::Normal)], 10);
+        let ready = [(worker(1), Priority::Normal), (worker(2), Priority::Normal)];
+        let picked = run(&mut scheduler, &ready, 10);
+        assert_eq!(picked[&worker(1)], 5);
+        assert_eq!(picked[&worker(2)], 5);
+    }
+
+    #[test]
+    fn turns_are_limited() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_turn_limit(2);
+        scheduler.set_actor_turn_limit(worker(2), 3);
+        scheduler.start_turn();
+        run(&mut scheduler, &[(worker(1), Priority::Normal)], 2);
+        run(&mut scheduler, &[(worker(2), Priority::Normal)], 2);
+        assert!(!scheduler.can_run(worker(1)));
+        assert!(scheduler.can_run(worker(2)));
+        run(&mut scheduler, &[(worker(2), Priority::Normal)], 1);
+        assert!(!scheduler.can_run(worker(2)));
+        scheduler.start_turn();
+        assert!(scheduler.can_run(worker(1)));
+        assert!(scheduler.can_run(worker(2)));
+    }
+
+    #[test]
+    fn removed_actors_are_forgotten() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_weight(worker(1), 3);
+        scheduler.set_actor_turn_limit(worker(1), 1);
+        scheduler.start_turn();
+        run(&mut scheduler, &[(worker(1), Priority::Normal)], 1);
+        scheduler.remove(worker(1));
+        assert!(scheduler.can_run(worker(1)));
+        assert!(scheduler.finish.is_empty());
+        assert!(scheduler.weights.is_empty());
+        assert!(scheduler.actor_turn_limits.is_empty());
+    }
+
+    #[test]
+    fn limits_and_weights_are_at_least_one() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_turn_limit(0);
+        scheduler.set_weight(worker(1), 0);
+        scheduler.start_turn();
+        assert!(scheduler.can_run(worker(1)));
+        let ready = [(worker(1), Priority::Normal), (worker(2), Priority::Normal)];
+        let picked = run(&mut scheduler, &ready, 2);
+        assert_eq!(picked[&worker(1)], 1);
+        assert!(!scheduler.can_run(worker(1)));
+    }
+}
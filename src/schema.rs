@@ -0,0 +1,238 @@
+use std::{borrow::Cow, collections::HashMap, hash::Hash, panic::RefUnwindSafe};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{CodecError, CodecId},
+    database::Bytes,
+};
+
+/// Version of the Rust type a persisted value or message was written with. Encoded bytes start
+/// with the version, so bytes written by an older version of a type can be migrated with
+/// upcasters when they are decoded. Upcasters decode and encode with the codec of the bytes
+/// they migrate.
+pub type SchemaVersion = u32;
+
+const HEADER_LEN: usize = std::mem::size_of::<SchemaVersion>();
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The bytes don't start with a version.
+    MissingVersion,
+    /// The bytes were written by a newer version of the type than the one decoding them.
+    Newer {
+        version: SchemaVersion,
+        current: SchemaVersion,
+    },
+    /// There is no upcaster registered from `version` to the next version.
+    MissingUpcaster {
+        version: SchemaVersion,
+    },
+    Codec(CodecError),
+}
+
+type UpcastFn = dyn Fn(CodecId, &[u8]) -> Result<Bytes, DecodeError> + RefUnwindSafe;
+
+/// Functions migrating the encoding of a type from one version to the next, keyed by `K`.
+pub struct Upcasters<K> {
+    upcasters: HashMap<(K, SchemaVersion), Box<UpcastFn>>,
+}
+
+impl<K> Upcasters<K>
+where
+    K: Hash + Eq,
+{
+    pub fn new() -> Self {
+        Self {
+            upcasters: HashMap::new(),
+        }
+    }
+
+    /// Registers `upcast` to migrate values written at version `from` to version `from + 1`.
+    pub fn register<Old, New>(&mut self, key: K, from: SchemaVersion, upcast: fn(Old) -> New)
+    where
+        Old: 'static + for<'a> Deserialize<'a>,
+        New: 'static + Serialize,
+    {
+        let key = (key, from);
+        if self.upcasters.contains_key(&key) {
+            panic!("Upcaster already registered")
+        }
+        let upcast = move |codec: CodecId, bytes: &[u8]| -> Result<Bytes, DecodeError> {
+            let old: Old = codec.decode(bytes).map_err(DecodeError::Codec)?;
+            codec.encode(&upcast(old)).map_err(DecodeError::Codec)
+        };
+        self.upcasters.insert(key, Box::new(upcast));
+    }
+
+    /// Decodes versioned `bytes` as a `T` at version `current`, upcasting them first if they
+    /// were written by an older version.
+    pub fn decode<T>(
+        &self,
+        key: K,
+        codec: CodecId,
+        current: SchemaVersion,
+        bytes: &[u8],
+    ) -> Result<T, DecodeError>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        let payload = self.upcast(key, codec, current, bytes)?;
+        codec.decode(&payload).map_err(DecodeError::Codec)
+    }
+
+    /// Migrates versioned `bytes` to version `current` returning them without the version.
+    pub fn upcast<'b>(
+        &self,
+        key: K,
+        codec: CodecId,
+        current: SchemaVersion,
+        bytes: &'b [u8],
+    ) -> Result<Cow<'b, [u8]>, DecodeError> {
+        let (mut version, payload) = split(bytes)?;
+        if version > current {
+            return Err(DecodeError::Newer { version, current });
+        }
+        let mut key = (key, version);
+        let mut payload = Cow::Borrowed(payload);
+        while key.1 < current {
+            let upcast = self
+                .upcasters
+                .get(&key)
+                .ok_or(DecodeError::MissingUpcaster { version })?;
+            payload = Cow::Owned(upcast(codec, &payload)?);
+            version += 1;
+            key.1 = version;
+        }
+        Ok(payload)
+    }
+}
+
+/// Encodes `value` with `codec` prefixed with `version`.
+pub fn encode<T>(codec: CodecId, version: SchemaVersion, value: &T) -> Result<Bytes, CodecError>
+where
+    T: Serialize + ?Sized,
+{
+    Ok(with_version(version, &codec.encode(value)?))
+}
+
+/// Prefixes an already encoded `payload` with `version`.
+pub fn with_version(version: SchemaVersion, payload: &[u8]) -> Bytes {
+    let mut bytes = version.to_be_bytes().to_vec();
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Decodes `bytes` written with `codec` at version `current`, without upcasting.
+pub fn decode<T>(codec: CodecId, current: SchemaVersion, bytes: &[u8]) -> Result<T, DecodeError>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    match split(bytes)? {
+        (version, payload) if version == current => {
+            codec.decode(payload).map_err(DecodeError::Codec)
+        }
+        (version, _) if version > current => Err(DecodeError::Newer { version, current }),
+        (version, _) => Err(DecodeError::MissingUpcaster { version }),
+    }
+}
+
+fn split(bytes: &[u8]) -> Result<(SchemaVersion, &[u8]), DecodeError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(DecodeError::MissingVersion);
+    }
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    let version = SchemaVersion::from_be_bytes(header.try_into().unwrap());
+    Ok((version, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, DecodeError, Upcasters};
+    use crate::codec::CodecId;
+
+    const CODEC: CodecId = CodecId::MessagePack;
+
+    fn upcasters() -> Upcasters<&'static str> {
+        let mut upcasters = Upcasters::new();
+        upcasters.register("count", 0, |count: u32| u64::from(count) * 10);
+        upcasters.register("count", 1, |count: u64| (count, "migrated".to_string()));
+        upcasters
+    }
+
+    #[test]
+    fn current_bytes_are_not_upcast() {
+        let bytes = encode(CODEC, 2, &(7u64, "current")).unwrap();
+        let value: (u64, String) = upcasters().decode("count", CODEC, 2, &bytes).unwrap();
+        assert_eq!(value, (7, "current".to_string()));
+    }
+
+    #[test]
+    fn upcasters_are_chained() {
+        let bytes = encode(CODEC, 0, &3u32).unwrap();
+        let value: (u64, String) = upcasters().decode("count", CODEC, 2, &bytes).unwrap();
+        assert_eq!(value, (30, "migrated".to_string()));
+        let bytes = encode(CODEC, 1, &4u64).unwrap();
+        let value: (u64, String) = upcasters().decode("count", CODEC, 2, &bytes).unwrap();
+        assert_eq!(value, (4, "migrated".to_string()));
+    }
+
+    #[test]
+    fn upcasters_are_keyed() {
+        let bytes = encode(CODEC, 0, &3u32).unwrap();
+        let decoded = upcasters().decode::<(u64, String)>("other", CODEC, 2, &bytes);
+        assert!(matches!(
+            decoded,
+            Err(DecodeError::MissingUpcaster { version: 0 })
+        ));
+    }
+
+    #[test]
+    fn missing_upcasters_are_reported() {
+        let bytes = encode(CODEC, 0, &3u32).unwrap();
+        let decoded = upcasters().decode::<u64>("count", CODEC, 3, &bytes);
+        assert!(matches!(
+            decoded,
+            Err(DecodeError::MissingUpcaster { version: 2 })
+        ));
+    }
+
+    #[test]
+    fn newer_bytes_are_rejected() {
+        let bytes = encode(CODEC, 3, &3u32).unwrap();
+        let decoded = upcasters().decode::<u32>("count", CODEC, 2, &bytes);
+        assert!(matches!(
+            decoded,
+            Err(DecodeError::Newer {
+                version: 3,
+                current: 2
+            })
+        ));
+        let decoded = decode::<u32>(CODEC, 2, &bytes);
+        assert!(matches!(decoded, Err(DecodeError::Newer { .. })));
+    }
+
+    #[test]
+    fn plain_decoding_does_not_upcast() {
+        let bytes = encode(CODEC, 1, &3u32).unwrap();
+        assert_eq!(decode::<u32>(CODEC, 1, &bytes).unwrap(), 3);
+        let decoded = decode::<u32>(CODEC, 2, &bytes);
+        assert!(matches!(
+            decoded,
+            Err(DecodeError::MissingUpcaster { version: 1 })
+        ));
+    }
+
+    #[test]
+    fn bytes_without_a_version_are_rejected() {
+        let decoded = decode::<u32>(CODEC, 0, &[0, 1]);
+        assert!(matches!(decoded, Err(DecodeError::MissingVersion)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Upcaster already registered")]
+    fn upcasters_are_registered_once() {
+        let mut upcasters = upcasters();
+        upcasters.register("count", 1, |count: u64| count);
+    }
+}
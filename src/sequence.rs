@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use crate::{actor::AnyActorId, database::Database};
+
+/// Prefix of the keys the last sequence number sent between two actors is stored under, in the
+/// runtime's part of the database.
+const SEQUENCES_PREFIX: &str = "runtime/sequences/";
+
+/// How a message fits in the sequence of messages from its sender.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Order {
+    Expected,
+    /// Messages sent before this one are missing, they may have been lost.
+    Gap,
+    /// A message sent after this one was already received.
+    Late,
+}
+
+/// The last sequence number an actor received from each sender. The first message received
+/// from a sender, e.g. after a restart, sets where its sequence is expected to continue.
+#[derive(Default)]
+pub(crate) struct ReceivedSequences {
+    last: HashMap<AnyActorId, u64>,
+}
+
+impl ReceivedSequences {
+    /// Records message `seq` from `from`. Receiving the same message again, e.g. when its
+    /// handler is re-run, is in order.
+    pub(crate) fn receive(&mut self, from: AnyActorId, seq: u64) -> Order {
+        let Some(last) = self.last.get_mut(&from) else {
+            self.last.insert(from, seq);
+            return Order::Expected;
+        };
+        if seq < *last {
+            return Order::Late;
+        }
+        let order = if seq > *last + 1 {
+            Order::Gap
+        } else {
+            Order::Expected
+        };
+        *last = seq;
+        order
+    }
+}
+
+/// Key the last sequence number sent from `from` to `to` is stored under.
+pub(crate) fn sequence_key(from: AnyActorId, to: AnyActorId) -> String {
+    format!("{}{}/{}", SEQUENCES_PREFIX, from, to)
+}
+
+/// Keys of the sequences stored in `db` that `actor_id` sends or receives.
+pub(crate) fn keys_of(db: &Database, actor_id: AnyActorId) -> Vec<String> {
+    let sent = format!("{}{}/", SEQUENCES_PREFIX, actor_id);
+    let received = format!("/{}", actor_id);
+    db.keys_with_prefix(SEQUENCES_PREFIX)
+        .filter(|key| key.starts_with(&sent) || key.ends_with(&received))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Order, ReceivedSequences};
+    use crate::actor::{AnyActorId, PersistentActor, PersistentActorId};
+
+    struct Sender;
+
+    impl PersistentActor for Sender {
+        const NAME: &'static str = "Sender";
+    }
+
+    fn sender(value: u32) -> AnyActorId {
+        PersistentActorId::<Sender>::new(value).into()
+    }
+
+    #[test]
+    fn first_message_sets_the_sequence() {
+        let mut received = ReceivedSequences::default();
+        assert_eq!(received.receive(sender(1), 5), Order::Expected);
+        assert_eq!(received.receive(sender(1), 6), Order::Expected);
+    }
+
+    #[test]
+    fn repeated_messages_are_expected() {
+        let mut received = ReceivedSequences::default();
+        received.receive(sender(1), 1);
+        assert_eq!(received.receive(sender(1), 1), Order::Expected);
+        assert_eq!(received.receive(sender(1), 2), Order::Expected);
+    }
+
+    #[test]
+    fn skipped_messages_are_gaps() {
+        let mut received = ReceivedSequences::default();
+        received.receive(sender(1), 1);
+        assert_eq!(received.receive(sender(1), 4), Order::Gap);
+        assert_eq!(received.receive(sender(1), 5), Order::Expected);
+    }
+
+    #[test]
+    fn older_messages_are_late() {
+        let mut received = ReceivedSequences::default();
+        received.receive(sender(1), 1);
+        received.receive(sender(1), 4);
+        assert_eq!(received.receive(sender(1), 2), Order::Late);
+        assert_eq!(received.receive(sender(1), 5), Order::Expected);
+    }
+
+    #[test]
+    fn senders_are_sequenced_apart() {
+        let mut received = ReceivedSequences::default();
+        received.receive(sender(1), 10);
+        assert_eq!(received.receive(sender(2), 1), Order::Expected);
+        assert_eq!(received.receive(sender(2), 2), Order::Expected);
+        assert_eq!(received.receive(sender(1), 11), Order::Expected);
+    }
+}
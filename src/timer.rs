@@ -0,0 +1,366 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    future::{self, Future},
+    hash::Hash,
+    ops::Add,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    codec::CodecId,
+    database::{Bytes, Database, PersistentValue},
+    dispatcher::CallbackTarget,
+    dyn_table::DispatchResult,
+    envelope::Envelope,
+    message::{AnyMessage, MessageName},
+};
+
+/// Prefix of the keys timers are stored under. Timers live in the runtime's part of the database,
+/// next to the `actor/` and `shared/` namespaces.
+const TIMERS_PREFIX: &str = "runtime/timers/";
+/// Key of the next timer id, a max register so runs creating timers never conflict on it.
+pub(crate) const NEXT_TIMER_KEY: &str = "runtime/next_timer";
+
+/// Milliseconds since the Unix epoch.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Timestamp(u64);
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TimerId(u64);
+
+/// Source of time for timers. The runtime reads it once before every handler run, so a handler
+/// sees a single `now`.
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+
+    /// Waits until `deadline`, called when there is nothing to do before the next timer is due.
+    /// The runtime awaits the sleep, it must not block the thread polling the runtime.
+    fn sleep_until(&self, deadline: Timestamp) -> Sleep;
+}
+
+/// A wait for a [`Clock`] to reach a deadline, see [`Clock::sleep_until`].
+pub type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+/// The wall clock.
+pub struct SystemClock;
+
+/// Sleeps on a thread of its own until the wall clock reaches `deadline`, then wakes the task
+/// waiting for it.
+struct WallClockSleep {
+    deadline: Timestamp,
+    /// Waker of the task waiting, shared with the sleeping thread once it is started.
+    waker: Option<Arc<Mutex<Option<Waker>>>>,
+}
+
+/// A clock that only moves when told to, or when the runtime would otherwise wait for a timer.
+/// Clones share the same time, so a test can keep one to control the clock given to the runtime.
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    now: Arc<AtomicU64>,
+}
+
+/// A timer as stored in the database.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Timer {
+    pub(crate) due: Timestamp,
+    pub(crate) from: AnyActorId,
+    pub(crate) to: AnyActorId,
+    message: MessageName,
+    codec: CodecId,
+    bytes: Bytes,
+    #[serde(default)]
+    envelope: Option<Envelope>,
+}
+
+impl PersistentValue for Timer {}
+
+impl PersistentValue for TimerId {}
+
+/// In-memory index of things stored in the database that are due at some point, e.g. timers.
+pub(crate) struct DueQueue<K> {
+    queue: BTreeSet<(Timestamp, K)>,
+    due: HashMap<K, Timestamp>,
+}
+
+pub(crate) type TimerQueue = DueQueue<TimerId>;
+
+impl Timestamp {
+    pub const fn from_millis(millis: u64) -> Self {
+        Timestamp(millis)
+    }
+
+    pub fn as_millis(self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    /// Saturates at the largest timestamp, a timer that far out never fires.
+    fn add(self, duration: Duration) -> Timestamp {
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        Timestamp(self.0.saturating_add(millis))
+    }
+}
+
+impl TimerId {
+    pub(crate) fn new(id: u64) -> Self {
+        TimerId(id)
+    }
+
+    pub(crate) fn key(self) -> String {
+        format!("{}{}", TIMERS_PREFIX, self.0)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp(elapsed.as_millis() as u64)
+    }
+
+    fn sleep_until(&self, deadline: Timestamp) -> Sleep {
+        Box::pin(WallClockSleep {
+            deadline,
+            waker: None,
+        })
+    }
+}
+
+impl Future for WallClockSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = SystemClock.now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => *waker.lock().unwrap() = Some(cx.waker().clone()),
+            None => {
+                let waker = Arc::new(Mutex::new(Some(cx.waker().clone())));
+                let delay = Duration::from_millis(self.deadline.0 - now.0);
+                let sleeping = waker.clone();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    if let Some(waker) = sleeping.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                });
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl VirtualClock {
+    pub fn new(now: Timestamp) -> Self {
+        Self {
+            now: Arc::new(AtomicU64::new(now.0)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let advance = |now| Some((Timestamp(now) + duration).0);
+        let _ = self
+            .now
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, advance);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.now.load(Ordering::SeqCst))
+    }
+
+    fn sleep_until(&self, deadline: Timestamp) -> Sleep {
+        self.now.fetch_max(deadline.0, Ordering::SeqCst);
+        Box::pin(future::ready(()))
+    }
+}
+
+impl Timer {
+    pub(crate) fn new(
+        due: Timestamp,
+        from: AnyActorId,
+        to: AnyActorId,
+        message: &AnyMessage,
+    ) -> DispatchResult<Self> {
+        Ok(Self {
+            due,
+            from,
+            to,
+            message: message.name,
+            codec: message.codec,
+            bytes: message.bytes()?.into_owned(),
+            envelope: Some(message.envelope.clone()),
+        })
+    }
+
+    pub(crate) fn message(self) -> AnyMessage {
+        let message = AnyMessage::from_parts(self.message, self.codec, self.bytes);
+        match self.envelope {
+            Some(envelope) => message.with_envelope(envelope),
+            None => message,
+        }
+    }
+}
+
+impl TimerQueue {
+    /// The timers in `db` expiring a callback or timing out a gather of `actor_id`.
+    pub(crate) fn timeouts_of(db: &Database, actor_id: AnyActorId) -> Vec<TimerId> {
+        let mut timeouts = Vec::new();
+        for key in db.keys_with_prefix(TIMERS_PREFIX) {
+            let Ok(id) = key[TIMERS_PREFIX.len()..].parse() else {
+                continue;
+            };
+            let Ok(Some(timer)) = db.get_resource::<Timer>(key) else {
+                continue;
+            };
+            let callback = timer.envelope.and_then(|envelope| envelope.callback);
+            let is_timeout = matches!(
+                callback,
+                Some(CallbackTarget::Expire { .. } | CallbackTarget::GatherTimeout(_))
+            );
+            if timer.to == actor_id && is_timeout {
+                timeouts.push(TimerId(id));
+            }
+        }
+        timeouts
+    }
+
+    /// Rebuilds the index from the timers stored in `db`.
+    /// Returns the key of a timer that cannot be read, firing the others could lose it.
+    pub(crate) fn load(db: &Database) -> Result<Self, String> {
+        let mut queue = DueQueue::default();
+        for key in db.keys_with_prefix(TIMERS_PREFIX) {
+            let id = key[TIMERS_PREFIX.len()..].parse();
+            match (id, db.get_resource::<Timer>(key)) {
+                (Ok(id), Ok(Some(timer))) => queue.insert(TimerId(id), timer.due),
+                (Ok(_), Ok(None)) => {}
+                _ => return Err(key.to_string()),
+            }
+        }
+        Ok(queue)
+    }
+}
+
+impl<K> DueQueue<K>
+where
+    K: Ord + Hash + Clone,
+{
+    /// Adds `key`, replacing its previous due time if it was already in the queue.
+    pub(crate) fn insert(&mut self, key: K, due: Timestamp) {
+        self.remove(&key);
+        self.queue.insert((due, key.clone()));
+        self.due.insert(key, due);
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(due) = self.due.remove(key) {
+            self.queue.remove(&(due, key.clone()));
+        }
+    }
+
+    /// When the first entry is due.
+    pub(crate) fn next_due(&self) -> Option<Timestamp> {
+        self.queue.first().map(|(due, _)| *due)
+    }
+
+    /// Removes and returns an entry due at `now`, if there is one.
+    pub(crate) fn pop_due(&mut self, now: Timestamp) -> Option<K> {
+        let (due, key) = self.queue.first()?.clone();
+        if due > now {
+            return None;
+        }
+        self.remove(&key);
+        Some(key)
+    }
+}
+
+impl<K> Default for DueQueue<K> {
+    fn default() -> Self {
+        Self {
+            queue: BTreeSet::new(),
+            due: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc},
+        task::{Context, Poll, Wake, Waker},
+        time::Duration,
+    };
+
+    use super::{Clock, SystemClock, Timestamp, VirtualClock};
+
+    struct Notify(mpsc::Sender<()>);
+
+    impl Wake for Notify {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn wall_clock_sleeps_without_blocking() {
+        let (sender, woken) = mpsc::channel();
+        let waker = Waker::from(Arc::new(Notify(sender)));
+        let mut cx = Context::from_waker(&waker);
+        let mut sleep = SystemClock.sleep_until(SystemClock.now() + Duration::from_millis(20));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        woken.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(sleep.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn past_deadlines_are_ready() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut sleep = SystemClock.sleep_until(Timestamp::from_millis(0));
+        assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    fn virtual_clock_jumps_to_the_deadline() {
+        let clock = VirtualClock::new(Timestamp::from_millis(10));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut sleep = clock.sleep_until(Timestamp::from_millis(50));
+        assert_eq!(clock.now(), Timestamp::from_millis(50));
+        assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Ready(()));
+        drop(clock.sleep_until(Timestamp::from_millis(20)));
+        assert_eq!(clock.now(), Timestamp::from_millis(50));
+    }
+
+    #[test]
+    fn adding_durations_saturates() {
+        let end = Timestamp::from_millis(u64::MAX - 1);
+        assert_eq!(
+            end + Duration::from_millis(5),
+            Timestamp::from_millis(u64::MAX)
+        );
+        assert_eq!(
+            Timestamp::from_millis(1) + Duration::MAX,
+            Timestamp::from_millis(u64::MAX)
+        );
+        let clock = VirtualClock::new(end);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), Timestamp::from_millis(u64::MAX));
+    }
+}
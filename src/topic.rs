@@ -0,0 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actor::AnyActorId,
+    database::{Database, PersistentValue},
+    message::MessageName,
+};
+
+/// Prefix of the keys subscriptions are stored under, in the runtime's part of the database.
+pub(crate) const TOPICS_PREFIX: &str = "runtime/topics/";
+
+/// `actor_id` receives the messages `message` published to `topic`. Each subscription has its own
+/// key, so actors subscribing to the same topic concurrently do not conflict.
+#[derive(PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    pub(crate) topic: String,
+    pub(crate) actor_id: AnyActorId,
+    pub(crate) message: MessageName,
+}
+
+impl PersistentValue for Subscription {}
+
+/// In-memory index of the subscriptions stored in the database, by topic.
+#[derive(Default)]
+pub(crate) struct TopicIndex {
+    topics: HashMap<String, HashSet<(AnyActorId, MessageName)>>,
+}
+
+impl Subscription {
+    pub(crate) fn key(&self) -> String {
+        format!(
+            "{}{}/{}/{}",
+            TOPICS_PREFIX, self.topic, self.actor_id, self.message
+        )
+    }
+}
+
+impl TopicIndex {
+    /// Rebuilds the index from the subscriptions stored in `db`.
+    /// Returns the key of a subscription that cannot be read, publishing without it would not
+    /// deliver to its subscriber.
+    pub(crate) fn load(db: &Database) -> Result<Self, String> {
+        let mut index = TopicIndex::default();
+        for key in db.keys_with_prefix(TOPICS_PREFIX) {
+            match db.get_resource::<Subscription>(key) {
+                Ok(Some(subscription)) => index.insert(subscription),
+                Ok(None) => {}
+                Err(_) => return Err(key.to_string()),
+            }
+        }
+        Ok(index)
+    }
+
+    /// The subscriptions of `actor_id` stored in `db`.
+    pub(crate) fn subscriptions_of(db: &Database, actor_id: AnyActorId) -> Vec<Subscription> {
+        db.keys_with_prefix(TOPICS_PREFIX)
+            .filter_map(|key| db.get_resource::<Subscription>(key).ok().flatten())
+            .filter(|subscription| subscription.actor_id == actor_id)
+            .collect()
+    }
+
+    pub(crate) fn insert(&mut self, subscription: Subscription) {
+        self.topics
+            .entry(subscription.topic)
+            .or_default()
+            .insert((subscription.actor_id, subscription.message));
+    }
+
+    pub(crate) fn remove(&mut self, subscription: &Subscription) {
+        if let Some(subscribers) = self.topics.get_mut(&subscription.topic) {
+            subscribers.remove(&(subscription.actor_id, subscription.message));
+            if subscribers.is_empty() {
+                self.topics.remove(&subscription.topic);
+            }
+        }
+    }
+
+    /// Actors subscribed to messages `message` published to `topic`.
+    pub(crate) fn subscribers(
+        &self,
+        topic: &str,
+        message: MessageName,
+    ) -> impl Iterator<Item = AnyActorId> + '_ {
+        self.topics
+            .get(topic)
+            .into_iter()
+            .flatten()
+            .filter(move |(_, name)| *name == message)
+            .map(|(actor_id, _)| *actor_id)
+    }
+}